@@ -1,6 +1,314 @@
 #[allow(unused_imports)]
 use datavir::prelude::*;
-use datavir::ws_client::WSClient;
+use datavir::jwt::{Encoding, Issuer, JwtCodec};
+use datavir::ws_client::{Reconnect, WSClient, WSClientConfig};
+use datavir::net::{Timeouts, TlsClientConfig};
+use datavir::messages::*;
+use datavir::streams::MAX_STREAM_CHUNK;
+use futures_util::TryStreamExt;
+
+fn node_or_path_from_arg(val: &str) -> NodeOrPath {
+    match str_to_uuid(val) {
+        Ok(uuid) => NodeOrPath::Node(uuid),
+        Err(_) => NodeOrPath::Path(val.to_string()),
+    }
+}
+
+/// Anything that parses as a UUID is taken as one, everything else is a path.
+fn nodes_or_paths_from_args(args: &clap::ArgMatches, name: &str) -> Vec<NodeOrPath> {
+    args.values_of(name).map(|values| values.map(node_or_path_from_arg).collect()).unwrap_or_default()
+}
+
+fn list_order_opt() -> clap::Arg<'static> {
+    clap::Arg::new("order-by")
+        .long("order-by")
+        .takes_value(true)
+        .default_value("name")
+        .possible_values(["name", "changedAt", "createdAt"])
+}
+
+fn list_order_arg(args: &clap::ArgMatches) -> DVResult<ListOrder> {
+    Ok(serde_json::from_value(serde_json::Value::String(args.value_of("order-by").expect("missing argument with a default").to_string()))?)
+}
+
+/// Reads a numeric argument, if it was given.
+fn u64_arg(args: &clap::ArgMatches, name: &str) -> DVResult<Option<u64>> {
+    match args.value_of(name) {
+        Some(val) => match val.parse::<u64>() {
+            Ok(v) => Ok(Some(v)),
+            Err(err) => Err(DVError::InvalidArgument(format!("{} {:?}: {}", name, val, err))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// `--expect-version`, when given.
+fn expected_content_arg(args: &clap::ArgMatches) -> DVResult<ExpectedContent> {
+    Ok(ExpectedContent{
+        expected_version: u64_arg(args, "expect-version")?,
+        expected_hash: None,
+    })
+}
+
+fn expect_version_opt() -> clap::Arg<'static> {
+    clap::Arg::new("expect-version")
+        .long("expect-version")
+        .takes_value(true)
+        .validator(|val| val.parse::<u64>())
+        .help("Fails if the content is no longer at this version")
+}
+
+/// Reads an argument given in seconds.
+fn seconds_arg(args: &clap::ArgMatches, name: &str) -> Option<std::time::Duration> {
+    match args.value_of(name).expect("missing argument with a default").parse::<u64>() {
+        Ok(v) => Some(std::time::Duration::from_secs(v)),
+        Err(err) => {
+            error!("Invalid --{}: {:?}", name, err);
+            None
+        }
+    }
+}
+
+async fn run_command(client: &WSClient, args: &clap::ArgMatches) -> DVResult<()> {
+    match args.subcommand() {
+        Some(("hello", _)) => {
+            if let Some(hello) = client.capabilities() {
+                println!("datavir {}", hello.datavir_version);
+                println!("protocol version {} (of {:?})", hello.protocol_version, hello.protocol_versions);
+                println!("encodings: {:?}", hello.encodings);
+                println!("hash algorithms: {:?}", hello.hash_algorithms);
+                println!("generators: {:?}", hello.generators);
+                println!("features: {:?}", hello.features);
+            }
+        },
+        Some(("list-volumes", sub_args)) => {
+            let volumes = client.volumes(list_order_arg(sub_args)?);
+            futures_util::pin_mut!(volumes);
+            while let Some(volume) = volumes.try_next().await? {
+                println!("{}\t{}\t{}", volume.uuid, volume.name, volume.title);
+            }
+        },
+        Some(("list", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let children = client.children(node, volume, list_order_arg(sub_args)?);
+            futures_util::pin_mut!(children);
+            while let Some(node) = children.try_next().await? {
+                println!("{}\t{}\t{}\t{}", node.uuid, node.content.file_kind.as_str(), node.changed, node.name);
+            }
+        },
+        Some(("new-volume", sub_args)) => {
+            let name = sub_args.value_of("NAME").expect("missing name");
+            let volume = VolumeInfo{
+                uuid: Uuid::nil(),
+                title: sub_args.value_of("title").unwrap_or(name).to_string(),
+                name: name.to_string(),
+                is_real: !sub_args.is_present("pseudo"),
+                uid2name: HashMap::new(),
+                gid2name: HashMap::new(),
+            };
+            let rpl = client.new_volume(volume).await?;
+            match rpl.volume {
+                Some(volume) => println!("{:?}\t{}", rpl.status, volume.uuid),
+                None => println!("{:?}", rpl.status),
+            }
+        },
+        Some(("rename-volume", sub_args)) => {
+            let volume = str_to_uuid(sub_args.value_of("VOLUME").expect("missing volume"))?;
+            let name = sub_args.value_of("name").map(|v| v.to_string());
+            let title = sub_args.value_of("title").map(|v| v.to_string());
+            let rpl = client.rename_volume(volume, name, title).await?;
+            println!("{:?}", rpl.status);
+        },
+        Some(("delete-volume", sub_args)) => {
+            let volume = str_to_uuid(sub_args.value_of("VOLUME").expect("missing volume"))?;
+            println!("{:?}", client.delete_volume(volume).await?);
+        },
+        Some(("create", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let uuid = match sub_args.value_of("uuid") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let req = CreateNodeReq{
+                parent: node_or_path_from_arg(sub_args.value_of("PARENT").expect("missing parent")),
+                volume,
+                name: sub_args.value_of("NAME").expect("missing name").to_string(),
+                uuid,
+                file_kind: sub_args.value_of("kind").expect("missing argument with a default").parse()?,
+                title: sub_args.value_of("title").unwrap_or_default().to_string(),
+                description: sub_args.value_of("description").unwrap_or_default().to_string(),
+                unix_perm: None,
+                target: sub_args.value_of("target").map(|v| v.to_string()),
+                link_to: sub_args.value_of("link-to").map(node_or_path_from_arg),
+            };
+            let node = client.create_node(req).await?;
+            println!("{}", serde_json::to_string_pretty(&node)?);
+        },
+        Some(("rename", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let req = RenameNodeReq{
+                node: node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node")),
+                volume,
+                name: sub_args.value_of("name").map(|v| v.to_string()),
+                title: sub_args.value_of("title").map(|v| v.to_string()),
+                description: sub_args.value_of("description").map(|v| v.to_string()),
+            };
+            let node = client.rename_node(req).await?;
+            println!("{}", serde_json::to_string_pretty(&node)?);
+        },
+        Some(("move", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let parent = node_or_path_from_arg(sub_args.value_of("PARENT").expect("missing parent"));
+            let name = sub_args.value_of("name").map(|v| v.to_string());
+            let node = client.move_node(node, parent, volume, name).await?;
+            println!("{}", serde_json::to_string_pretty(&node)?);
+        },
+        Some(("clone", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let parent = node_or_path_from_arg(sub_args.value_of("PARENT").expect("missing parent"));
+            let name = sub_args.value_of("name").map(|v| v.to_string());
+            let (node, count) = client.clone_node(node, parent, volume, name, sub_args.is_present("recursive")).await?;
+            println!("{}\t{} nodes cloned", node.uuid, count);
+        },
+        Some(("delete", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let count = client.delete_node(node, volume, sub_args.is_present("recursive")).await?;
+            println!("{} nodes deleted", count);
+        },
+        Some(("node-info", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let rpl = client.node_info(nodes_or_paths_from_args(sub_args, "ITEMS"), volume).await?;
+            println!("{}", serde_json::to_string_pretty(&rpl)?);
+        },
+        Some(("stream-hash", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let alg: HashAlg = serde_json::from_value(serde_json::Value::String(sub_args.value_of("alg").expect("missing alg").to_string()))?;
+            let rpl = client.stream_hash(alg, nodes_or_paths_from_args(sub_args, "ITEMS"), volume).await?;
+            for (uuid, value) in rpl.values {
+                let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{}\t{}", hex, uuid);
+            }
+        },
+        Some(("upload", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let mut file = tokio::fs::File::open(sub_args.value_of("FILE").expect("missing file")).await?;
+            let rpl = client.upload(node, volume, sub_args.is_present("resume"), &mut file).await?;
+            println!("{}\t{} bytes", rpl.node, rpl.size);
+        },
+        Some(("download", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let mut file = tokio::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(!sub_args.is_present("resume"))
+                .open(sub_args.value_of("FILE").expect("missing file"))
+                .await?;
+            let size = client.download(node, volume, &mut file).await?;
+            println!("{} bytes", size);
+        },
+        Some((command @ ("write" | "append"), sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let data = tokio::fs::read(sub_args.value_of("FILE").expect("missing file")).await?;
+            let mut expect = expected_content_arg(sub_args)?;
+            let mut offset = match command {
+                "write" => Some(u64_arg(sub_args, "offset")?.expect("missing offset")),
+                _ => None,
+            };
+            // Each chunk expects the version the previous one left, so
+            // nobody else's changes end up in between
+            let mut version = None;
+            let mut size = 0;
+            for chunk in data.chunks(MAX_STREAM_CHUNK) {
+                if version.is_some() {
+                    expect.expected_version = version;
+                }
+                let (new_size, new_version) = match offset {
+                    Some(at) => {
+                        let rpl = client.write_stream(node.clone(), volume, at, chunk.to_vec(), expect.clone()).await?;
+                        offset = Some(at + chunk.len() as u64);
+                        (rpl.size, rpl.version)
+                    },
+                    None => {
+                        let rpl = client.append_stream(node.clone(), volume, chunk.to_vec(), expect.clone()).await?;
+                        (rpl.size, rpl.version)
+                    },
+                };
+                size = new_size;
+                version = Some(new_version);
+            }
+            println!("{} bytes, version {}", size, version.map(|v| v.to_string()).unwrap_or_else(|| "unchanged".to_string()));
+        },
+        Some(("truncate", sub_args)) => {
+            let volume = match sub_args.value_of("volume") {
+                Some(val) => Some(str_to_uuid(val)?),
+                None => None,
+            };
+            let node = node_or_path_from_arg(sub_args.value_of("NODE").expect("missing node"));
+            let size = u64_arg(sub_args, "SIZE")?.expect("missing size");
+            let rpl = client.truncate_stream(node, volume, size, expected_content_arg(sub_args)?).await?;
+            println!("{} bytes, version {}", rpl.size, rpl.version);
+        },
+        Some(("batch", sub_args)) => {
+            let ops: Vec<DVRequest> = match sub_args.value_of("FILE").expect("missing file") {
+                "-" => serde_json::from_reader(std::io::stdin())?,
+                file => serde_json::from_slice(&tokio::fs::read(file).await?)?,
+            };
+            let rpl = client.batch(ops).await?;
+            println!("{}", serde_json::to_string_pretty(&rpl)?);
+        },
+        _ => {
+            let (time1, time2, time3) = tokio::join!(
+                client.ask_time(),
+                client.ask_time(),
+                client.ask_time());
+            info!("Got time: {:?}", time3);
+            info!("Got time: {:?}", time1);
+            info!("Got time: {:?}", time2);
+        },
+    };
+    Ok(())
+}
 
 async fn real_main() -> i32 {
     let args = clap::Command::new("dv-client")
@@ -16,10 +324,225 @@ async fn real_main() -> i32 {
         )
         .arg(
             clap::Arg::new("ADDR")
-                .help("Address of the datavir full node")
+                .help("Address of the datavir full node (ws://, wss://, coap:// or unix:///path/to/.datavir.socket)")
                 .default_value(DEFAULT_WS_ADDR_URL)
                 .index(1),
         )
+        .arg(
+            clap::Arg::new("key-file")
+                .long("key-file")
+                .takes_value(true)
+                .default_value(DEFAULT_KEY_FILE)
+                .help("File with the secret key used to sign and verify messages"),
+        )
+        .arg(
+            clap::Arg::new("user")
+                .long("user")
+                .takes_value(true)
+                .default_value(DEFAULT_USER_UUID)
+                .help("UUID of the user on whose behalf messages are sent"),
+        )
+        .arg(
+            clap::Arg::new("ca")
+                .long("ca")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("PEM file with CAs to trust for wss:// instead of the system ones"),
+        )
+        .arg(
+            clap::Arg::new("client-cert")
+                .long("client-cert")
+                .takes_value(true)
+                .requires("client-key")
+                .help("PEM certificate to present to the full node"),
+        )
+        .arg(
+            clap::Arg::new("client-key")
+                .long("client-key")
+                .takes_value(true)
+                .requires("client-cert")
+                .help("PEM private key of --client-cert"),
+        )
+        .arg(
+            clap::Arg::new("encoding")
+                .long("encoding")
+                .takes_value(true)
+                .default_value("cbor")
+                .possible_values(["cose", "cbor", "json", "plain"])
+                .help("Message encoding to ask for (JSON is easier to debug, plain is the default for unix:// and needs no key)"),
+        )
+        .arg(
+            clap::Arg::new("ping-interval")
+                .long("ping-interval")
+                .takes_value(true)
+                .default_value("30")
+                .help("Seconds without hearing from the other side before pinging it"),
+        )
+        .arg(
+            clap::Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .takes_value(true)
+                .default_value("90")
+                .help("Seconds without hearing from the other side before giving up on it"),
+        )
+        .arg(
+            clap::Arg::new("request-timeout")
+                .long("request-timeout")
+                .takes_value(true)
+                .default_value("60")
+                .help("Seconds a request may take"),
+        )
+        .arg(
+            clap::Arg::new("reconnect")
+                .long("reconnect")
+                .help("Connect again if the connection to the full node is lost, retrying requests that are safe to retry"),
+        )
+        .subcommand(
+            clap::Command::new("time")
+                .about("Asks the full node what time it is (default)"),
+        )
+        .subcommand(
+            clap::Command::new("hello")
+                .about("Shows the version and capabilities of the full node"),
+        )
+        .subcommand(
+            clap::Command::new("list-volumes")
+                .about("Lists all volumes")
+                .arg(list_order_opt()),
+        )
+        .subcommand(
+            clap::Command::new("list")
+                .about("Lists the nodes in a directory given by UUID or by path")
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(clap::Arg::new("NODE").required(true).index(1))
+                .arg(list_order_opt()),
+        )
+        .subcommand(
+            clap::Command::new("new-volume")
+                .about("Creates a new volume")
+                .arg(clap::Arg::new("NAME").required(true).index(1))
+                .arg(clap::Arg::new("title").long("title").takes_value(true))
+                .arg(clap::Arg::new("pseudo").long("pseudo").help("Creates a pseudo volume instead of a real one")),
+        )
+        .subcommand(
+            clap::Command::new("rename-volume")
+                .about("Changes the name and/or title of a volume")
+                .arg(clap::Arg::new("VOLUME").required(true).index(1))
+                .arg(clap::Arg::new("name").long("name").takes_value(true))
+                .arg(clap::Arg::new("title").long("title").takes_value(true)),
+        )
+        .subcommand(
+            clap::Command::new("delete-volume")
+                .about("Deletes a volume and everything in it")
+                .arg(clap::Arg::new("VOLUME").required(true).index(1)),
+        )
+        .subcommand(
+            clap::Command::new("create")
+                .about("Creates a node below a parent given by UUID or by path")
+                .arg(clap::Arg::new("PARENT").required(true).index(1))
+                .arg(clap::Arg::new("NAME").required(true).index(2))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the paths are relative to"))
+                .arg(clap::Arg::new("kind").long("kind").takes_value(true).default_value("empty")
+                    .possible_values(["empty", "regular", "symbolic-link", "hard-link", "socket"]))
+                .arg(clap::Arg::new("uuid").long("uuid").takes_value(true))
+                .arg(clap::Arg::new("title").long("title").takes_value(true))
+                .arg(clap::Arg::new("description").long("description").takes_value(true))
+                .arg(clap::Arg::new("target").long("target").takes_value(true).help("What a symbolic link points to"))
+                .arg(clap::Arg::new("link-to").long("link-to").takes_value(true).help("Node a hard link shares its content with")),
+        )
+        .subcommand(
+            clap::Command::new("rename")
+                .about("Changes the name, title and/or description of a node")
+                .arg(clap::Arg::new("NODE").required(true).index(1))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(clap::Arg::new("name").long("name").takes_value(true))
+                .arg(clap::Arg::new("title").long("title").takes_value(true))
+                .arg(clap::Arg::new("description").long("description").takes_value(true)),
+        )
+        .subcommand(
+            clap::Command::new("move")
+                .about("Moves a node below another one")
+                .arg(clap::Arg::new("NODE").required(true).index(1))
+                .arg(clap::Arg::new("PARENT").required(true).index(2))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the paths are relative to"))
+                .arg(clap::Arg::new("name").long("name").takes_value(true).help("New name, the old one is kept otherwise")),
+        )
+        .subcommand(
+            clap::Command::new("clone")
+                .about("Copies a node below another one, sharing contents until either copy changes")
+                .arg(clap::Arg::new("NODE").required(true).index(1))
+                .arg(clap::Arg::new("PARENT").required(true).index(2))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the paths are relative to"))
+                .arg(clap::Arg::new("name").long("name").takes_value(true).help("Name of the copy, the same as the original otherwise"))
+                .arg(clap::Arg::new("recursive").long("recursive").short('r').help("Also copies everything below it")),
+        )
+        .subcommand(
+            clap::Command::new("delete")
+                .about("Deletes a node")
+                .arg(clap::Arg::new("NODE").required(true).index(1))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(clap::Arg::new("recursive").long("recursive").short('r').help("Also deletes everything below it")),
+        )
+        .subcommand(
+            clap::Command::new("node-info")
+                .about("Shows information about nodes given by UUID or by path")
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the paths are relative to"))
+                .arg(clap::Arg::new("ITEMS").required(true).multiple_values(true).index(1)),
+        )
+        .subcommand(
+            clap::Command::new("stream-hash")
+                .about("Shows the digest of the content of nodes given by UUID or by path")
+                .arg(clap::Arg::new("alg").long("alg").takes_value(true).default_value("sha-256")
+                    .possible_values(["sha-256", "sha-1", "blake3", "md5"]))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the paths are relative to"))
+                .arg(clap::Arg::new("ITEMS").required(true).multiple_values(true).index(1)),
+        )
+        .subcommand(
+            clap::Command::new("upload")
+                .about("Replaces the content of a node with a local file")
+                .arg(clap::Arg::new("FILE").required(true).index(1))
+                .arg(clap::Arg::new("NODE").required(true).index(2))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(clap::Arg::new("resume").long("resume").help("Continues an interrupted upload")),
+        )
+        .subcommand(
+            clap::Command::new("download")
+                .about("Copies the content of a node into a local file")
+                .arg(clap::Arg::new("NODE").required(true).index(1))
+                .arg(clap::Arg::new("FILE").required(true).index(2))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(clap::Arg::new("resume").long("resume").help("Continues after whatever FILE already holds")),
+        )
+        .subcommand(
+            clap::Command::new("write")
+                .about("Writes a local file into the content of a node at some offset")
+                .arg(clap::Arg::new("FILE").required(true).index(1))
+                .arg(clap::Arg::new("NODE").required(true).index(2))
+                .arg(clap::Arg::new("offset").long("offset").takes_value(true).required(true).validator(|val| val.parse::<u64>()))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(expect_version_opt()),
+        )
+        .subcommand(
+            clap::Command::new("append")
+                .about("Adds a local file to the end of the content of a node")
+                .arg(clap::Arg::new("FILE").required(true).index(1))
+                .arg(clap::Arg::new("NODE").required(true).index(2))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(expect_version_opt()),
+        )
+        .subcommand(
+            clap::Command::new("truncate")
+                .about("Cuts the content of a node down to SIZE bytes, or grows it with zeros")
+                .arg(clap::Arg::new("NODE").required(true).index(1))
+                .arg(clap::Arg::new("SIZE").required(true).index(2).validator(|val| val.parse::<u64>()))
+                .arg(clap::Arg::new("volume").long("volume").takes_value(true).help("Volume the path is relative to"))
+                .arg(expect_version_opt()),
+        )
+        .subcommand(
+            clap::Command::new("batch")
+                .about("Runs requests read from a JSON array so that either all or none of them take effect")
+                .arg(clap::Arg::new("FILE").required(true).index(1).help("File with the requests, - for stdin")),
+        )
         .get_matches();
 
     // Setup and test logger
@@ -31,9 +554,62 @@ async fn real_main() -> i32 {
     trace!("TRACE output enabled.");
     debug!("Arg matches: {:?}", args);
 
-    // TODO: run multiple parallel requests
+    let user = match str_to_uuid(args.value_of("user").expect("missing user")) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Invalid user UUID: {:?}", err);
+            return 1;
+        }
+    };
+    let addr = args.value_of("ADDR").expect("missing address");
+    // Local tools don't sign their messages unless asked to
+    let encoding = match (args.occurrences_of("encoding"), addr.starts_with("unix:")) {
+        (0, true) => Encoding::Plain,
+        _ => Encoding::from_name(args.value_of("encoding").expect("missing encoding")).unwrap_or_default(),
+    };
+    let issuer = Issuer::new(user, "dv-client");
+    let codec = match encoding {
+        Encoding::Plain => JwtCodec::without_key(issuer),
+        _ => {
+            let key_file = Path::new(args.value_of("key-file").expect("missing key file"));
+            match JwtCodec::from_key_file(key_file, issuer) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("Failed to load signing key: {:?}", err);
+                    return 1;
+                }
+            }
+        },
+    };
+
+    let mut tls = TlsClientConfig::default();
+    for ca_file in args.values_of("ca").into_iter().flatten() {
+        tls = tls.with_ca(Path::new(ca_file));
+    }
+    if let (Some(cert_file), Some(key_file)) = (args.value_of("client-cert"), args.value_of("client-key")) {
+        tls = tls.with_client_cert(Path::new(cert_file), Path::new(key_file));
+    }
+
+    let timeouts = match (seconds_arg(&args, "ping-interval"), seconds_arg(&args, "idle-timeout"), seconds_arg(&args, "request-timeout")) {
+        (Some(ping_interval), Some(idle_timeout), Some(request_timeout)) => Timeouts{
+            ping_interval,
+            idle_timeout,
+            request_timeout,
+        },
+        _ => return 1,
+    };
+
+    let config = WSClientConfig{
+        tls,
+        encoding,
+        timeouts,
+        reconnect: match args.is_present("reconnect") {
+            true => Some(Reconnect::default()),
+            false => None,
+        },
+    };
 
-    let client = match WSClient::new(args.value_of("ADDR").expect("missing address")).await {
+    let client = match WSClient::new_with_config(addr, codec, config).await {
         Ok(v) => v,
         Err(err) => {
             error!("Failed to start WSClient: {:?}", err);
@@ -41,18 +617,17 @@ async fn real_main() -> i32 {
         }
     };
 
-    {
-        let time1 = client.ask_time();
-        let time2 = client.ask_time();
-        let time3 = client.ask_time();
-        info!("Got time: {:?}", time3.await);
-        info!("Got time: {:?}", time1.await);
-        info!("Got time: {:?}", time2.await);
-    }
+    let ans = match run_command(&client, &args).await {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("Command failed: {:?}", err);
+            3
+        }
+    };
 
     client.close().await.expect("Failed to close WSClient");
 
-    0
+    ans
 }
 
 #[tokio::main]
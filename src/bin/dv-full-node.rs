@@ -1,6 +1,59 @@
 #[allow(unused_imports)]
 use datavir::prelude::*;
+use datavir::jwt::{Issuer, JwtCodec};
 use datavir::ws_server::WSServer;
+use datavir::coap_server::CoapServer;
+use datavir::dispatcher::Dispatcher;
+use datavir::schema::open_database;
+use datavir::net::{Timeouts, TlsServerConfig};
+use datavir::messages::Feature;
+
+/// Reads an argument given in seconds.
+fn seconds_arg(args: &clap::ArgMatches, name: &str) -> Option<std::time::Duration> {
+    match args.value_of(name).expect("missing argument with a default").parse::<u64>() {
+        Ok(v) => Some(std::time::Duration::from_secs(v)),
+        Err(err) => {
+            error!("Invalid --{}: {:?}", name, err);
+            None
+        }
+    }
+}
+
+/// Runs the main loop of a server that may not have been asked for.
+async fn run_optional<F: std::future::Future<Output = DVResult<()>>>(main_loop: Option<F>) -> DVResult<()> {
+    match main_loop {
+        Some(main_loop) => main_loop.await,
+        None => Ok(()),
+    }
+}
+
+/// Waits for SIGINT or SIGTERM and returns its name.
+async fn termination_signal() -> IOResult<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigint.recv() => Ok("SIGINT"),
+        _ = sigterm.recv() => Ok("SIGTERM"),
+    }
+}
+
+/// Asks the servers to shut down on the first signal and gives up on them
+/// on the second.
+async fn handle_signals(shutdown_tx: tokio::sync::watch::Sender<bool>) {
+    match termination_signal().await {
+        Ok(name) => info!("Got {}, shutting down", name),
+        Err(err) => {
+            error!("Failed to set up signal handlers: {:?}", err);
+            return;
+        }
+    }
+    let _ = shutdown_tx.send(true);
+    if let Ok(name) = termination_signal().await {
+        warn!("Got {} again, exiting without waiting for connections", name);
+        std::process::exit(130);
+    }
+}
 
 async fn real_main() -> i32 {
     let args = clap::Command::new("dv-full-node")
@@ -20,6 +73,114 @@ async fn real_main() -> i32 {
                 .default_value(DEFAULT_WS_ADDR)
                 .index(1),
         )
+        .arg(
+            clap::Arg::new("key-file")
+                .long("key-file")
+                .takes_value(true)
+                .default_value(DEFAULT_KEY_FILE)
+                .help("File with the secret key used to sign and verify messages"),
+        )
+        .arg(
+            clap::Arg::new("user")
+                .long("user")
+                .takes_value(true)
+                .default_value(DEFAULT_USER_UUID)
+                .help("UUID of the user on whose behalf messages are sent"),
+        )
+        .arg(
+            clap::Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .default_value(DEFAULT_DB_FILE)
+                .help("SQLite database with all the metadata"),
+        )
+        .arg(
+            clap::Arg::new("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires("tls-key")
+                .help("PEM certificate chain, serves wss:// instead of ws://"),
+        )
+        .arg(
+            clap::Arg::new("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("PEM private key of --tls-cert"),
+        )
+        .arg(
+            clap::Arg::new("tls-client-ca")
+                .long("tls-client-ca")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("Only accepts clients with a certificate signed by one of the CAs in this PEM file"),
+        )
+        .arg(
+            clap::Arg::new("coap")
+                .long("coap")
+                .takes_value(true)
+                .min_values(0)
+                .default_missing_value(DEFAULT_COAP_ADDR)
+                .help("Also serves CoAP over UDP on this address (default: 127.0.0.1:5683)"),
+        )
+        .arg(
+            clap::Arg::new("socket")
+                .long("socket")
+                .takes_value(true)
+                .min_values(0)
+                .default_missing_value(DEFAULT_SOCKET_FILE)
+                .help("Also listens on this Unix socket, where local tools need no key (default: .datavir.socket)"),
+        )
+        .arg(
+            clap::Arg::new("socket-mode")
+                .long("socket-mode")
+                .takes_value(true)
+                .default_value("600")
+                .help("Octal permissions of the --socket file, which decide who may connect"),
+        )
+        .arg(
+            clap::Arg::new("socket-uid")
+                .long("socket-uid")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .validator(|val| val.parse::<u32>())
+                .help("Only lets this user connect to the --socket (may be repeated)"),
+        )
+        .arg(
+            clap::Arg::new("shutdown-timeout")
+                .long("shutdown-timeout")
+                .takes_value(true)
+                .default_value("10")
+                .help("Seconds open connections get to finish their requests on SIGINT or SIGTERM"),
+        )
+        .arg(
+            clap::Arg::new("ping-interval")
+                .long("ping-interval")
+                .takes_value(true)
+                .default_value("30")
+                .help("Seconds without hearing from the other side before pinging it"),
+        )
+        .arg(
+            clap::Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .takes_value(true)
+                .default_value("90")
+                .help("Seconds without hearing from the other side before giving up on it"),
+        )
+        .arg(
+            clap::Arg::new("request-timeout")
+                .long("request-timeout")
+                .takes_value(true)
+                .default_value("60")
+                .help("Seconds a request may take"),
+        )
+        .arg(
+            clap::Arg::new("max-in-flight")
+                .long("max-in-flight")
+                .takes_value(true)
+                .default_value("16")
+                .help("Requests a single client may have the node work on at once"),
+        )
         .get_matches();
 
     // Setup and test logger
@@ -31,15 +192,144 @@ async fn real_main() -> i32 {
     trace!("TRACE output enabled.");
     debug!("Arg matches: {:?}", args);
 
-    let mut server = WSServer::new(args.value_of("ADDR").expect("missing address"));
+    let user = match str_to_uuid(args.value_of("user").expect("missing user")) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Invalid user UUID: {:?}", err);
+            return 1;
+        }
+    };
+    let key_file = Path::new(args.value_of("key-file").expect("missing key file"));
+    let codec = match JwtCodec::from_key_file(key_file, Issuer::new(user, "dv-full-node")) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Failed to load signing key: {:?}", err);
+            return 1;
+        }
+    };
+
+    let drain_timeout = match seconds_arg(&args, "shutdown-timeout") {
+        Some(v) => v,
+        None => return 1,
+    };
+    let timeouts = match (seconds_arg(&args, "ping-interval"), seconds_arg(&args, "idle-timeout"), seconds_arg(&args, "request-timeout")) {
+        (Some(ping_interval), Some(idle_timeout), Some(request_timeout)) => Timeouts{
+            ping_interval,
+            idle_timeout,
+            request_timeout,
+        },
+        _ => return 1,
+    };
+    let max_in_flight = match args.value_of("max-in-flight").expect("missing argument with a default").parse::<usize>() {
+        Ok(v) if v > 0 => v,
+        Ok(_) => {
+            error!("Invalid --max-in-flight: must be at least 1");
+            return 1;
+        },
+        Err(err) => {
+            error!("Invalid --max-in-flight: {:?}", err);
+            return 1;
+        }
+    };
+    let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+
+    let db_path = Path::new(args.value_of("db").expect("missing database path"));
+    let db = match open_database(db_path) {
+        Ok(v) => v,
+        Err(_err) => return 1,
+    };
+    let mut features = Vec::new();
+    if args.is_present("tls-cert") {
+        features.push(Feature::Tls);
+    }
+    if args.is_present("tls-client-ca") {
+        features.push(Feature::ClientCertificates);
+    }
+    if args.is_present("coap") {
+        features.push(Feature::Coap);
+    }
+    if args.is_present("socket") {
+        features.push(Feature::UnixSocket);
+    }
+    let dispatcher = Arc::new(Dispatcher::new(db).with_features(features));
+
+    let coap_server = match args.value_of("coap") {
+        Some(addr) => {
+            let mut coap_server = CoapServer::new(addr, codec.clone(), dispatcher.clone())
+                .with_shutdown(shutdown.clone(), drain_timeout)
+                .with_max_in_flight(max_in_flight);
+            if let Err(_err) = coap_server.prepare().await {
+                return 1;
+            }
+            Some(coap_server)
+        },
+        None => None,
+    };
+
+    let unix_server = match args.value_of("socket") {
+        Some(path) => {
+            let mode = match u32::from_str_radix(args.value_of("socket-mode").expect("missing socket mode"), 8) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("Invalid socket mode: {:?}", err);
+                    return 1;
+                }
+            };
+            let allowed_uids = match args.values_of("socket-uid").into_iter().flatten().map(|val| val.parse::<u32>()).collect() {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("Invalid socket uid: {:?}", err);
+                    return 1;
+                }
+            };
+            let mut unix_server = WSServer::new_unix(Path::new(path), mode, codec.clone(), dispatcher.clone())
+                .with_allowed_uids(allowed_uids)
+                .with_shutdown(shutdown.clone(), drain_timeout)
+                .with_timeouts(timeouts)
+                .with_max_in_flight(max_in_flight);
+            if let Err(_err) = unix_server.prepare().await {
+                return 1;
+            }
+            Some(unix_server)
+        },
+        None => None,
+    };
+
+    let mut server = WSServer::new(args.value_of("ADDR").expect("missing address"), codec, dispatcher.clone())
+        .with_shutdown(shutdown, drain_timeout)
+        .with_timeouts(timeouts)
+        .with_max_in_flight(max_in_flight);
+    if let (Some(cert_file), Some(key_file)) = (args.value_of("tls-cert"), args.value_of("tls-key")) {
+        let mut tls = TlsServerConfig::new(Path::new(cert_file), Path::new(key_file));
+        if let Some(client_ca) = args.value_of("tls-client-ca") {
+            tls = tls.with_client_ca(Path::new(client_ca));
+        }
+        server = match tls.acceptor() {
+            Ok(acceptor) => server.with_tls(acceptor),
+            Err(err) => {
+                error!("Failed to set up TLS: {:?}", err);
+                return 1;
+            }
+        };
+    }
     if let Err(_err) = server.prepare().await {
         return 1;
     }
-    if let Err(_err) = server.main_loop().await {
-        return 2;
+    tokio::spawn(handle_signals(shutdown_tx));
+    let res = tokio::try_join!(
+        server.main_loop(),
+        run_optional(coap_server.as_ref().map(|server| server.main_loop())),
+        run_optional(unix_server.as_ref().map(|server| server.main_loop())),
+    );
+    let mut status = 0;
+    if let Err(_err) = res {
+        status = 2;
     }
-
-    0
+    if let Err(_err) = dispatcher.checkpoint() {
+        status = 2;
+    }
+    info!("DataVir Full Node v{} stopped", DATAVIR_VERSION);
+    status
 }
 
 #[tokio::main]
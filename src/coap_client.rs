@@ -0,0 +1,316 @@
+use crate::prelude::*;
+use crate::messages::*;
+use crate::coap_server::{content_format, COAP_MAX_DATAGRAM, COAP_MESSAGE_PATH, COAP_NOTICES_PATH};
+use crate::jwt::{Encoding, JwtCodec};
+use crate::ws_client::{WSClientConfig, WSRequestBundle};
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType, ResponseType};
+use coap_lite::block_handler::BlockValue;
+use rand::Rng;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+
+/// Port of `coap://` URLs that don't say.
+pub const COAP_DEFAULT_PORT: u16 = 5683;
+/// Payload bytes per block when a message needs block-wise transfer.
+pub const COAP_BLOCK_SIZE: usize = 1024;
+/// Transmission parameters from RFC 7252.
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+/// How long to wait for a response once the request was acknowledged.
+const SEPARATE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(247);
+/// Registrations for notices are renewed this often, which also keeps our
+/// session alive on the node (see `COAP_PEER_TIMEOUT`).
+const OBSERVE_REFRESH: Duration = Duration::from_secs(150);
+
+/// Whatever woke up `CoapClientInner::run`.
+enum CoapEvent {
+	Request(Option<Box<WSRequestBundle>>),
+	Datagram(IOResult<usize>),
+	Refresh,
+}
+
+/// Does for `coap://` URLs what `WSClientInner` does for WebSockets.
+///
+/// Exchanges happen one at a time (NSTART = 1 in RFC 7252), which also keeps
+/// upload chunks in order.
+#[derive(Debug)]
+pub(crate) struct CoapClientInner {
+	addr: String,
+	codec: Arc<JwtCodec>,
+	recv_ch: mpsc::Receiver<WSRequestBundle>,
+	notices_tx: broadcast::Sender<DVNotice>,
+	socket: UdpSocket,
+	next_message_id: u16,
+	/// Token of our registration for notices.
+	observe_token: Vec<u8>,
+	_marker: PhantomPinned,
+}
+
+impl CoapClientInner {
+	pub async fn new(addr: String, config: &WSClientConfig, codec: Arc<JwtCodec>, notices_tx: broadcast::Sender<DVNotice>) -> DVResult<(CoapClientInner, mpsc::Sender<WSRequestBundle>, Encoding)> {
+		let url = match url::Url::parse(&addr) {
+			Ok(v) => v,
+			Err(_) => return Err(DVError::InvalidUrl(addr)),
+		};
+		let peer = match url.socket_addrs(|| Some(COAP_DEFAULT_PORT))?.into_iter().next() {
+			Some(v) => v,
+			None => return Err(DVError::InvalidUrl(addr)),
+		};
+		let socket = UdpSocket::bind(if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+		socket.connect(peer).await?;
+		let (tx, rx) = mpsc::channel(10);
+		let mut inner = CoapClientInner{
+			addr,
+			codec,
+			recv_ch: rx,
+			notices_tx,
+			socket,
+			next_message_id: rand::random(),
+			observe_token: rand::random::<[u8; 4]>().to_vec(),
+			_marker: PhantomPinned,
+		};
+		// UDP has no handshake, this is how we find out whether anybody is there
+		if let Err(err) = inner.observe(true).await {
+			error!("Failed to connect to {}: {:?}", inner.addr, err);
+			return Err(err);
+		}
+		debug!("Talking CoAP to {} (encoding: {:?})", inner.addr, config.encoding);
+		Ok((inner, tx, config.encoding))
+	}
+
+	pub async fn run(mut self) {
+		let mut refresh = tokio::time::interval_at(Instant::now() + OBSERVE_REFRESH, OBSERVE_REFRESH);
+		let mut buf = vec![0u8; COAP_MAX_DATAGRAM];
+		loop {
+			let event = tokio::select! {
+				item = self.recv_ch.recv() => CoapEvent::Request(item.map(Box::new)),
+				res = self.socket.recv(&mut buf) => CoapEvent::Datagram(res),
+				_ = refresh.tick() => CoapEvent::Refresh,
+			};
+			match event {
+				CoapEvent::Request(Some(msg)) => {
+					trace!("Sending reqId={} ({} bytes)", msg.req_id, msg.data.len());
+					if msg.close_ws {
+						info!("Closing CoapClientInner");
+						if let Err(err) = self.observe(false).await {
+							warn!("Failed to stop observing {}: {:?}", self.addr, err);
+						}
+						if msg.return_ch.send(Ok(Vec::new())).is_err() {
+							error!("Failed to notify of CoAP closure");
+						}
+						return
+					}
+					let rpl = self.transfer(&msg.data, msg.encoding).await;
+					if let Err(err) = &rpl {
+						error!("CoAP request reqId={} to {} failed: {:?}", msg.req_id, self.addr, err);
+					}
+					if msg.return_ch.send(rpl).is_err() {
+						warn!("Nobody is waiting for reqId={} anymore", msg.req_id);
+					}
+				},
+				CoapEvent::Request(None) => {
+					warn!("WSClient is gone, closing CoapClientInner");
+					if let Err(err) = self.observe(false).await {
+						warn!("Failed to stop observing {}: {:?}", self.addr, err);
+					}
+					return
+				},
+				CoapEvent::Datagram(Ok(len)) => match Packet::from_bytes(&buf[..len]) {
+					Ok(packet) => self.handle_incoming(packet).await,
+					Err(err) => debug!("Ignoring bad datagram from {}: {:?}", self.addr, err),
+				},
+				CoapEvent::Datagram(Err(err)) => warn!("Failed to receive datagram from {}: {:?}", self.addr, err),
+				CoapEvent::Refresh => if let Err(err) = self.observe(true).await {
+					warn!("Failed to renew observation of {}: {:?}", self.addr, err);
+				},
+			}
+		}
+	}
+
+	fn new_packet(&mut self, token: &[u8], method: RequestType, path: &str) -> Packet {
+		let mut packet = Packet::new();
+		packet.header.set_type(MessageType::Confirmable);
+		packet.header.code = MessageClass::Request(method);
+		packet.header.message_id = self.next_message_id;
+		self.next_message_id = self.next_message_id.wrapping_add(1);
+		packet.set_token(token.to_vec());
+		packet.add_option(CoapOption::UriPath, path.as_bytes().to_vec());
+		packet
+	}
+
+	async fn send_packet(&self, packet: &Packet) -> DVResult<()> {
+		let bytes = match packet.to_bytes_unlimited() {
+			Ok(v) => v,
+			Err(err) => return Err(DVError::CoapError(format!("{:?}", err))),
+		};
+		self.socket.send(&bytes).await?;
+		Ok(())
+	}
+
+	/// Sends a confirmable request and waits for its response, retransmitting
+	/// as RFC 7252 says. Notices that show up meanwhile are passed on.
+	async fn exchange(&mut self, packet: Packet) -> DVResult<Packet> {
+		let message_id = packet.header.message_id;
+		let token = packet.get_token().to_vec();
+		let mut timeout = ACK_TIMEOUT.mul_f64(rand::thread_rng().gen_range(1.0..1.5));
+		let mut retransmissions = 0;
+		let mut acked = false;
+		let mut buf = vec![0u8; COAP_MAX_DATAGRAM];
+		self.send_packet(&packet).await?;
+		let mut deadline = Instant::now() + timeout;
+		loop {
+			let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+				Ok(res) => res?,
+				Err(_) => {
+					if acked || retransmissions >= MAX_RETRANSMIT {
+						return Err(DVError::CoapError(format!("no response from {}", self.addr)));
+					}
+					retransmissions += 1;
+					timeout *= 2;
+					self.send_packet(&packet).await?;
+					deadline = Instant::now() + timeout;
+					continue;
+				}
+			};
+			let reply = match Packet::from_bytes(&buf[..len]) {
+				Ok(v) => v,
+				Err(err) => {
+					debug!("Ignoring bad datagram from {}: {:?}", self.addr, err);
+					continue;
+				}
+			};
+			match reply.header.get_type() {
+				MessageType::Acknowledgement if reply.header.message_id == message_id => {
+					if reply.header.code != MessageClass::Empty {
+						return Ok(reply);
+					}
+					// The response will come on its own
+					acked = true;
+					deadline = Instant::now() + SEPARATE_RESPONSE_TIMEOUT;
+				},
+				MessageType::Reset if reply.header.message_id == message_id => {
+					return Err(DVError::CoapError(format!("{} rejected the request", self.addr)));
+				},
+				MessageType::Confirmable | MessageType::NonConfirmable if reply.get_token() == token.as_slice() => {
+					self.acknowledge(&reply).await?;
+					return Ok(reply);
+				},
+				_ => self.handle_incoming(reply).await,
+			}
+		}
+	}
+
+	async fn acknowledge(&self, packet: &Packet) -> DVResult<()> {
+		if packet.header.get_type() != MessageType::Confirmable {
+			return Ok(());
+		}
+		let mut ack = Packet::new();
+		ack.header.set_type(MessageType::Acknowledgement);
+		ack.header.code = MessageClass::Empty;
+		ack.header.message_id = packet.header.message_id;
+		self.send_packet(&ack).await
+	}
+
+	/// Anything that isn't the response we are waiting for: notices, mostly.
+	async fn handle_incoming(&mut self, packet: Packet) {
+		if let Err(err) = self.acknowledge(&packet).await {
+			warn!("Failed to acknowledge message from {}: {:?}", self.addr, err);
+		}
+		if packet.get_token() != self.observe_token.as_slice() {
+			// Notifications for a registration we don't know about get a reset
+			if packet.get_observe_value().is_some() {
+				let mut rst = Packet::new();
+				rst.header.set_type(MessageType::Reset);
+				rst.header.code = MessageClass::Empty;
+				rst.header.message_id = packet.header.message_id;
+				let _ = self.send_packet(&rst).await;
+			}
+			return;
+		}
+		// Such as 5.03 from a node that is shutting down
+		if packet.header.code != MessageClass::Response(ResponseType::Content) {
+			info!("{} stopped sending notices ({:?})", self.addr, packet.header.code);
+			return;
+		}
+		if packet.payload.is_empty() {
+			return;
+		}
+		match self.codec.decode(&packet.payload) {
+			Ok(claims) => match claims.msg {
+				DVMessage::Ntc(ntc) => {
+					// An error only means nobody is listening right now
+					let _ = self.notices_tx.send(ntc);
+				},
+				other => warn!("Got unexpected notification from {}: {:?}", self.addr, other),
+			},
+			Err(err) => warn!("Rejected notification from {}: {:?}", self.addr, err),
+		}
+	}
+
+	/// Registers for notices (or stops getting them).
+	async fn observe(&mut self, register: bool) -> DVResult<()> {
+		let token = self.observe_token.clone();
+		let mut packet = self.new_packet(&token, RequestType::Get, COAP_NOTICES_PATH);
+		// 0 registers, 1 deregisters (RFC 7641)
+		packet.set_observe_value(if register { 0 } else { 1 });
+		let reply = self.exchange(packet).await?;
+		match reply.header.code {
+			MessageClass::Response(ResponseType::Content) => Ok(()),
+			code => Err(DVError::CoapError(format!("{} answered {:?}", self.addr, code))),
+		}
+	}
+
+	/// Sends one token and returns the one that comes back, block by block
+	/// when they don't fit in a datagram.
+	async fn transfer(&mut self, payload: &[u8], encoding: Encoding) -> DVResult<Vec<u8>> {
+		let token = rand::random::<[u8; 4]>().to_vec();
+		let blocks: Vec<&[u8]> = match payload.len() > COAP_BLOCK_SIZE {
+			true => payload.chunks(COAP_BLOCK_SIZE).collect(),
+			false => vec![payload],
+		};
+		let mut reply = None;
+		for (num, block) in blocks.iter().enumerate() {
+			let more = num + 1 < blocks.len();
+			let mut packet = self.new_packet(&token, RequestType::Post, COAP_MESSAGE_PATH);
+			packet.set_content_format(content_format(encoding));
+			if blocks.len() > 1 {
+				packet.add_option_as(CoapOption::Block1, block_value(num, more, COAP_BLOCK_SIZE)?);
+			}
+			packet.payload = block.to_vec();
+			let response = self.exchange(packet).await?;
+			match (response.header.code, more) {
+				(MessageClass::Response(ResponseType::Continue), true) => continue,
+				(MessageClass::Response(ResponseType::Content), false) => reply = Some(response),
+				(code, _) => return Err(DVError::CoapError(format!("{} answered {:?}", self.addr, code))),
+			}
+		}
+		let mut reply = match reply {
+			Some(v) => v,
+			None => return Err(DVError::CoapError("nothing to send".to_string())),
+		};
+		let mut data = Vec::new();
+		loop {
+			data.extend_from_slice(&reply.payload);
+			let block2 = reply.get_first_option_as::<BlockValue>(CoapOption::Block2).and_then(|val| val.ok());
+			let block2 = match block2 {
+				Some(block) if block.more => block,
+				_ => return Ok(data),
+			};
+			let mut packet = self.new_packet(&token, RequestType::Post, COAP_MESSAGE_PATH);
+			packet.add_option_as(CoapOption::Block2, block_value(usize::from(block2.num) + 1, false, block2.size())?);
+			reply = self.exchange(packet).await?;
+			if reply.header.code != MessageClass::Response(ResponseType::Content) {
+				return Err(DVError::CoapError(format!("{} answered {:?}", self.addr, reply.header.code)));
+			}
+		}
+	}
+}
+
+fn block_value(num: usize, more: bool, size: usize) -> DVResult<BlockValue> {
+	match BlockValue::new(num, more, size) {
+		Ok(v) => Ok(v),
+		Err(err) => Err(DVError::CoapError(format!("{:?}", err))),
+	}
+}
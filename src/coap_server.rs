@@ -0,0 +1,549 @@
+use crate::prelude::*;
+use crate::messages::*;
+use crate::dispatcher::{Dispatcher, Session};
+use crate::jwt::{Encoding, JwtCodec};
+use crate::net::{self, ShutdownSignal};
+use coap_lite::{BlockHandler, BlockHandlerConfig, CoapOption, CoapRequest, ContentFormat, MessageClass, MessageType, ObserveOption, Packet, RequestType, ResponseType};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+
+/// Resource that takes a signed request (POST) and answers with a signed reply.
+pub const COAP_MESSAGE_PATH: &str = "dv";
+/// Resource to observe (GET with `Observe: 0`) for notices.
+pub const COAP_NOTICES_PATH: &str = "notices";
+/// Peers that send nothing for this long lose their session (and with it
+/// their subscriptions).
+pub const COAP_PEER_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long a reply is kept in case its request is retransmitted
+/// (EXCHANGE_LIFETIME in RFC 7252).
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+/// Largest datagram we are willing to read.
+pub(crate) const COAP_MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Content-Format of a token: COSE_Mac0, CBOR, plain text for JWTs or JSON.
+pub(crate) fn content_format(encoding: Encoding) -> ContentFormat {
+	match encoding {
+		Encoding::Cose => ContentFormat::ApplicationCoseMac0,
+		Encoding::Cbor => ContentFormat::ApplicationCBOR,
+		Encoding::Json => ContentFormat::TextPlain,
+		Encoding::Plain => ContentFormat::ApplicationJSON,
+	}
+}
+
+/// What we remember about a CoAP client between datagrams.
+#[derive(Debug)]
+struct CoapPeer {
+	session: Arc<Session>,
+	/// Encoding of its last request, also used for the notices it gets.
+	encoding: Encoding,
+	last_seen: Instant,
+	/// Token of the GET that registered it as an observer of notices.
+	observe_token: Option<Vec<u8>>,
+	observe_seq: u32,
+	/// One permit per request it may have us working on at once.
+	in_flight: Arc<Semaphore>,
+}
+
+impl CoapPeer {
+	fn new(max_in_flight: usize) -> CoapPeer {
+		CoapPeer{
+			session: Arc::new(Session::new()),
+			encoding: Encoding::Cose,
+			last_seen: Instant::now(),
+			observe_token: None,
+			observe_seq: 0,
+			in_flight: Arc::new(Semaphore::new(max_in_flight)),
+		}
+	}
+
+	fn next_observe_seq(&mut self) -> u32 {
+		let seq = self.observe_seq;
+		// The Observe option only has 24 bits
+		self.observe_seq = (self.observe_seq + 1) & 0xff_ffff;
+		seq
+	}
+}
+
+/// When a reply was sent and its bytes, if it was sent already.
+type RecentReply = (Instant, Option<Vec<u8>>);
+
+struct CoapState {
+	peers: HashMap<SocketAddr, CoapPeer>,
+	blocks: BlockHandler<SocketAddr>,
+	/// Replies by peer and message ID, `None` while still being worked on.
+	recent: HashMap<(SocketAddr, u16), RecentReply>,
+	next_message_id: u16,
+	max_in_flight: usize,
+}
+
+impl CoapState {
+	fn new() -> CoapState {
+		CoapState{
+			peers: HashMap::new(),
+			blocks: BlockHandler::new(BlockHandlerConfig::default()),
+			recent: HashMap::new(),
+			next_message_id: rand::random(),
+			max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+		}
+	}
+
+	/// The peer at `addr`, which has just been heard from.
+	fn peer(&mut self, addr: SocketAddr) -> &mut CoapPeer {
+		let max_in_flight = self.max_in_flight;
+		let peer = self.peers.entry(addr).or_insert_with(|| CoapPeer::new(max_in_flight));
+		peer.last_seen = Instant::now();
+		peer
+	}
+
+	fn expire(&mut self) {
+		let now = Instant::now();
+		self.peers.retain(|addr, peer| {
+			let keep = now.duration_since(peer.last_seen) < COAP_PEER_TIMEOUT;
+			if !keep {
+				info!("Forgetting CoAP peer {}", addr);
+			}
+			keep
+		});
+		self.recent.retain(|_, (when, _)| now.duration_since(*when) < EXCHANGE_LIFETIME);
+	}
+}
+
+/// Serves the same messages as `WSServer`, one token per CoAP request.
+///
+/// Signed requests are POSTed to `/dv` and the signed reply comes back as
+/// the response payload. Large messages use block-wise transfer (RFC 7959)
+/// and notices go to whoever observes `/notices` (RFC 7641).
+pub struct CoapServer {
+	addr: String,
+	socket: Option<Arc<UdpSocket>>,
+	dispatcher: Arc<Dispatcher>,
+	codec: Arc<JwtCodec>,
+	state: Arc<Mutex<CoapState>>,
+	shutdown: ShutdownSignal,
+	/// How long requests being handled get to finish once `shutdown` fires.
+	drain_timeout: Duration,
+}
+
+impl std::fmt::Debug for CoapServer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("CoapServer")
+			.field("addr", &self.addr)
+			.field("open", &self.socket.is_some())
+			.field("drain_timeout", &self.drain_timeout)
+			.finish_non_exhaustive()
+	}
+}
+
+impl CoapServer {
+	pub fn new(addr: &str, codec: JwtCodec, dispatcher: Arc<Dispatcher>) -> CoapServer {
+		CoapServer{
+			addr: addr.to_string(),
+			socket: None,
+			dispatcher,
+			codec: Arc::new(codec),
+			state: Arc::new(Mutex::new(CoapState::new())),
+			shutdown: net::no_shutdown(),
+			drain_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+		}
+	}
+
+	/// Lets each peer have up to `max_in_flight` requests handled at once.
+	/// Past that, requests are answered with 5.03 (Service Unavailable) and
+	/// a Max-Age of one second, after which they may be sent again.
+	pub fn with_max_in_flight(self, max_in_flight: usize) -> CoapServer {
+		self.state.lock().unwrap().max_in_flight = max_in_flight;
+		self
+	}
+
+	/// Stops reading requests once `shutdown` fires, gives the ones being
+	/// handled up to `drain_timeout` to be answered and ends every
+	/// observation.
+	pub fn with_shutdown(mut self, shutdown: ShutdownSignal, drain_timeout: Duration) -> CoapServer {
+		self.shutdown = shutdown;
+		self.drain_timeout = drain_timeout;
+		self
+	}
+
+	pub async fn prepare(&mut self) -> DVResult<()> {
+		self.socket = match UdpSocket::bind(&self.addr).await {
+			Ok(v) => {
+				info!("Bound on udp/{}", self.addr);
+				Some(Arc::new(v))
+			},
+			Err(err) => {
+				error!("Failed to bind to udp/{}: {}", self.addr, err);
+				return Err(err)?
+			}
+		};
+		Ok(())
+	}
+
+	pub async fn main_loop(&self) -> DVResult<()> {
+		let socket = match &self.socket {
+			Some(v) => v.clone(),
+			None => return Err(DVError::NotReady("run CoapServer.prepare() first".to_string()))?
+		};
+		info!("Listening on udp/{}", self.addr);
+		let forwarder = tokio::spawn(forward_notices(socket.clone(), self.state.clone(), self.dispatcher.clone(), self.codec.clone()));
+		// Each packet being handled holds a sender, see `WSServer::main_loop`
+		let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
+		let mut shutdown = self.shutdown.clone();
+		let mut sweep = tokio::time::interval(Duration::from_secs(60));
+		let mut buf = vec![0u8; COAP_MAX_DATAGRAM];
+		loop {
+			tokio::select! {
+				_ = net::shutdown_requested(&mut shutdown) => break,
+				_ = sweep.tick() => self.state.lock().unwrap().expire(),
+				res = socket.recv_from(&mut buf) => match res {
+					Ok((len, peer)) => {
+						let packet = match Packet::from_bytes(&buf[..len]) {
+							Ok(v) => v,
+							Err(err) => {
+								debug!("Ignoring bad datagram from {}: {:?}", peer, err);
+								continue;
+							}
+						};
+						let handled = handle_packet(socket.clone(), self.state.clone(), self.dispatcher.clone(), self.codec.clone(), packet, peer);
+						let done_tx = done_tx.clone();
+						tokio::spawn(async move {
+							handled.await;
+							drop(done_tx);
+						});
+					},
+					Err(err) => error!("Failed to receive datagram: {:?}", err),
+				},
+			}
+		}
+		info!("Stopped listening on udp/{}", self.addr);
+		forwarder.abort();
+		drop(done_tx);
+		match tokio::time::timeout(self.drain_timeout, done_rx.recv()).await {
+			Ok(_) => info!("All CoAP requests to udp/{} answered", self.addr),
+			Err(_) => warn!("Gave up waiting for CoAP requests to udp/{} after {:?}", self.addr, self.drain_timeout),
+		}
+		end_observations(&socket, &self.state).await;
+		Ok(())
+	}
+}
+
+/// Tells every observer there will be no more notices (RFC 7641, 3.2).
+async fn end_observations(socket: &UdpSocket, state: &Mutex<CoapState>) {
+	let mut datagrams = Vec::new();
+	{
+		let mut state = state.lock().unwrap();
+		let state = &mut *state;
+		for (addr, peer) in state.peers.iter_mut() {
+			let token = match peer.observe_token.take() {
+				Some(v) => v,
+				None => continue,
+			};
+			let mut packet = Packet::new();
+			packet.header.set_type(MessageType::NonConfirmable);
+			packet.header.code = MessageClass::Response(ResponseType::ServiceUnavailable);
+			packet.header.message_id = state.next_message_id;
+			state.next_message_id = state.next_message_id.wrapping_add(1);
+			packet.set_token(token);
+			match packet.to_bytes() {
+				Ok(bytes) => datagrams.push((*addr, bytes)),
+				Err(err) => error!("Failed to encode final notification for {}: {:?}", addr, err),
+			}
+		}
+	}
+	for (addr, bytes) in datagrams {
+		send_datagram(socket, &bytes, addr).await;
+	}
+}
+
+async fn send_datagram(socket: &UdpSocket, bytes: &[u8], peer: SocketAddr) {
+	if let Err(err) = socket.send_to(bytes, peer).await {
+		warn!("Failed to send datagram to {}: {:?}", peer, err);
+	}
+}
+
+async fn handle_packet(socket: Arc<UdpSocket>, state: Arc<Mutex<CoapState>>, dispatcher: Arc<Dispatcher>, codec: Arc<JwtCodec>, packet: Packet, peer: SocketAddr) {
+	trace!("Got {:?} from {}", packet, peer);
+	match (packet.header.get_type(), packet.header.code) {
+		// A reset in answer to a notice means the peer is no longer interested
+		(MessageType::Reset, _) => {
+			if let Some(peer) = state.lock().unwrap().peers.get_mut(&peer) {
+				peer.observe_token = None;
+			}
+			return;
+		},
+		(MessageType::Acknowledgement, _) => return,
+		// CoAP ping
+		(MessageType::Confirmable, MessageClass::Empty) => {
+			let mut pong = Packet::new();
+			pong.header.set_type(MessageType::Reset);
+			pong.header.code = MessageClass::Empty;
+			pong.header.message_id = packet.header.message_id;
+			if let Ok(bytes) = pong.to_bytes() {
+				send_datagram(&socket, &bytes, peer).await;
+			}
+			return;
+		},
+		(_, MessageClass::Request(_)) => {},
+		_ => return,
+	}
+
+	// Retransmissions get the reply we already sent, or nothing if we are
+	// still working on it
+	let key = (peer, packet.header.message_id);
+	let cached = {
+		let mut state = state.lock().unwrap();
+		match state.recent.get(&key) {
+			Some((_, reply)) => Some(reply.clone()),
+			None => {
+				state.recent.insert(key, (Instant::now(), None));
+				None
+			},
+		}
+	};
+	match cached {
+		Some(Some(bytes)) => return send_datagram(&socket, &bytes, peer).await,
+		Some(None) => return,
+		None => {},
+	}
+
+	let mut request = CoapRequest::from_packet(packet, peer);
+	let intercepted = state.lock().unwrap().blocks.intercept_request(&mut request);
+	match intercepted {
+		Ok(true) => {},
+		Ok(false) => {
+			handle_request(&state, &dispatcher, &codec, &mut request, peer).await;
+			let intercepted = state.lock().unwrap().blocks.intercept_response(&mut request);
+			if let Err(err) = intercepted {
+				request.apply_from_error(err);
+			}
+		},
+		Err(err) => {
+			request.apply_from_error(err);
+		},
+	}
+
+	let response = match request.response {
+		Some(v) => v,
+		None => return,
+	};
+	let bytes = match response.message.to_bytes_unlimited() {
+		Ok(v) => v,
+		Err(err) => {
+			error!("Failed to encode CoAP response to {}: {:?}", peer, err);
+			state.lock().unwrap().recent.remove(&key);
+			return;
+		}
+	};
+	state.lock().unwrap().recent.insert(key, (Instant::now(), Some(bytes.clone())));
+	send_datagram(&socket, &bytes, peer).await;
+}
+
+async fn handle_request(state: &Mutex<CoapState>, dispatcher: &Dispatcher, codec: &JwtCodec, request: &mut CoapRequest<SocketAddr>, peer: SocketAddr) {
+	let path = request.get_path();
+	let method = *request.get_method();
+	let observe = request.get_observe_flag();
+	let token = std::mem::take(&mut request.message.payload);
+	let req_token = request.message.get_token().to_vec();
+	let response = match request.response.as_mut() {
+		Some(v) => v,
+		None => return,
+	};
+	match (path.as_str(), method) {
+		(COAP_MESSAGE_PATH, RequestType::Post) => {
+			let (session, encoding, in_flight) = {
+				let mut state = state.lock().unwrap();
+				let peer = state.peer(peer);
+				peer.encoding = Encoding::of_token(&token);
+				(peer.session.clone(), peer.encoding, peer.in_flight.clone())
+			};
+			let _permit = match in_flight.try_acquire_owned() {
+				Ok(v) => v,
+				Err(_) => {
+					warn!("Too many requests from {} at once", peer);
+					response.set_status(ResponseType::ServiceUnavailable);
+					response.message.add_option(CoapOption::MaxAge, vec![1]);
+					return;
+				}
+			};
+			match handle_token(dispatcher, &session, codec, encoding, &token, peer).await {
+				Ok(rpl) => {
+					response.set_status(ResponseType::Content);
+					response.message.set_content_format(content_format(encoding));
+					response.message.payload = rpl;
+				},
+				Err(err) => {
+					error!("Failed to encode reply to {}: {:?}", peer, err);
+					response.set_status(ResponseType::InternalServerError);
+				},
+			}
+		},
+		(COAP_NOTICES_PATH, RequestType::Get) => {
+			let mut state = state.lock().unwrap();
+			let peer = state.peer(peer);
+			match observe {
+				Some(Ok(ObserveOption::Register)) => {
+					peer.observe_token = Some(req_token);
+					let seq = peer.next_observe_seq();
+					response.message.set_observe_value(seq);
+				},
+				Some(Ok(ObserveOption::Deregister)) => peer.observe_token = None,
+				_ => {},
+			}
+			response.set_status(ResponseType::Content);
+		},
+		(COAP_MESSAGE_PATH, _) | (COAP_NOTICES_PATH, _) => response.set_status(ResponseType::MethodNotAllowed),
+		_ => response.set_status(ResponseType::NotFound),
+	}
+}
+
+/// Same as a WebSocket frame: a signed request in, a signed reply out.
+async fn handle_token(dispatcher: &Dispatcher, session: &Session, codec: &JwtCodec, encoding: Encoding, token: &[u8], peer: SocketAddr) -> DVResult<Vec<u8>> {
+	let (req_id, rpl) = match codec.decode(token) {
+		Ok(claims) => match claims.msg {
+			DVMessage::Req(req) => {
+				debug!("Got {} (reqId={:?}) from {} over CoAP", req.msg_type(), claims.req_id, claims.iss);
+				let cancel = session.begin_request(claims.req_id);
+				let rpl = dispatcher.dispatch(session, req, &cancel).await;
+				session.end_request(claims.req_id);
+				(claims.req_id, rpl)
+			},
+			DVMessage::Rpl(_) | DVMessage::Ntc(_) => {
+				(claims.req_id, DVReply::ErrorRpl(ErrorInfo::from(&DVError::UnexpectedMessage("expected a request".to_string()))))
+			},
+		},
+		Err(err) => {
+			warn!("Rejected message from {}: {:?}", peer, err);
+			(codec.peek_req_id(token), DVReply::ErrorRpl(ErrorInfo::from(&err)))
+		},
+	};
+	codec.encode_reply(encoding, req_id, rpl)
+}
+
+/// Sends every observer a notification per notice its session asked for.
+async fn forward_notices(socket: Arc<UdpSocket>, state: Arc<Mutex<CoapState>>, dispatcher: Arc<Dispatcher>, codec: Arc<JwtCodec>) {
+	use tokio::sync::broadcast::error::RecvError;
+	let mut events = dispatcher.notices().listen();
+	loop {
+		let event = match events.recv().await {
+			Ok(event) => Ok(event),
+			Err(RecvError::Lagged(count)) => {
+				warn!("CoAP observers lagged behind and lost {} change events", count);
+				Err(count)
+			},
+			Err(RecvError::Closed) => return,
+		};
+		let mut datagrams = Vec::new();
+		{
+			let mut state = state.lock().unwrap();
+			let state = &mut *state;
+			for (addr, peer) in state.peers.iter_mut() {
+				let token = match &peer.observe_token {
+					Some(v) => v.clone(),
+					None => continue,
+				};
+				let notices = match &event {
+					Ok(event) => peer.session.notices_for(event),
+					Err(count) if peer.session.has_subscriptions() => vec![DVNotice::NoticesLostNtc(NoticesLostNtc{count: *count})],
+					Err(_) => vec![],
+				};
+				for ntc in notices {
+					let payload = match codec.encode_as(peer.encoding, None, DVMessage::Ntc(ntc)) {
+						Ok(v) => v,
+						Err(err) => {
+							error!("Failed to encode notice for {}: {:?}", addr, err);
+							continue;
+						}
+					};
+					let mut packet = Packet::new();
+					packet.header.set_type(MessageType::NonConfirmable);
+					packet.header.code = MessageClass::Response(ResponseType::Content);
+					packet.header.message_id = state.next_message_id;
+					state.next_message_id = state.next_message_id.wrapping_add(1);
+					packet.set_token(token.clone());
+					packet.set_observe_value(peer.next_observe_seq());
+					packet.set_content_format(content_format(peer.encoding));
+					packet.payload = payload;
+					match packet.to_bytes_unlimited() {
+						Ok(bytes) => datagrams.push((*addr, bytes)),
+						Err(err) => error!("Failed to encode notification for {}: {:?}", addr, err),
+					}
+				}
+			}
+		}
+		for (addr, bytes) in datagrams {
+			send_datagram(&socket, &bytes, addr).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::coap_client::COAP_BLOCK_SIZE;
+	use crate::jwt::Issuer;
+	use crate::schema::open_test_database;
+	use crate::ws_client::WSClient;
+
+	fn codec() -> JwtCodec {
+		JwtCodec::new(b"coap test secret", Issuer::new(Uuid::nil(), "coap-test")).unwrap()
+	}
+
+	#[tokio::test]
+	async fn serves_blocks_and_notices_on_loopback() {
+		let dispatcher = Arc::new(Dispatcher::new(open_test_database()));
+		let mut server = CoapServer::new("127.0.0.1:0", codec(), dispatcher);
+		server.prepare().await.unwrap();
+		let addr = server.socket.as_ref().unwrap().local_addr().unwrap();
+		tokio::spawn(async move { server.main_loop().await });
+		let client = WSClient::new(&format!("coap://{}", addr), codec()).await.unwrap();
+
+		let volume = VolumeInfo{
+			uuid: Uuid::nil(),
+			title: "Test".to_string(),
+			name: "test".to_string(),
+			is_real: true,
+			uid2name: HashMap::new(),
+			gid2name: HashMap::new(),
+		};
+		let volume = client.new_volume(volume).await.unwrap().volume.unwrap();
+		let node = client.create_node(CreateNodeReq{
+			parent: NodeOrPath::Path("/".to_string()),
+			volume: Some(volume.uuid),
+			name: "big".to_string(),
+			uuid: None,
+			file_kind: FileKind::Empty,
+			title: String::new(),
+			description: String::new(),
+			unix_perm: None,
+			target: None,
+			link_to: None,
+		}).await.unwrap();
+		let mut notices = client.notices();
+		let subscription = client.subscribe(WatchTarget::Node(node.uuid)).await.unwrap();
+
+		// Several blocks each way: Block1 for the write, Block2 for the read
+		let data: Vec<u8> = (0..4 * COAP_BLOCK_SIZE).map(|i| i as u8).collect();
+		let rpl = client.write_stream(NodeOrPath::Node(node.uuid), None, 0, data.clone(), ExpectedContent::default()).await.unwrap();
+		assert_eq!(rpl.size, data.len() as u64);
+		let rpl = client.request(DVRequest::ReadStreamReq(ReadStreamReq{
+			node: NodeOrPath::Node(node.uuid),
+			volume: None,
+			offset: 0,
+			length: data.len() as u64,
+		})).await.unwrap();
+		match rpl {
+			DVReply::ReadStreamRpl(rpl) => assert_eq!(rpl.data, data),
+			other => panic!("unexpected reply {:?}", other),
+		}
+
+		let ntc = tokio::time::timeout(Duration::from_secs(5), notices.recv()).await.unwrap().unwrap();
+		assert_eq!(ntc, DVNotice::NodeChangedNtc(NodeChangedNtc{
+			subscription,
+			change: NodeChange::ContentChanged,
+			node: node.uuid,
+			volume: volume.uuid,
+		}));
+		client.close().await.unwrap();
+	}
+}
@@ -0,0 +1,102 @@
+use crate::prelude::*;
+use crate::jwt::RawClaims;
+use coset::{CoseMac0, CoseMac0Builder, HeaderBuilder, TaggedCborSerializable};
+use coset::iana::Algorithm;
+use hmac::{Hmac, Mac};
+
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+/// First byte of a tagged COSE_Mac0 (CBOR tag 17).
+const COSE_MAC0_TAG: u8 = 0xd1;
+
+/// Keeps the key used for COSE_Mac0 envelopes.
+///
+/// There is still a single shared secret per installation (see MESSAGES.md),
+/// so tokens are MACed with it (HMAC 256/256) rather than signed.
+#[derive(Clone)]
+pub struct CoseKey {
+	mac_key: HmacSha256,
+}
+
+impl CoseKey {
+	pub fn from_secret(secret: &[u8]) -> CoseKey {
+		CoseKey{
+			mac_key: HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size"),
+		}
+	}
+
+	pub fn sign(&self, claims: &impl serde::Serialize) -> DVResult<Vec<u8>> {
+		let mut payload = Vec::new();
+		if let Err(err) = ciborium::ser::into_writer(claims, &mut payload) {
+			return Err(DVError::CborError(err.to_string()));
+		}
+		let protected = HeaderBuilder::new()
+			.algorithm(Algorithm::HMAC_256_256)
+			.build();
+		let mac0 = CoseMac0Builder::new()
+			.protected(protected)
+			.payload(payload)
+			.create_tag(b"", |data| {
+				let mut mac = self.mac_key.clone();
+				mac.update(data);
+				mac.finalize().into_bytes().to_vec()
+			})
+			.build();
+		match mac0.to_tagged_vec() {
+			Ok(v) => Ok(v),
+			Err(err) => Err(DVError::CborError(err.to_string())),
+		}
+	}
+
+	pub(crate) fn verify(&self, token: &[u8]) -> DVResult<RawClaims> {
+		let mac0 = parse_mac0(token)?;
+		if mac0.protected.header.alg != Some(coset::RegisteredLabelWithPrivate::Assigned(Algorithm::HMAC_256_256)) {
+			return Err(DVError::InvalidSignature);
+		}
+		let verified = mac0.verify_tag(b"", |tag, data| {
+			let mut mac = self.mac_key.clone();
+			mac.update(data);
+			mac.verify_slice(tag)
+		});
+		if verified.is_err() {
+			return Err(DVError::InvalidSignature);
+		}
+		mac0_claims(&mac0)
+	}
+}
+
+impl std::fmt::Debug for CoseKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("CoseKey").finish_non_exhaustive()
+	}
+}
+
+pub fn is_cose_token(token: &[u8]) -> bool {
+	token.first() == Some(&COSE_MAC0_TAG)
+}
+
+fn parse_mac0(token: &[u8]) -> DVResult<CoseMac0> {
+	match CoseMac0::from_tagged_slice(token) {
+		Ok(v) => Ok(v),
+		Err(err) => Err(DVError::CborError(err.to_string())),
+	}
+}
+
+fn mac0_payload(mac0: &CoseMac0) -> DVResult<&[u8]> {
+	match &mac0.payload {
+		Some(v) => Ok(v),
+		None => Err(DVError::CborError("COSE_Mac0 without payload".to_string())),
+	}
+}
+
+fn mac0_claims(mac0: &CoseMac0) -> DVResult<RawClaims> {
+	match ciborium::de::from_reader(mac0_payload(mac0)?) {
+		Ok(v) => Ok(v),
+		Err(err) => Err(DVError::CborError(err.to_string())),
+	}
+}
+
+/// The CBOR claims of a COSE_Mac0, whether or not its tag is right.
+pub(crate) fn peek_payload(token: &[u8]) -> DVResult<Vec<u8>> {
+	Ok(mac0_payload(&parse_mac0(token)?)?.to_vec())
+}
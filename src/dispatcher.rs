@@ -0,0 +1,787 @@
+use crate::prelude::*;
+use crate::messages::*;
+use crate::jwt::Encoding;
+use crate::net::PeerCred;
+use crate::nodes;
+use crate::schema;
+use crate::notices::{ChangeEvent, NoticeHub};
+use crate::streams;
+use crate::volumes;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Tells a request to stop. Long loops check it now and then, so even work
+/// that never waits for anything can be cut short.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<CancelState>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+	cancelled: AtomicBool,
+	notify: Notify,
+}
+
+impl CancelToken {
+	pub fn new() -> CancelToken {
+		CancelToken::default()
+	}
+
+	pub fn cancel(&self) {
+		self.0.cancelled.store(true, Ordering::Relaxed);
+		self.0.notify.notify_waiters();
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.cancelled.load(Ordering::Relaxed)
+	}
+
+	/// Fails with `DVError::Cancelled` once `cancel` was called.
+	pub fn check(&self) -> DVResult<()> {
+		match self.is_cancelled() {
+			true => Err(DVError::Cancelled("request was cancelled".to_string())),
+			false => Ok(()),
+		}
+	}
+
+	/// Resolves once `cancel` is called.
+	pub async fn cancelled(&self) {
+		loop {
+			// Registered before checking, so a cancel in between isn't missed
+			let notified = self.0.notify.notified();
+			if self.is_cancelled() {
+				return;
+			}
+			notified.await;
+		}
+	}
+}
+
+/// State that lives as long as a single connection.
+#[derive(Debug)]
+pub struct Session {
+	next_subscription: AtomicU64,
+	subscriptions: Mutex<HashMap<u64, WatchTarget>>,
+	/// Requests still being worked on, by reqId.
+	requests: Mutex<HashMap<u64, CancelToken>>,
+	/// Who sent the requests, for Unix socket connections.
+	peer_cred: Option<PeerCred>,
+}
+
+impl Default for Session {
+	fn default() -> Self {
+		Session::new()
+	}
+}
+
+impl Session {
+	pub fn new() -> Session {
+		Session{
+			next_subscription: AtomicU64::new(1),
+			subscriptions: Mutex::new(HashMap::new()),
+			requests: Mutex::new(HashMap::new()),
+			peer_cred: None,
+		}
+	}
+
+	/// A session for a local process connected through a Unix socket.
+	pub fn local(peer_cred: PeerCred) -> Session {
+		Session{
+			peer_cred: Some(peer_cred),
+			..Session::new()
+		}
+	}
+
+	pub fn peer_cred(&self) -> Option<&PeerCred> {
+		self.peer_cred.as_ref()
+	}
+
+	pub fn subscribe(&self, target: WatchTarget) -> u64 {
+		let id = self.next_subscription.fetch_add(1, Ordering::Relaxed);
+		self.subscriptions.lock().unwrap().insert(id, target);
+		id
+	}
+
+	pub fn unsubscribe(&self, id: u64) -> bool {
+		self.subscriptions.lock().unwrap().remove(&id).is_some()
+	}
+
+	/// One notice per subscription interested in `event`.
+	pub fn notices_for(&self, event: &ChangeEvent) -> Vec<DVNotice> {
+		let subscriptions = self.subscriptions.lock().unwrap();
+		subscriptions.iter()
+			.filter(|(_, target)| target.matches(event))
+			.map(|(id, _)| DVNotice::NodeChangedNtc(NodeChangedNtc{
+				subscription: *id,
+				change: event.change,
+				node: event.node,
+				volume: event.volume,
+			}))
+			.collect()
+	}
+
+	pub fn has_subscriptions(&self) -> bool {
+		!self.subscriptions.lock().unwrap().is_empty()
+	}
+
+	/// A token for a request that just arrived, which a `cancelReq` for the
+	/// same reqId will trigger until `end_request`.
+	pub fn begin_request(&self, req_id: Option<u64>) -> CancelToken {
+		let cancel = CancelToken::new();
+		if let Some(req_id) = req_id {
+			self.requests.lock().unwrap().insert(req_id, cancel.clone());
+		}
+		cancel
+	}
+
+	pub fn end_request(&self, req_id: Option<u64>) {
+		if let Some(req_id) = req_id {
+			self.requests.lock().unwrap().remove(&req_id);
+		}
+	}
+
+	pub fn cancel_request(&self, req_id: u64) -> bool {
+		match self.requests.lock().unwrap().remove(&req_id) {
+			Some(cancel) => {
+				cancel.cancel();
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Stops everything, for when nobody is left to read the replies.
+	pub fn cancel_all(&self) {
+		for (_, cancel) in self.requests.lock().unwrap().drain() {
+			cancel.cancel();
+		}
+	}
+}
+
+/// Routes each incoming request to the function that handles it.
+///
+/// Requests run concurrently, each on a thread of its own since database
+/// work blocks, but they take turns on the single database connection.
+#[derive(Debug)]
+pub struct Dispatcher {
+	db: Mutex<SQLConnection>,
+	notices: NoticeHub,
+	/// Reported by `helloReq`.
+	features: Vec<Feature>,
+}
+
+impl Dispatcher {
+	pub fn new(db: SQLConnection) -> Dispatcher {
+		Dispatcher{
+			db: Mutex::new(db),
+			notices: NoticeHub::default(),
+			features: Vec::new(),
+		}
+	}
+
+	pub fn with_features(mut self, features: Vec<Feature>) -> Dispatcher {
+		self.features = features;
+		self
+	}
+
+	pub fn notices(&self) -> &NoticeHub {
+		&self.notices
+	}
+
+	/// Flushes the database to disk, for when the node is about to stop.
+	pub fn checkpoint(&self) -> DVResult<()> {
+		let db = self.db.lock().unwrap();
+		schema::checkpoint_database(&db)?;
+		Ok(())
+	}
+
+	pub async fn dispatch(&self, session: &Session, req: DVRequest, cancel: &CancelToken) -> DVReply {
+		let trace_msg = format!("{}(req={})", function!(), req.msg_type());
+		trace!("+{}", trace_msg);
+		let ans = match cancel.check() {
+			Err(err) => Err(err),
+			// Handling a request never waits on anything but the database,
+			// so it gets a thread of its own to keep connections (and their
+			// cancelReqs) going meanwhile
+			Ok(()) => match tokio::runtime::Handle::current().runtime_flavor() {
+				tokio::runtime::RuntimeFlavor::CurrentThread => self.handle(session, req, cancel),
+				_ => tokio::task::block_in_place(|| self.handle(session, req, cancel)),
+			},
+		};
+		match ans {
+			Ok(rpl) => {
+				trace!("-{} -> Ok", trace_msg);
+				rpl
+			},
+			Err(err) => {
+				warn!("Failed to handle request: {:?}", err);
+				trace!("-{} -> {:?}", trace_msg, err);
+				DVReply::ErrorRpl(ErrorInfo::from(&err))
+			}
+		}
+	}
+
+	fn handle(&self, session: &Session, req: DVRequest, cancel: &CancelToken) -> DVResult<DVReply> {
+		match req {
+			DVRequest::HelloReq(req) => self.hello(session, req),
+			DVRequest::GetTimeReq => self.get_time(),
+			DVRequest::ListVolumesReq(req) => self.list_volumes(req),
+			DVRequest::NewVolumeReq(_)
+			| DVRequest::RenameVolumeReq(_)
+			| DVRequest::DeleteVolumeReq(_)
+			| DVRequest::CreateNodeReq(_)
+			| DVRequest::RenameNodeReq(_)
+			| DVRequest::MoveNodeReq(_)
+			| DVRequest::DeleteNodeReq(_)
+			| DVRequest::CloneNodeReq(_)
+			| DVRequest::WriteStreamReq(_)
+			| DVRequest::TruncateStreamReq(_)
+			| DVRequest::AppendStreamReq(_) => self.mutate(req, cancel),
+			DVRequest::NodeInfoReq(req) => self.node_info(req),
+			DVRequest::ListChildrenReq(req) => self.list_children(req),
+			DVRequest::StreamHashReq(req) => self.stream_hash(req, cancel),
+			DVRequest::ReadStreamReq(req) => self.read_stream(req),
+			DVRequest::BeginUploadReq(req) => self.begin_upload(req),
+			DVRequest::UploadChunkReq(req) => self.upload_chunk(req),
+			DVRequest::FinishUploadReq(req) => self.finish_upload(req, cancel),
+			DVRequest::AbortUploadReq(req) => self.abort_upload(req),
+			DVRequest::SubscribeReq(req) => self.subscribe(session, req),
+			DVRequest::UnsubscribeReq(req) => self.unsubscribe(session, req),
+			DVRequest::CancelReq(req) => self.cancel(session, req),
+			DVRequest::BatchReq(req) => self.batch(req, cancel),
+		}
+	}
+
+	/// Carries out a request that only changes the database (see
+	/// `DVRequest::is_batchable`) inside `tx`. Also returns what changed,
+	/// to be published once `tx` is committed.
+	fn apply(&self, tx: &SQLTransaction, req: DVRequest, cancel: &CancelToken) -> DVResult<(DVReply, Vec<ChangeEvent>)> {
+		let mut events = Vec::new();
+		let rpl = match req {
+			DVRequest::NewVolumeReq(req) => {
+				let (status, volume) = volumes::create_volume(tx, &req.volume)?;
+				DVReply::NewVolumeRpl(NewVolumeRpl{
+					status,
+					volume,
+				})
+			},
+			DVRequest::RenameVolumeReq(req) => {
+				let (status, volume) = volumes::rename_volume(tx, req.volume, req.name.as_deref(), req.title.as_deref())?;
+				DVReply::RenameVolumeRpl(RenameVolumeRpl{
+					status,
+					volume,
+				})
+			},
+			DVRequest::DeleteVolumeReq(req) => DVReply::DeleteVolumeRpl(DeleteVolumeRpl{
+				status: volumes::delete_volume(tx, req.volume)?,
+			}),
+			DVRequest::CreateNodeReq(req) => {
+				let node = nodes::create_node(tx, &req)?;
+				events.push(ChangeEvent::new(NodeChange::Created, node.uuid, node.volume, nodes::get_ancestors(tx, node.uuid)?));
+				DVReply::CreateNodeRpl(CreateNodeRpl{
+					node,
+				})
+			},
+			DVRequest::RenameNodeReq(req) => {
+				let node_uuid = nodes::resolve_node(tx, &req.node, req.volume)?.uuid;
+				let node = nodes::rename_node(tx, node_uuid, req.name.as_deref(), req.title.as_deref(), req.description.as_deref())?;
+				let ancestors = nodes::get_ancestors(tx, node.uuid)?;
+				if req.name.is_some() {
+					events.push(ChangeEvent::new(NodeChange::Renamed, node.uuid, node.volume, ancestors.clone()));
+				}
+				if req.title.is_some() || req.description.is_some() {
+					events.push(ChangeEvent::new(NodeChange::Retitled, node.uuid, node.volume, ancestors));
+				}
+				DVReply::RenameNodeRpl(RenameNodeRpl{
+					node,
+				})
+			},
+			DVRequest::MoveNodeReq(req) => {
+				let node_uuid = nodes::resolve_node(tx, &req.node, req.volume)?.uuid;
+				let parent_uuid = nodes::resolve_node(tx, &req.parent, req.volume)?.uuid;
+				let (node, mut ancestors) = nodes::move_node(tx, node_uuid, parent_uuid, req.name.as_deref())?;
+				for ancestor in nodes::get_ancestors(tx, node.uuid)? {
+					if !ancestors.contains(&ancestor) {
+						ancestors.push(ancestor);
+					}
+				}
+				events.push(ChangeEvent::new(NodeChange::Renamed, node.uuid, node.volume, ancestors));
+				DVReply::MoveNodeRpl(MoveNodeRpl{
+					node,
+				})
+			},
+			DVRequest::DeleteNodeReq(req) => {
+				let node = nodes::resolve_node(tx, &req.node, req.volume)?;
+				let deleted = nodes::delete_node(tx, node.uuid, req.recursive)?;
+				let count = deleted.len() as u64;
+				for (uuid, ancestors) in deleted {
+					events.push(ChangeEvent::new(NodeChange::Deleted, uuid, node.volume, ancestors));
+				}
+				DVReply::DeleteNodeRpl(DeleteNodeRpl{
+					node: node.uuid,
+					count,
+				})
+			},
+			DVRequest::CloneNodeReq(req) => {
+				let node_uuid = nodes::resolve_node(tx, &req.node, req.volume)?.uuid;
+				let parent_uuid = nodes::resolve_node(tx, &req.parent, req.volume)?.uuid;
+				let cloned = nodes::clone_node(tx, node_uuid, parent_uuid, req.name.as_deref(), req.recursive)?;
+				let node = match nodes::get_node(tx, cloned[0].0)? {
+					Some(v) => v,
+					None => return Err(DVError::NotFound(cloned[0].0.to_string())),
+				};
+				let count = cloned.len() as u64;
+				for (uuid, ancestors) in cloned {
+					events.push(ChangeEvent::new(NodeChange::Created, uuid, node.volume, ancestors));
+				}
+				DVReply::CloneNodeRpl(CloneNodeRpl{
+					node,
+					count,
+				})
+			},
+			DVRequest::WriteStreamReq(req) => {
+				if req.data.len() > streams::MAX_STREAM_CHUNK {
+					return Err(DVError::ChunkTooLarge(req.data.len()));
+				}
+				let node = nodes::resolve_node(tx, &req.node, req.volume)?;
+				let stream = nodes::writable_stream(tx, &node, &req.expect, cancel)?;
+				let end = streams::end_of_write(req.offset, req.data.len())?;
+				let version = streams::write_at(tx, &stream, req.offset, &req.data)?;
+				nodes::touch_stream_nodes(tx, stream.uuid)?;
+				events.push(ChangeEvent::new(NodeChange::ContentChanged, node.uuid, node.volume, nodes::get_ancestors(tx, node.uuid)?));
+				DVReply::WriteStreamRpl(WriteStreamRpl{
+					node: node.uuid,
+					size: std::cmp::max(stream.size, end),
+					version,
+				})
+			},
+			DVRequest::TruncateStreamReq(req) => {
+				let node = nodes::resolve_node(tx, &req.node, req.volume)?;
+				let stream = nodes::writable_stream(tx, &node, &req.expect, cancel)?;
+				let version = streams::truncate(tx, &stream, req.size)?;
+				nodes::touch_stream_nodes(tx, stream.uuid)?;
+				events.push(ChangeEvent::new(NodeChange::ContentChanged, node.uuid, node.volume, nodes::get_ancestors(tx, node.uuid)?));
+				DVReply::TruncateStreamRpl(TruncateStreamRpl{
+					node: node.uuid,
+					size: req.size,
+					version,
+				})
+			},
+			DVRequest::AppendStreamReq(req) => {
+				if req.data.len() > streams::MAX_STREAM_CHUNK {
+					return Err(DVError::ChunkTooLarge(req.data.len()));
+				}
+				let node = nodes::resolve_node(tx, &req.node, req.volume)?;
+				let stream = nodes::writable_stream(tx, &node, &req.expect, cancel)?;
+				let version = streams::write_at(tx, &stream, stream.size, &req.data)?;
+				nodes::touch_stream_nodes(tx, stream.uuid)?;
+				events.push(ChangeEvent::new(NodeChange::ContentChanged, node.uuid, node.volume, nodes::get_ancestors(tx, node.uuid)?));
+				DVReply::AppendStreamRpl(AppendStreamRpl{
+					node: node.uuid,
+					offset: stream.size,
+					size: stream.size + req.data.len() as u64,
+					version,
+				})
+			},
+			req => return Err(DVError::NotBatchable(req.msg_type())),
+		};
+		Ok((rpl, events))
+	}
+
+	/// A single request that changes the database, in a transaction of its own.
+	fn mutate(&self, req: DVRequest, cancel: &CancelToken) -> DVResult<DVReply> {
+		let (rpl, events) = self.with_transaction(|tx| self.apply(tx, req, cancel))?;
+		for event in events {
+			self.notices.publish(event);
+		}
+		Ok(rpl)
+	}
+
+	/// Same as `dispatch`, but answers with a `timeout` error once `deadline`
+	/// has passed. A timer cancels the request then, so it stops at its next
+	/// `cancel.check()`. Work that never checks runs to its end, and keeps
+	/// its reply.
+	pub async fn dispatch_within(&self, session: &Session, req: DVRequest, cancel: &CancelToken, deadline: Duration) -> DVReply {
+		let msg_type = req.msg_type();
+		let timed_out = Arc::new(AtomicBool::new(false));
+		// `dispatch` doesn't yield, so the deadline has to be kept elsewhere
+		let timer = {
+			let cancel = cancel.clone();
+			let timed_out = timed_out.clone();
+			tokio::spawn(async move {
+				tokio::time::sleep(deadline).await;
+				timed_out.store(true, Ordering::Relaxed);
+				cancel.cancel();
+			})
+		};
+		let rpl = self.dispatch(session, req, cancel).await;
+		timer.abort();
+		match rpl {
+			DVReply::ErrorRpl(err) if err.code == "cancelled" && timed_out.load(Ordering::Relaxed) => {
+				warn!("Gave up on {} after {:?}", msg_type, deadline);
+				let err = DVError::Timeout(format!("{} took longer than {:?}", msg_type, deadline));
+				DVReply::ErrorRpl(ErrorInfo::from(&err))
+			},
+			rpl => rpl,
+		}
+	}
+
+	/// Runs `f` inside a transaction that is only committed if it succeeds.
+	fn with_transaction<T, F>(&self, f: F) -> DVResult<T>
+	where F: FnOnce(&SQLTransaction) -> DVResult<T> {
+		let mut db = self.db.lock().unwrap();
+		let tx = db.transaction()?;
+		let ans = f(&tx)?;
+		tx.commit()?;
+		Ok(ans)
+	}
+
+	fn hello(&self, session: &Session, req: HelloReq) -> DVResult<DVReply> {
+		let protocol_version = PROTOCOL_VERSIONS.iter()
+			.filter(|version| req.protocol_versions.contains(version))
+			.max();
+		let protocol_version = match protocol_version {
+			Some(v) => *v,
+			None => return Err(DVError::UnsupportedProtocol(format!(
+				"datavir {} speaks protocol versions {:?}, not {:?}", DATAVIR_VERSION, PROTOCOL_VERSIONS, req.protocol_versions)))
+		};
+		info!("Said hello to datavir {} (protocol version {})", req.datavir_version, protocol_version);
+		let encodings = match session.peer_cred() {
+			Some(_) => Encoding::LOCAL.to_vec(),
+			None => Encoding::ALL.to_vec(),
+		};
+		Ok(DVReply::HelloRpl(HelloRpl{
+			datavir_version: DATAVIR_VERSION.to_string(),
+			protocol_version,
+			protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+			encodings,
+			hash_algorithms: HashAlg::ALL.to_vec(),
+			generators: Vec::new(),
+			features: self.features.clone(),
+		}))
+	}
+
+	fn get_time(&self) -> DVResult<DVReply> {
+		Ok(DVReply::GetTimeRpl(GetTimeRpl{
+			current_time: Utc::now(),
+		}))
+	}
+
+	fn subscribe(&self, session: &Session, req: SubscribeReq) -> DVResult<DVReply> {
+		let id = session.subscribe(req.target);
+		debug!("New subscription {} to {:?}", id, req.target);
+		Ok(DVReply::SubscribeRpl(SubscribeRpl{
+			subscription: id,
+		}))
+	}
+
+	fn unsubscribe(&self, session: &Session, req: UnsubscribeReq) -> DVResult<DVReply> {
+		if !session.unsubscribe(req.subscription) {
+			return Err(DVError::UnknownSubscription(req.subscription));
+		}
+		debug!("Removed subscription {}", req.subscription);
+		Ok(DVReply::UnsubscribeRpl)
+	}
+
+	fn cancel(&self, session: &Session, req: CancelReq) -> DVResult<DVReply> {
+		let cancelled = session.cancel_request(req.request);
+		debug!("Cancelling reqId={}: {}", req.request, if cancelled { "stopping it" } else { "already answered" });
+		Ok(DVReply::CancelRpl(CancelRpl{
+			request: req.request,
+			cancelled,
+		}))
+	}
+
+	fn list_volumes(&self, req: ListVolumesReq) -> DVResult<DVReply> {
+		let db = self.db.lock().unwrap();
+		let (volumes, next) = volumes::list_volumes(&db, &req.page)?;
+		Ok(DVReply::ListVolumesRpl(ListVolumesRpl{
+			volumes,
+			next,
+		}))
+	}
+
+	fn batch(&self, req: BatchReq, cancel: &CancelToken) -> DVResult<DVReply> {
+		if let Some(op) = req.ops.iter().find(|op| !op.is_batchable()) {
+			return Err(DVError::NotBatchable(op.msg_type()));
+		}
+		let count = req.ops.len();
+		let mut db = self.db.lock().unwrap();
+		let tx = db.transaction()?;
+		let mut results = Vec::with_capacity(count);
+		let mut events = Vec::new();
+		for op in req.ops {
+			cancel.check()?;
+			let rpl = match self.apply(&tx, op, cancel) {
+				Ok((rpl, op_events)) => {
+					events.extend(op_events);
+					rpl
+				},
+				Err(err) => DVReply::ErrorRpl(ErrorInfo::from(&err)),
+			};
+			let failed = rpl.is_failure();
+			results.push(rpl);
+			if failed {
+				tx.rollback()?;
+				info!("Rolled back a batch of {} ops, op {} failed", count, results.len() - 1);
+				return Ok(DVReply::BatchRpl(BatchRpl{
+					committed: false,
+					results,
+				}));
+			}
+		}
+		tx.commit()?;
+		drop(db);
+		debug!("Committed a batch of {} ops", count);
+		// Nobody hears about the changes before they all happened
+		for event in events {
+			self.notices.publish(event);
+		}
+		Ok(DVReply::BatchRpl(BatchRpl{
+			committed: true,
+			results,
+		}))
+	}
+
+	fn node_info(&self, req: NodeInfoReq) -> DVResult<DVReply> {
+		let db = self.db.lock().unwrap();
+		Ok(DVReply::NodeInfoRpl(nodes::get_nodes_info(&db, &req)?))
+	}
+
+	fn list_children(&self, req: ListChildrenReq) -> DVResult<DVReply> {
+		let db = self.db.lock().unwrap();
+		let node = nodes::resolve_node(&db, &req.node, req.volume)?;
+		let (children, next) = nodes::list_children(&db, node.uuid, &req.page)?;
+		Ok(DVReply::ListChildrenRpl(ListChildrenRpl{
+			node: node.uuid,
+			children,
+			next,
+		}))
+	}
+
+	fn stream_hash(&self, req: StreamHashReq, cancel: &CancelToken) -> DVResult<DVReply> {
+		// Computing a digest may fill the cache, so this needs a transaction
+		let rpl = self.with_transaction(|tx| nodes::get_nodes_hash(tx, &req, cancel))?;
+		Ok(DVReply::StreamHashRpl(rpl))
+	}
+
+	fn read_stream(&self, req: ReadStreamReq) -> DVResult<DVReply> {
+		let db = self.db.lock().unwrap();
+		let node = nodes::resolve_node(&db, &req.node, req.volume)?;
+		let length = std::cmp::min(req.length, streams::MAX_STREAM_CHUNK as u64);
+		let (data, size, version) = match streams::get_stream(&db, node.content.stream)? {
+			Some(stream) => (streams::read_at(&db, &stream, req.offset, length)?, stream.size, stream.version),
+			None => (vec![], 0, 0),
+		};
+		Ok(DVReply::ReadStreamRpl(ReadStreamRpl{
+			node: node.uuid,
+			offset: req.offset,
+			data,
+			size,
+			version,
+		}))
+	}
+
+	fn begin_upload(&self, req: BeginUploadReq) -> DVResult<DVReply> {
+		let rpl = self.with_transaction(|tx| {
+			let node = nodes::resolve_node(tx, &req.node, req.volume)?;
+			let pending = match req.resume {
+				true => streams::find_upload(tx, node.uuid)?,
+				false => None,
+			};
+			let (upload, offset) = match pending {
+				Some(upload) => (upload, streams::get_upload(tx, upload)?.1.size),
+				None => (streams::begin_upload(tx, node.uuid)?, 0),
+			};
+			Ok(BeginUploadRpl{
+				upload,
+				node: node.uuid,
+				offset,
+			})
+		})?;
+		Ok(DVReply::BeginUploadRpl(rpl))
+	}
+
+	fn upload_chunk(&self, req: UploadChunkReq) -> DVResult<DVReply> {
+		if req.data.len() > streams::MAX_STREAM_CHUNK {
+			return Err(DVError::ChunkTooLarge(req.data.len()));
+		}
+		let offset = self.with_transaction(|tx| {
+			let (_, stream) = streams::get_upload(tx, req.upload)?;
+			// Chunks must arrive in order, so the stream size is how much we got
+			if req.offset != stream.size {
+				return Err(DVError::BadOffset{expected: stream.size, got: req.offset});
+			}
+			streams::write_at(tx, &stream, req.offset, &req.data)?;
+			Ok(stream.size + req.data.len() as u64)
+		})?;
+		Ok(DVReply::UploadChunkRpl(UploadChunkRpl{
+			upload: req.upload,
+			offset,
+		}))
+	}
+
+	fn finish_upload(&self, req: FinishUploadReq, cancel: &CancelToken) -> DVResult<DVReply> {
+		let (rpl, event) = self.with_transaction(|tx| {
+			let (node_uuid, stream) = streams::get_upload(tx, req.upload)?;
+			if streams::stream_hash(tx, stream.uuid, req.alg, cancel)? != req.hash {
+				return Err(DVError::HashMismatch);
+			}
+			streams::end_upload(tx, req.upload, true)?;
+			nodes::set_node_stream(tx, node_uuid, stream.uuid)?;
+			let node = match nodes::get_node(tx, node_uuid)? {
+				Some(v) => v,
+				None => return Err(DVError::NotFound(node_uuid.to_string())),
+			};
+			let event = ChangeEvent::new(NodeChange::ContentChanged, node_uuid, node.volume, nodes::get_ancestors(tx, node_uuid)?);
+			let rpl = FinishUploadRpl{
+				node: node_uuid,
+				size: stream.size,
+				version: stream.version,
+			};
+			Ok((rpl, event))
+		})?;
+		info!("Finished upload {} into node {} ({} bytes)", req.upload, rpl.node, rpl.size);
+		self.notices.publish(event);
+		Ok(DVReply::FinishUploadRpl(rpl))
+	}
+
+	fn abort_upload(&self, req: AbortUploadReq) -> DVResult<DVReply> {
+		self.with_transaction(|tx| {
+			streams::get_upload(tx, req.upload)?;
+			streams::end_upload(tx, req.upload, false)
+		})?;
+		Ok(DVReply::AbortUploadRpl)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::schema::open_test_database;
+
+	fn new_volume_req(name: &str) -> DVRequest {
+		DVRequest::NewVolumeReq(NewVolumeReq{
+			volume: VolumeInfo{
+				uuid: Uuid::nil(),
+				title: name.to_string(),
+				name: name.to_string(),
+				is_real: true,
+				uid2name: HashMap::new(),
+				gid2name: HashMap::new(),
+			},
+		})
+	}
+
+	fn create_node_req(parent: NodeOrPath, name: &str) -> DVRequest {
+		DVRequest::CreateNodeReq(CreateNodeReq{
+			parent,
+			volume: None,
+			name: name.to_string(),
+			uuid: None,
+			file_kind: FileKind::Empty,
+			title: String::new(),
+			description: String::new(),
+			unix_perm: None,
+			target: None,
+			link_to: None,
+		})
+	}
+
+	fn volume_count(dispatcher: &Dispatcher) -> usize {
+		let db = dispatcher.db.lock().unwrap();
+		volumes::list_volumes(&db, &PageReq::default()).unwrap().0.len()
+	}
+
+	#[test]
+	fn batch_rolls_back_on_first_failure() {
+		let dispatcher = Dispatcher::new(open_test_database());
+		let mut events = dispatcher.notices().listen();
+		let req = BatchReq{
+			ops: vec![
+				new_volume_req("first"),
+				create_node_req(NodeOrPath::Node(Uuid::new_v4()), "orphan"),
+				new_volume_req("second"),
+			],
+		};
+		let rpl = match dispatcher.batch(req, &CancelToken::new()).unwrap() {
+			DVReply::BatchRpl(v) => v,
+			other => panic!("unexpected reply {:?}", other),
+		};
+		assert!(!rpl.committed);
+		// Nothing runs after the op that failed
+		assert_eq!(rpl.results.len(), 2);
+		assert!(matches!(&rpl.results[0], DVReply::NewVolumeRpl(v) if v.status == VolumeStatus::Ok));
+		assert!(matches!(&rpl.results[1], DVReply::ErrorRpl(err) if err.code == "notFound"));
+		assert_eq!(volume_count(&dispatcher), 0);
+		assert!(events.try_recv().is_err());
+	}
+
+	#[test]
+	fn batch_commits_when_every_op_succeeds() {
+		let dispatcher = Dispatcher::new(open_test_database());
+		let volume = match dispatcher.batch(BatchReq{ops: vec![new_volume_req("first")]}, &CancelToken::new()).unwrap() {
+			DVReply::BatchRpl(rpl) => match &rpl.results[..] {
+				[DVReply::NewVolumeRpl(NewVolumeRpl{volume: Some(volume), ..})] => volume.uuid,
+				other => panic!("unexpected results {:?}", other),
+			},
+			other => panic!("unexpected reply {:?}", other),
+		};
+		let mut events = dispatcher.notices().listen();
+		let root = {
+			let db = dispatcher.db.lock().unwrap();
+			volumes::get_volume_root(&db, volume).unwrap().unwrap()
+		};
+		let req = BatchReq{
+			ops: vec![
+				create_node_req(NodeOrPath::Node(root), "a"),
+				create_node_req(NodeOrPath::Node(root), "b"),
+			],
+		};
+		match dispatcher.batch(req, &CancelToken::new()).unwrap() {
+			DVReply::BatchRpl(rpl) => {
+				assert!(rpl.committed);
+				assert_eq!(rpl.results.len(), 2);
+			},
+			other => panic!("unexpected reply {:?}", other),
+		}
+		assert_eq!(events.try_recv().unwrap().change, NodeChange::Created);
+		assert_eq!(events.try_recv().unwrap().change, NodeChange::Created);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn slow_requests_time_out() {
+		let dispatcher = Dispatcher::new(open_test_database());
+		let session = Session::new();
+		let volume = match dispatcher.mutate(new_volume_req("slow"), &CancelToken::new()).unwrap() {
+			DVReply::NewVolumeRpl(NewVolumeRpl{volume: Some(volume), ..}) => volume.uuid,
+			other => panic!("unexpected reply {:?}", other),
+		};
+		let root = {
+			let db = dispatcher.db.lock().unwrap();
+			volumes::get_volume_root(&db, volume).unwrap().unwrap()
+		};
+		let node = match dispatcher.mutate(create_node_req(NodeOrPath::Node(root), "big"), &CancelToken::new()).unwrap() {
+			DVReply::CreateNodeRpl(rpl) => rpl.node.uuid,
+			other => panic!("unexpected reply {:?}", other),
+		};
+		// A GiB of holes takes seconds to hash but no time to make
+		let req = DVRequest::TruncateStreamReq(TruncateStreamReq{
+			node: NodeOrPath::Node(node),
+			volume: None,
+			size: 1 << 30,
+			expect: ExpectedContent::default(),
+		});
+		dispatcher.mutate(req, &CancelToken::new()).unwrap();
+
+		let req = DVRequest::StreamHashReq(StreamHashReq{
+			alg: HashAlg::Sha256,
+			nodes_or_paths: vec![NodeOrPath::Node(node)],
+			volume: None,
+		});
+		let started = std::time::Instant::now();
+		let rpl = dispatcher.dispatch_within(&session, req, &CancelToken::new(), Duration::from_millis(50)).await;
+		assert!(matches!(&rpl, DVReply::ErrorRpl(err) if err.code == "timeout"), "{:?}", rpl);
+		assert!(started.elapsed() < Duration::from_secs(5));
+	}
+}
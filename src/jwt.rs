@@ -0,0 +1,554 @@
+use crate::prelude::*;
+use crate::messages::{DVMessage, DVReply, ErrorInfo};
+use serde::{Serialize, Deserialize};
+use crate::cose::{self, CoseKey};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use hmac::{Hmac, Mac};
+use serde_bytes::ByteBuf;
+
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+/// How far (in seconds) the `iat` of a message may be from our clock.
+pub const DEFAULT_JWT_MAX_AGE: i64 = 300;
+
+/// `iss` of `Plain` messages that don't say who sent them.
+pub const LOCAL_ISSUER: &str = "local";
+
+/// First byte of a CBOR token, which is an array of two items.
+const CBOR_TOKEN_START: u8 = 0x82;
+
+/// How the claims of a token are serialized and signed.
+///
+/// It is agreed on once per connection (see `subprotocol`), but tokens say
+/// how they were encoded, so `JwtCodec::decode` takes any of them.
+/// `Cbor` tokens are the CBOR claims followed by their HMAC, and `Cose`
+/// tokens are COSE_Mac0 envelopes (RFC 9052), both sent as binary.
+/// `Plain` messages are the bare JSON claims, unsigned, which is only good
+/// enough for Unix socket connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+	#[default]
+	Json,
+	Cbor,
+	Cose,
+	Plain,
+}
+
+impl Encoding {
+	pub const ALL: [Encoding; 3] = [Encoding::Cose, Encoding::Cbor, Encoding::Json];
+	/// What Unix socket connections may use.
+	pub const LOCAL: [Encoding; 4] = [Encoding::Plain, Encoding::Cose, Encoding::Cbor, Encoding::Json];
+
+	/// Name of the WebSocket subprotocol used to ask for this encoding.
+	pub fn subprotocol(&self) -> &'static str {
+		match self {
+			Encoding::Json => "datavir.json",
+			Encoding::Cbor => "datavir.cbor",
+			Encoding::Cose => "datavir.cose",
+			Encoding::Plain => "datavir.plain",
+		}
+	}
+
+	pub fn from_subprotocol(val: &str) -> Option<Encoding> {
+		Encoding::LOCAL.iter().copied().find(|enc| enc.subprotocol() == val.trim())
+	}
+
+	pub fn from_name(val: &str) -> Option<Encoding> {
+		match val {
+			"json" => Some(Encoding::Json),
+			"cbor" => Some(Encoding::Cbor),
+			"cose" => Some(Encoding::Cose),
+			"plain" => Some(Encoding::Plain),
+			_ => None,
+		}
+	}
+
+	/// How a token was encoded, judging by its first bytes.
+	pub fn of_token(token: &[u8]) -> Encoding {
+		if cose::is_cose_token(token) {
+			return Encoding::Cose;
+		}
+		if is_plain_token(token) {
+			return Encoding::Plain;
+		}
+		if is_cbor_token(token) {
+			return Encoding::Cbor;
+		}
+		Encoding::Json
+	}
+
+	/// The first encoding in a `Sec-WebSocket-Protocol` list we understand.
+	/// `Plain` is only allowed on `local` connections.
+	pub fn negotiate(offered: &str, local: bool) -> Option<Encoding> {
+		offered.split(',')
+			.filter_map(Encoding::from_subprotocol)
+			.find(|enc| local || *enc != Encoding::Plain)
+	}
+}
+
+/// The `iss` claim: `{user-uuid} via {application-uuid-or-url}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issuer {
+	pub user: Uuid,
+	pub via: String,
+}
+
+impl Issuer {
+	pub fn new(user: Uuid, via: &str) -> Issuer {
+		Issuer{
+			user,
+			via: via.to_string(),
+		}
+	}
+
+	pub fn parse(val: &str) -> DVResult<Issuer> {
+		let (user, via) = match val.split_once(" via ") {
+			Some(v) => v,
+			None => return Err(DVError::InvalidIssuer(val.to_string())),
+		};
+		let user = match Uuid::parse_str(user.trim()) {
+			Ok(v) => v,
+			Err(_) => return Err(DVError::InvalidIssuer(val.to_string())),
+		};
+		let via = via.trim();
+		if via.is_empty() {
+			return Err(DVError::InvalidIssuer(val.to_string()));
+		}
+		Ok(Issuer::new(user, via))
+	}
+}
+
+impl std::fmt::Display for Issuer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} via {}", self.user.to_string().to_uppercase(), self.via)
+	}
+}
+
+/// What actually goes inside every JWT.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DVClaims {
+	pub iat: i64,
+	pub iss: String,
+	/// Correlation ID chosen by the requester and copied into the reply.
+	#[serde(rename = "reqId", default, skip_serializing_if = "Option::is_none")]
+	pub req_id: Option<u64>,
+	#[serde(flatten)]
+	pub msg: DVMessage,
+}
+
+/// Same as `DVClaims` but lets us tell which mandatory claim is missing.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RawClaims {
+	pub(crate) iat: Option<i64>,
+	pub(crate) iss: Option<String>,
+	#[serde(rename = "reqId", default)]
+	pub(crate) req_id: Option<u64>,
+	#[serde(flatten)]
+	pub(crate) msg: DVMessage,
+}
+
+/// Signs outgoing messages and verifies incoming ones.
+///
+/// For now there is a single user with a single (HMAC) key, so both sides of
+/// a connection must be configured with the same secret.
+#[derive(Clone)]
+pub struct JwtCodec {
+	issuer: Issuer,
+	max_age: i64,
+	encoding_key: EncodingKey,
+	decoding_key: DecodingKey,
+	mac_key: HmacSha256,
+	cose_key: CoseKey,
+}
+
+impl std::fmt::Debug for JwtCodec {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("JwtCodec")
+			.field("issuer", &self.issuer)
+			.field("max_age", &self.max_age)
+			.finish_non_exhaustive()
+	}
+}
+
+impl JwtCodec {
+	pub fn new(secret: &[u8], issuer: Issuer) -> DVResult<JwtCodec> {
+		if secret.is_empty() {
+			return Err(DVError::InvalidKey("the signing key is empty".to_string()));
+		}
+		Ok(JwtCodec{
+			issuer,
+			max_age: DEFAULT_JWT_MAX_AGE,
+			encoding_key: EncodingKey::from_secret(secret),
+			decoding_key: DecodingKey::from_secret(secret),
+			mac_key: mac_key(secret),
+			cose_key: CoseKey::from_secret(secret),
+		})
+	}
+
+	/// For local tools that only send `Plain` messages and so have no key.
+	/// A random one is used for anything that does get signed.
+	pub fn without_key(issuer: Issuer) -> JwtCodec {
+		let secret: [u8; 32] = rand::random();
+		JwtCodec{
+			issuer,
+			max_age: DEFAULT_JWT_MAX_AGE,
+			encoding_key: EncodingKey::from_secret(&secret),
+			decoding_key: DecodingKey::from_secret(&secret),
+			mac_key: mac_key(&secret),
+			cose_key: CoseKey::from_secret(&secret),
+		}
+	}
+
+	pub fn from_key_file(path: &Path, issuer: Issuer) -> DVResult<JwtCodec> {
+		let secret = match fs::read(path) {
+			Ok(v) => v,
+			Err(err) => {
+				error!("Failed to read signing key from {:?}: {}", path, err);
+				return Err(err)?
+			}
+		};
+		JwtCodec::new(&secret, issuer)
+	}
+
+	pub fn with_max_age(mut self, max_age: i64) -> JwtCodec {
+		self.max_age = max_age;
+		self
+	}
+
+	pub fn issuer(&self) -> &Issuer {
+		&self.issuer
+	}
+
+	pub fn encode(&self, req_id: Option<u64>, msg: DVMessage) -> DVResult<Vec<u8>> {
+		self.encode_as(Encoding::Json, req_id, msg)
+	}
+
+	/// JWTs come out as UTF-8 text, CBOR and COSE tokens as binary.
+	pub fn encode_as(&self, encoding: Encoding, req_id: Option<u64>, msg: DVMessage) -> DVResult<Vec<u8>> {
+		let claims = DVClaims{
+			iat: Utc::now().timestamp(),
+			iss: self.issuer.to_string(),
+			req_id,
+			msg,
+		};
+		match encoding {
+			Encoding::Json => Ok(jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)?.into_bytes()),
+			Encoding::Cbor => self.encode_cbor(&claims),
+			Encoding::Cose => self.cose_key.sign(&claims),
+			Encoding::Plain => Ok(serde_json::to_vec(&claims)?),
+		}
+	}
+
+	/// Signs the reply to request `req_id`. If that fails, the reply is
+	/// replaced with the `errorRpl` that says why, so the peer still hears
+	/// back.
+	pub fn encode_reply(&self, encoding: Encoding, req_id: Option<u64>, rpl: DVReply) -> DVResult<Vec<u8>> {
+		match self.encode_as(encoding, req_id, DVMessage::Rpl(rpl)) {
+			Ok(v) => Ok(v),
+			Err(err) => {
+				error!("Failed to encode reply to reqId={:?}: {:?}", req_id, err);
+				let rpl = DVReply::ErrorRpl(ErrorInfo::from(&err));
+				self.encode_as(encoding, req_id, DVMessage::Rpl(rpl))
+			}
+		}
+	}
+
+	/// `[claims, mac]`: the CBOR encoding of the claims and its HMAC-SHA256,
+	/// both as byte strings.
+	fn encode_cbor(&self, claims: &impl Serialize) -> DVResult<Vec<u8>> {
+		let payload = to_cbor(claims)?;
+		let mut mac = self.mac_key.clone();
+		mac.update(&payload);
+		let tag = mac.finalize().into_bytes().to_vec();
+		to_cbor(&(ByteBuf::from(payload), ByteBuf::from(tag)))
+	}
+
+	pub fn decode(&self, token: &[u8]) -> DVResult<DVClaims> {
+		match self.verify(token) {
+			Ok(claims) => self.check_claims(claims),
+			Err(err) => Err(self.unknown_message_or(token, err)),
+		}
+	}
+
+	/// The claims of a signed token, if the signature is good.
+	fn verify(&self, token: &[u8]) -> DVResult<RawClaims> {
+		if is_plain_token(token) {
+			return Err(DVError::InvalidSignature);
+		}
+		if cose::is_cose_token(token) {
+			return self.cose_key.verify(token);
+		}
+		if is_cbor_token(token) {
+			return self.decode_cbor(token);
+		}
+		let token = match std::str::from_utf8(token) {
+			Ok(v) => v,
+			Err(_) => return Err(DVError::JwtError(jsonwebtoken::errors::ErrorKind::InvalidToken.into())),
+		};
+		let mut validation = Validation::new(Algorithm::HS256);
+		// We check `iat` and `iss` ourselves so we can give better errors
+		validation.required_spec_claims.clear();
+		validation.validate_exp = false;
+		Ok(jsonwebtoken::decode::<RawClaims>(token, &self.decoding_key, &validation)?.claims)
+	}
+
+	/// Claims that can't be read because of a `msgType` we don't know come
+	/// from a peer newer than us rather than a broken one, and we say so.
+	fn unknown_message_or(&self, token: &[u8], err: DVError) -> DVError {
+		let unreadable = match &err {
+			DVError::JsonError(_) | DVError::CborError(_) => true,
+			DVError::JwtError(err) => matches!(err.kind(), jsonwebtoken::errors::ErrorKind::Json(_)),
+			_ => false,
+		};
+		if !unreadable {
+			return err;
+		}
+		match self.peek(token).and_then(|peeked| peeked.msg_type) {
+			Some(msg_type) if !DVMessage::is_known_type(&msg_type) => DVError::UnknownMessage(msg_type),
+			_ => err,
+		}
+	}
+
+	/// What every kind of token must satisfy once its signature is good.
+	fn check_claims(&self, claims: RawClaims) -> DVResult<DVClaims> {
+		let iat = match claims.iat {
+			Some(v) => v,
+			None => return Err(DVError::MissingClaim("iat".to_string())),
+		};
+		let iss = match claims.iss {
+			Some(v) => v,
+			None => return Err(DVError::MissingClaim("iss".to_string())),
+		};
+		Issuer::parse(&iss)?;
+		let age = Utc::now().timestamp() - iat;
+		if age.abs() > self.max_age {
+			return Err(DVError::StaleMessage(iat));
+		}
+
+		Ok(DVClaims{
+			iat,
+			iss,
+			req_id: claims.req_id,
+			msg: claims.msg,
+		})
+	}
+
+	fn decode_cbor(&self, token: &[u8]) -> DVResult<RawClaims> {
+		let (payload, tag) = split_cbor_token(token)?;
+		let mut mac = self.mac_key.clone();
+		mac.update(&payload);
+		if mac.verify_slice(&tag).is_err() {
+			return Err(DVError::InvalidSignature);
+		}
+		from_cbor(&payload)
+	}
+
+	/// Same as `decode`, but also takes `Plain` messages, whose `iat` and
+	/// `iss` are optional. Only for peers we already trust, like those on
+	/// the other end of a Unix socket.
+	pub fn decode_local(&self, token: &[u8]) -> DVResult<DVClaims> {
+		if !is_plain_token(token) {
+			return self.decode(token);
+		}
+		let claims: RawClaims = match serde_json::from_slice(token) {
+			Ok(v) => v,
+			Err(err) => return Err(self.unknown_message_or(token, err.into())),
+		};
+		Ok(DVClaims{
+			iat: claims.iat.unwrap_or_else(|| Utc::now().timestamp()),
+			iss: claims.iss.unwrap_or_else(|| LOCAL_ISSUER.to_string()),
+			req_id: claims.req_id,
+			msg: claims.msg,
+		})
+	}
+
+	/// Reads `reqId` WITHOUT checking anything else.
+	///
+	/// Only meant for addressing the error reply of a message that failed
+	/// `decode`.
+	pub fn peek_req_id(&self, token: &[u8]) -> Option<u64> {
+		self.peek(token).and_then(|peeked| peeked.req_id)
+	}
+
+	fn peek(&self, token: &[u8]) -> Option<Peeked> {
+		if cose::is_cose_token(token) {
+			return from_cbor(&cose::peek_payload(token).ok()?).ok();
+		}
+		if is_plain_token(token) {
+			return serde_json::from_slice(token).ok();
+		}
+		if is_cbor_token(token) {
+			let (payload, _) = split_cbor_token(token).ok()?;
+			return from_cbor(&payload).ok();
+		}
+		let token = std::str::from_utf8(token).ok()?;
+		let mut validation = Validation::new(Algorithm::HS256);
+		validation.insecure_disable_signature_validation();
+		validation.required_spec_claims.clear();
+		validation.validate_exp = false;
+		match jsonwebtoken::decode::<Peeked>(token, &self.decoding_key, &validation) {
+			Ok(data) => Some(data.claims),
+			Err(_) => None,
+		}
+	}
+}
+
+/// The claims `JwtCodec::peek` reads from tokens it could not decode.
+#[derive(Deserialize)]
+struct Peeked {
+	#[serde(rename = "reqId", default)]
+	req_id: Option<u64>,
+	#[serde(rename = "msgType", default)]
+	msg_type: Option<String>,
+}
+
+/// Bare JSON claims, as opposed to a JWT (which starts with base64).
+fn is_plain_token(token: &[u8]) -> bool {
+	token.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+}
+
+fn is_cbor_token(token: &[u8]) -> bool {
+	token.first() == Some(&CBOR_TOKEN_START)
+}
+
+fn mac_key(secret: &[u8]) -> HmacSha256 {
+	HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size")
+}
+
+/// The claims and the MAC of a CBOR token, still unchecked.
+fn split_cbor_token(token: &[u8]) -> DVResult<(ByteBuf, ByteBuf)> {
+	from_cbor(token)
+}
+
+fn to_cbor<T: Serialize>(val: &T) -> DVResult<Vec<u8>> {
+	let mut ans = Vec::new();
+	match ciborium::ser::into_writer(val, &mut ans) {
+		Ok(()) => Ok(ans),
+		Err(err) => Err(DVError::CborError(err.to_string())),
+	}
+}
+
+fn from_cbor<T: serde::de::DeserializeOwned>(data: &[u8]) -> DVResult<T> {
+	match ciborium::de::from_reader(data) {
+		Ok(v) => Ok(v),
+		Err(err) => Err(DVError::CborError(err.to_string())),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::messages::DVRequest;
+
+	const SIGNED: [Encoding; 3] = [Encoding::Json, Encoding::Cbor, Encoding::Cose];
+
+	fn codec(secret: &[u8]) -> JwtCodec {
+		JwtCodec::new(secret, Issuer::new(Uuid::nil(), "test")).unwrap()
+	}
+
+	fn claims(iat: i64, iss: &str) -> DVClaims {
+		DVClaims{
+			iat,
+			iss: iss.to_string(),
+			req_id: Some(7),
+			msg: DVMessage::Req(DVRequest::GetTimeReq),
+		}
+	}
+
+	/// Signs arbitrary claims, which `encode_as` won't do.
+	fn sign(codec: &JwtCodec, encoding: Encoding, claims: &impl Serialize) -> Vec<u8> {
+		match encoding {
+			Encoding::Json => jsonwebtoken::encode(&Header::new(Algorithm::HS256), claims, &codec.encoding_key).unwrap().into_bytes(),
+			Encoding::Cbor => codec.encode_cbor(claims).unwrap(),
+			Encoding::Cose => codec.cose_key.sign(claims).unwrap(),
+			Encoding::Plain => serde_json::to_vec(claims).unwrap(),
+		}
+	}
+
+	#[test]
+	fn round_trips_every_encoding() {
+		let codec = codec(b"secret");
+		for encoding in SIGNED {
+			let token = codec.encode_as(encoding, Some(7), DVMessage::Req(DVRequest::GetTimeReq)).unwrap();
+			assert_eq!(Encoding::of_token(&token), encoding);
+			let claims = codec.decode(&token).unwrap();
+			assert_eq!(claims.req_id, Some(7));
+			assert_eq!(claims.iss, codec.issuer().to_string());
+			assert_eq!(codec.peek_req_id(&token), Some(7));
+		}
+	}
+
+	#[test]
+	fn rejects_bad_signature() {
+		let ours = codec(b"secret");
+		let theirs = codec(b"another secret");
+		for encoding in SIGNED {
+			let token = theirs.encode_as(encoding, Some(7), DVMessage::Req(DVRequest::GetTimeReq)).unwrap();
+			assert!(matches!(ours.decode(&token), Err(DVError::InvalidSignature)), "{:?}", encoding);
+			// The reply still finds its way back
+			assert_eq!(ours.peek_req_id(&token), Some(7));
+		}
+	}
+
+	#[test]
+	fn rejects_stale_messages() {
+		let codec = codec(b"secret");
+		let iss = codec.issuer().to_string();
+		let now = Utc::now().timestamp();
+		for encoding in SIGNED {
+			for iat in [now - DEFAULT_JWT_MAX_AGE - 10, now + DEFAULT_JWT_MAX_AGE + 10] {
+				let token = sign(&codec, encoding, &claims(iat, &iss));
+				assert!(matches!(codec.decode(&token), Err(DVError::StaleMessage(v)) if v == iat), "{:?}", encoding);
+			}
+		}
+	}
+
+	#[test]
+	fn rejects_wrong_issuer() {
+		let codec = codec(b"secret");
+		let now = Utc::now().timestamp();
+		for encoding in SIGNED {
+			for iss in ["nobody", "not-a-uuid via test", "00000000-0000-0000-0000-000000000000 via "] {
+				let token = sign(&codec, encoding, &claims(now, iss));
+				assert!(matches!(codec.decode(&token), Err(DVError::InvalidIssuer(_))), "{:?} {:?}", encoding, iss);
+			}
+		}
+	}
+
+	#[test]
+	fn plain_is_only_accepted_locally() {
+		let codec = codec(b"secret");
+		let token = codec.encode_as(Encoding::Plain, Some(7), DVMessage::Req(DVRequest::GetTimeReq)).unwrap();
+		assert_eq!(Encoding::of_token(&token), Encoding::Plain);
+		assert!(matches!(codec.decode(&token), Err(DVError::InvalidSignature)));
+		assert_eq!(codec.decode_local(&token).unwrap().req_id, Some(7));
+
+		// `iat` and `iss` may be left out
+		let token = br#"{"reqId": 1, "msgType": "getTimeReq"}"#;
+		assert!(matches!(codec.decode(token), Err(DVError::InvalidSignature)));
+		let claims = codec.decode_local(token).unwrap();
+		assert_eq!(claims.iss, LOCAL_ISSUER);
+		assert_eq!(claims.msg, DVMessage::Req(DVRequest::GetTimeReq));
+	}
+
+	#[test]
+	fn tells_unknown_messages_from_bad_ones() {
+		let codec = codec(b"secret");
+		let iss = codec.issuer().to_string();
+		let now = Utc::now().timestamp();
+		let unknown = serde_json::json!({"iat": now, "iss": iss, "reqId": 7, "msgType": "fromTheFutureReq"});
+		let broken = serde_json::json!({"iat": now, "iss": iss, "reqId": 7, "msgType": "nodeInfoReq"});
+		for encoding in [Encoding::Json, Encoding::Cbor, Encoding::Cose, Encoding::Plain] {
+			let token = sign(&codec, encoding, &unknown);
+			let err = codec.decode_local(&token).unwrap_err();
+			assert!(matches!(&err, DVError::UnknownMessage(t) if t == "fromTheFutureReq"), "{:?}", encoding);
+			assert_eq!(ErrorInfo::from(&err).code, "unknownMessage");
+			assert_eq!(codec.peek_req_id(&token), Some(7));
+
+			let token = sign(&codec, encoding, &broken);
+			let err = codec.decode_local(&token).unwrap_err();
+			assert_eq!(ErrorInfo::from(&err).code, "badMessage", "{:?}", encoding);
+		}
+	}
+}
@@ -1,6 +1,20 @@
 #[macro_use]
 pub mod prelude;
 
+pub mod messages;
+pub mod coap_client;
+pub mod coap_server;
+pub mod cose;
+pub mod dispatcher;
+pub mod jwt;
+pub mod net;
+pub mod nodes;
+pub mod notices;
+pub mod paging;
+pub mod schema;
+pub mod streams;
+pub mod utils;
+pub mod volumes;
 pub mod ws_client;
 pub mod ws_server;
 
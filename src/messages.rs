@@ -0,0 +1,1051 @@
+use crate::prelude::*;
+use serde::{Serialize, Deserialize};
+use serde::de::value::MapDeserializer;
+use serde_bytes::ByteBuf;
+use crate::jwt::Encoding;
+
+/// Versions of the message set this build speaks, oldest first.
+pub const PROTOCOL_VERSIONS: [u32; 1] = [1];
+
+/// Any message that can travel between datavir nodes and clients.
+///
+/// The concrete kind is given by the `msgType` field (see MESSAGES.md).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DVMessage {
+	Req(DVRequest),
+	Rpl(DVReply),
+	Ntc(DVNotice),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "msgType", rename_all = "camelCase")]
+pub enum DVRequest {
+	HelloReq(HelloReq),
+	GetTimeReq,
+	ListVolumesReq(ListVolumesReq),
+	NewVolumeReq(NewVolumeReq),
+	RenameVolumeReq(RenameVolumeReq),
+	DeleteVolumeReq(DeleteVolumeReq),
+	NodeInfoReq(NodeInfoReq),
+	ListChildrenReq(ListChildrenReq),
+	CreateNodeReq(CreateNodeReq),
+	RenameNodeReq(RenameNodeReq),
+	MoveNodeReq(MoveNodeReq),
+	DeleteNodeReq(DeleteNodeReq),
+	CloneNodeReq(CloneNodeReq),
+	StreamHashReq(StreamHashReq),
+	ReadStreamReq(ReadStreamReq),
+	WriteStreamReq(WriteStreamReq),
+	TruncateStreamReq(TruncateStreamReq),
+	AppendStreamReq(AppendStreamReq),
+	BeginUploadReq(BeginUploadReq),
+	UploadChunkReq(UploadChunkReq),
+	FinishUploadReq(FinishUploadReq),
+	AbortUploadReq(AbortUploadReq),
+	SubscribeReq(SubscribeReq),
+	UnsubscribeReq(UnsubscribeReq),
+	CancelReq(CancelReq),
+	BatchReq(BatchReq),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "msgType", rename_all = "camelCase")]
+pub enum DVReply {
+	HelloRpl(HelloRpl),
+	GetTimeRpl(GetTimeRpl),
+	ListVolumesRpl(ListVolumesRpl),
+	NewVolumeRpl(NewVolumeRpl),
+	RenameVolumeRpl(RenameVolumeRpl),
+	DeleteVolumeRpl(DeleteVolumeRpl),
+	NodeInfoRpl(NodeInfoRpl),
+	ListChildrenRpl(ListChildrenRpl),
+	CreateNodeRpl(CreateNodeRpl),
+	RenameNodeRpl(RenameNodeRpl),
+	MoveNodeRpl(MoveNodeRpl),
+	DeleteNodeRpl(DeleteNodeRpl),
+	CloneNodeRpl(CloneNodeRpl),
+	StreamHashRpl(StreamHashRpl),
+	ReadStreamRpl(ReadStreamRpl),
+	WriteStreamRpl(WriteStreamRpl),
+	TruncateStreamRpl(TruncateStreamRpl),
+	AppendStreamRpl(AppendStreamRpl),
+	BeginUploadRpl(BeginUploadRpl),
+	UploadChunkRpl(UploadChunkRpl),
+	FinishUploadRpl(FinishUploadRpl),
+	AbortUploadRpl,
+	SubscribeRpl(SubscribeRpl),
+	UnsubscribeRpl,
+	CancelRpl(CancelRpl),
+	BatchRpl(BatchRpl),
+	ErrorRpl(ErrorInfo),
+}
+
+/// Messages the server sends without being asked (they never have a `reqId`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "msgType", rename_all = "camelCase")]
+pub enum DVNotice {
+	NodeChangedNtc(NodeChangedNtc),
+	NoticesLostNtc(NoticesLostNtc),
+}
+
+impl DVMessage {
+	pub fn to_json(&self) -> DVResult<String> {
+		Ok(serde_json::to_string(self)?)
+	}
+
+	pub fn from_json(data: &[u8]) -> DVResult<DVMessage> {
+		Ok(serde_json::from_slice(data)?)
+	}
+
+	/// Whether `msg_type` names a request, reply or notice of this build.
+	pub fn is_known_type(msg_type: &str) -> bool {
+		knows_tag::<DVRequest>(msg_type) || knows_tag::<DVReply>(msg_type) || knows_tag::<DVNotice>(msg_type)
+	}
+}
+
+/// Whether `T`, an enum tagged with `msgType`, has a variant for `msg_type`.
+fn knows_tag<'de, T: Deserialize<'de>>(msg_type: &'de str) -> bool {
+	let map = MapDeserializer::new(std::iter::once(("msgType", msg_type)));
+	!matches!(T::deserialize(map), Err(TagCheck::UnknownVariant))
+}
+
+/// Tells apart the `msgType`s serde does not know from messages that just
+/// lack fields, which is all `DVMessage::is_known_type` needs.
+#[derive(Debug)]
+enum TagCheck {
+	UnknownVariant,
+	Other,
+}
+
+impl serde::de::Error for TagCheck {
+	fn custom<T: std::fmt::Display>(_msg: T) -> Self {
+		TagCheck::Other
+	}
+
+	fn unknown_variant(_variant: &str, _expected: &'static [&'static str]) -> Self {
+		TagCheck::UnknownVariant
+	}
+}
+
+impl std::fmt::Display for TagCheck {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl std::error::Error for TagCheck {}
+
+impl DVRequest {
+	/// The `msgType` of this request, mostly for logging.
+	pub fn msg_type(&self) -> &'static str {
+		match self {
+			DVRequest::HelloReq(_) => "helloReq",
+			DVRequest::GetTimeReq => "getTimeReq",
+			DVRequest::ListVolumesReq(_) => "listVolumesReq",
+			DVRequest::NewVolumeReq(_) => "newVolumeReq",
+			DVRequest::RenameVolumeReq(_) => "renameVolumeReq",
+			DVRequest::DeleteVolumeReq(_) => "deleteVolumeReq",
+			DVRequest::NodeInfoReq(_) => "nodeInfoReq",
+			DVRequest::ListChildrenReq(_) => "listChildrenReq",
+			DVRequest::CreateNodeReq(_) => "createNodeReq",
+			DVRequest::RenameNodeReq(_) => "renameNodeReq",
+			DVRequest::MoveNodeReq(_) => "moveNodeReq",
+			DVRequest::DeleteNodeReq(_) => "deleteNodeReq",
+			DVRequest::CloneNodeReq(_) => "cloneNodeReq",
+			DVRequest::StreamHashReq(_) => "streamHashReq",
+			DVRequest::ReadStreamReq(_) => "readStreamReq",
+			DVRequest::WriteStreamReq(_) => "writeStreamReq",
+			DVRequest::TruncateStreamReq(_) => "truncateStreamReq",
+			DVRequest::AppendStreamReq(_) => "appendStreamReq",
+			DVRequest::BeginUploadReq(_) => "beginUploadReq",
+			DVRequest::UploadChunkReq(_) => "uploadChunkReq",
+			DVRequest::FinishUploadReq(_) => "finishUploadReq",
+			DVRequest::AbortUploadReq(_) => "abortUploadReq",
+			DVRequest::SubscribeReq(_) => "subscribeReq",
+			DVRequest::UnsubscribeReq(_) => "unsubscribeReq",
+			DVRequest::CancelReq(_) => "cancelReq",
+			DVRequest::BatchReq(_) => "batchReq",
+		}
+	}
+
+	/// Whether sending it twice does no more harm than sending it once, so
+	/// it can be replayed after losing the connection.
+	pub fn is_idempotent(&self) -> bool {
+		match self {
+			DVRequest::HelloReq(_)
+			| DVRequest::GetTimeReq
+			| DVRequest::ListVolumesReq(_)
+			| DVRequest::NodeInfoReq(_)
+			| DVRequest::ListChildrenReq(_)
+			| DVRequest::StreamHashReq(_)
+			| DVRequest::ReadStreamReq(_) => true,
+			DVRequest::NewVolumeReq(_)
+			| DVRequest::RenameVolumeReq(_)
+			| DVRequest::DeleteVolumeReq(_)
+			| DVRequest::CreateNodeReq(_)
+			| DVRequest::RenameNodeReq(_)
+			| DVRequest::MoveNodeReq(_)
+			| DVRequest::DeleteNodeReq(_)
+			| DVRequest::CloneNodeReq(_)
+			| DVRequest::WriteStreamReq(_)
+			| DVRequest::TruncateStreamReq(_)
+			| DVRequest::AppendStreamReq(_)
+			| DVRequest::BeginUploadReq(_)
+			| DVRequest::UploadChunkReq(_)
+			| DVRequest::FinishUploadReq(_)
+			| DVRequest::AbortUploadReq(_)
+			| DVRequest::SubscribeReq(_)
+			| DVRequest::UnsubscribeReq(_)
+			// Means nothing on another connection
+			| DVRequest::CancelReq(_)
+			| DVRequest::BatchReq(_) => false,
+		}
+	}
+
+	/// Whether it only changes the database, so it can be part of a
+	/// `batchReq`.
+	pub fn is_batchable(&self) -> bool {
+		matches!(self,
+			DVRequest::NewVolumeReq(_)
+			| DVRequest::RenameVolumeReq(_)
+			| DVRequest::DeleteVolumeReq(_)
+			| DVRequest::CreateNodeReq(_)
+			| DVRequest::RenameNodeReq(_)
+			| DVRequest::MoveNodeReq(_)
+			| DVRequest::DeleteNodeReq(_)
+			| DVRequest::CloneNodeReq(_)
+			| DVRequest::WriteStreamReq(_)
+			| DVRequest::TruncateStreamReq(_)
+			| DVRequest::AppendStreamReq(_))
+	}
+}
+
+impl DVReply {
+	/// Whether the request it answers did not go through, be it with an
+	/// error or a status other than `ok`.
+	pub fn is_failure(&self) -> bool {
+		match self {
+			DVReply::ErrorRpl(_) => true,
+			DVReply::NewVolumeRpl(rpl) => rpl.status != VolumeStatus::Ok,
+			DVReply::RenameVolumeRpl(rpl) => rpl.status != VolumeStatus::Ok,
+			DVReply::DeleteVolumeRpl(rpl) => rpl.status != VolumeStatus::Ok,
+			DVReply::BatchRpl(rpl) => !rpl.committed,
+			_ => false,
+		}
+	}
+}
+
+impl From<DVRequest> for DVMessage {
+	fn from(req: DVRequest) -> Self {
+		DVMessage::Req(req)
+	}
+}
+
+impl From<DVReply> for DVMessage {
+	fn from(rpl: DVReply) -> Self {
+		DVMessage::Rpl(rpl)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorInfo {
+	pub code: String,
+	pub message: String,
+	/// The request that failed, when there was one and it could be read.
+	/// On the wire this is the `reqId` of the message carrying the error.
+	#[serde(skip)]
+	pub req_id: Option<u64>,
+}
+
+impl ErrorInfo {
+	pub fn new(code: &str, message: &str) -> ErrorInfo {
+		ErrorInfo{
+			code: code.to_string(),
+			message: message.to_string(),
+			req_id: None,
+		}
+	}
+
+	pub fn for_request(mut self, req_id: Option<u64>) -> ErrorInfo {
+		self.req_id = req_id;
+		self
+	}
+}
+
+impl From<DVNotice> for DVMessage {
+	fn from(ntc: DVNotice) -> Self {
+		DVMessage::Ntc(ntc)
+	}
+}
+
+/// Every error has a code that stays the same between versions, so peers can
+/// act on it. The message is only meant for humans.
+impl From<&DVError> for ErrorInfo {
+	fn from(err: &DVError) -> Self {
+		match err {
+			DVError::NotImplemented => ErrorInfo::new("notImplemented", "not implemented"),
+			DVError::RemoteError(info) => info.clone(),
+			DVError::InvalidSignature => ErrorInfo::new("badSignature", "invalid message signature"),
+			DVError::InvalidKey(reason) => ErrorInfo::new("badKey", &format!("invalid key: {}", reason)),
+			// The token itself was fine, the claims in it were not
+			DVError::JwtError(err) if matches!(err.kind(), jsonwebtoken::errors::ErrorKind::Json(_)) => ErrorInfo::new("badMessage", &err.to_string()),
+			DVError::JwtError(err) => ErrorInfo::new("badToken", &err.to_string()),
+			DVError::CborError(err) => ErrorInfo::new("badMessage", &format!("bad CBOR payload: {}", err)),
+			DVError::JsonError(err) => ErrorInfo::new("badMessage", &format!("bad JSON payload: {}", err)),
+			DVError::UnknownMessage(msg_type) => ErrorInfo::new("unknownMessage", &format!("unknown msgType {:?}", msg_type)),
+			DVError::UnexpectedMessage(what) => ErrorInfo::new("unexpectedMessage", what),
+			DVError::MissingClaim(claim) => ErrorInfo::new("missingClaim", &format!("missing mandatory claim {:?}", claim)),
+			DVError::InvalidIssuer(iss) => ErrorInfo::new("badIssuer", &format!("invalid issuer {:?}", iss)),
+			DVError::StaleMessage(iat) => ErrorInfo::new("staleMessage", &format!("message issued at {} is too old or too new", iat)),
+			DVError::InvalidName(reason) => ErrorInfo::new("invalidName", &format!("invalid name: {}", reason)),
+			DVError::InvalidArgument(what) => ErrorInfo::new("invalidArgument", &format!("invalid argument {}", what)),
+			DVError::SQLError(err) if is_sql_err_not_found(err) => ErrorInfo::new("notFound", "no such record"),
+			DVError::SQLError(err) if is_sql_err_conflict(err) => ErrorInfo::new("conflict", &format!("clashes with an existing record: {}", err)),
+			DVError::SQLError(err) => ErrorInfo::new("databaseError", &err.to_string()),
+			DVError::UuidParseError(what) => ErrorInfo::new("badUuid", &format!("invalid UUID {:?}", what)),
+			DVError::InvalidUrl(url) => ErrorInfo::new("badUrl", &format!("invalid URL {:?}", url)),
+			DVError::NotFound(what) => ErrorInfo::new("notFound", &format!("{} not found", what)),
+			DVError::VolumeRequired(path) => ErrorInfo::new("volumeRequired", &format!("path {:?} needs a volume", path)),
+			DVError::BadOffset{expected, got} => ErrorInfo::new("badOffset", &format!("expected offset {} but got {}", expected, got)),
+			DVError::OffsetTooLarge(offset) => ErrorInfo::new("badOffset", &format!("offset {} is past the largest stream size", offset)),
+			DVError::ChunkTooLarge(len) => ErrorInfo::new("chunkTooLarge", &format!("chunk of {} bytes is too large", len)),
+			DVError::HashMismatch => ErrorInfo::new("hashMismatch", "content does not match the given hash"),
+			DVError::Conflict(what) => ErrorInfo::new("conflict", what),
+			DVError::UnknownSubscription(id) => ErrorInfo::new("unknownSubscription", &format!("no subscription {}", id)),
+			DVError::UnsupportedProtocol(reason) => ErrorInfo::new("unsupportedProtocol", reason),
+			DVError::Timeout(what) => ErrorInfo::new("timeout", what),
+			DVError::ConnectionLost(what) => ErrorInfo::new("transportError", what),
+			DVError::Cancelled(what) => ErrorInfo::new("cancelled", what),
+			DVError::DirNotClear(path) => ErrorInfo::new("dirNotClear", &format!("{:?} is not empty", path)),
+			DVError::NotBatchable(msg_type) => ErrorInfo::new("notBatchable", &format!("{} can't be part of a batch", msg_type)),
+			DVError::NoMoreResults => ErrorInfo::new("noMoreResults", "no more results"),
+			DVError::BadCursor(reason) => ErrorInfo::new("badCursor", &format!("invalid cursor: {}", reason)),
+			DVError::NotReady(what) => ErrorInfo::new("notReady", what),
+			DVError::IOError(err) => ErrorInfo::new("ioError", &err.to_string()),
+			DVError::RawWsError(err) => ErrorInfo::new("transportError", &err.to_string()),
+			DVError::TlsError(err) => ErrorInfo::new("transportError", &format!("TLS: {}", err)),
+			DVError::CoapError(err) => ErrorInfo::new("transportError", &format!("CoAP: {}", err)),
+			DVError::SystemTimeError(_)
+			| DVError::TimeConversionErrorFromSecs(_)
+			| DVError::MpscRecvError(_)
+			| DVError::MpscSendError(_)
+			| DVError::OneshotRecvError(_) => ErrorInfo::new("internalError", &format!("{:?}", err)),
+		}
+	}
+}
+
+/// Either a node UUID or a path relative to a volume root.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum NodeOrPath {
+	Node(Uuid),
+	Path(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HelloReq {
+	pub datavir_version: String,
+	pub protocol_versions: Vec<u32>,
+}
+
+impl HelloReq {
+	/// Introduces this build.
+	pub fn new() -> HelloReq {
+		HelloReq{
+			datavir_version: DATAVIR_VERSION.to_string(),
+			protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+		}
+	}
+}
+
+impl Default for HelloReq {
+	fn default() -> Self {
+		HelloReq::new()
+	}
+}
+
+/// Optional parts of a node, which depend on how it was started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Feature {
+	Tls,
+	ClientCertificates,
+	Coap,
+	UnixSocket,
+}
+
+/// What a node can do, as told to whoever says hello.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HelloRpl {
+	pub datavir_version: String,
+	/// The newest version both sides speak, used from now on.
+	pub protocol_version: u32,
+	pub protocol_versions: Vec<u32>,
+	pub encodings: Vec<Encoding>,
+	pub hash_algorithms: Vec<HashAlg>,
+	/// Producers of virtual files.
+	pub generators: Vec<String>,
+	/// Unknown features are skipped, so older clients can still read this.
+	#[serde(deserialize_with = "known_features")]
+	pub features: Vec<Feature>,
+}
+
+fn known_features<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Feature>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum MaybeFeature {
+		Known(Feature),
+		#[allow(dead_code)]
+		Unknown(serde::de::IgnoredAny),
+	}
+	let features: Vec<MaybeFeature> = Vec::deserialize(deserializer)?;
+	Ok(features.into_iter().filter_map(|feature| match feature {
+		MaybeFeature::Known(feature) => Some(feature),
+		MaybeFeature::Unknown(_) => None,
+	}).collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetTimeRpl {
+	pub current_time: DateTime<Utc>,
+}
+
+/// What listings can be sorted by. Ties are broken by UUID, so the order
+/// is stable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ListOrder {
+	#[default]
+	Name,
+	ChangedAt,
+	CreatedAt,
+}
+
+/// Which part of a listing to return.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageReq {
+	#[serde(default)]
+	pub order_by: ListOrder,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub limit: Option<u32>,
+	/// The `next` of the previous page, none for the first one.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub cursor: Option<String>,
+}
+
+impl PageReq {
+	/// The page after the one that ended with `next`.
+	pub fn after(&self, next: String) -> PageReq {
+		PageReq{
+			cursor: Some(next),
+			..self.clone()
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListVolumesReq {
+	#[serde(flatten)]
+	pub page: PageReq,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListVolumesRpl {
+	pub volumes: Vec<VolumeInfo>,
+	/// Set when there are more, to be passed as the next `cursor`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub next: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewVolumeReq {
+	pub volume: VolumeInfo,
+}
+
+/// Outcome of a volume operation that did not fail outright.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum VolumeStatus {
+	Ok,
+	NotFound,
+	NameTaken,
+	UuidTaken,
+	InvalidName,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewVolumeRpl {
+	pub status: VolumeStatus,
+	/// The volume as stored (with its UUID filled in) when `status` is `ok`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<VolumeInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameVolumeReq {
+	pub volume: Uuid,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameVolumeRpl {
+	pub status: VolumeStatus,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<VolumeInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteVolumeReq {
+	pub volume: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteVolumeRpl {
+	pub status: VolumeStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeInfo {
+	pub uuid: Uuid,
+	pub title: String,
+	pub name: String,
+	pub is_real: bool,
+	pub uid2name: HashMap<u16, String>,
+	pub gid2name: HashMap<u16, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfoReq {
+	pub nodes_or_paths: Vec<NodeOrPath>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfoRpl {
+	pub nodes: Vec<NodeInfoOrError>,
+	pub paths2uuid: HashMap<String, Uuid>,
+}
+
+/// The nodes right below a directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListChildrenReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	#[serde(flatten)]
+	pub page: PageReq,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListChildrenRpl {
+	pub node: Uuid,
+	pub children: Vec<NodeInfo>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub next: Option<String>,
+}
+
+/// Creates a node right below `parent`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateNodeReq {
+	pub parent: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	pub name: String,
+	/// Missing or nil lets the node pick one.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub uuid: Option<Uuid>,
+	#[serde(rename = "file-kind")]
+	pub file_kind: FileKind,
+	#[serde(default)]
+	pub title: String,
+	#[serde(default)]
+	pub description: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub unix_perm: Option<UnixPerm>,
+	/// What a symbolic link points to, kept as its content.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub target: Option<String>,
+	/// The node a hard link shares its content with.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub link_to: Option<NodeOrPath>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateNodeRpl {
+	pub node: NodeInfo,
+}
+
+/// Changes whichever of the name, title and description are given.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameNodeReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub title: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameNodeRpl {
+	pub node: NodeInfo,
+}
+
+/// Gives a node another parent in the same volume, and optionally another
+/// name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveNodeReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	pub parent: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveNodeRpl {
+	pub node: NodeInfo,
+}
+
+/// Deletes a node, and with `recursive` everything below it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteNodeReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	#[serde(default)]
+	pub recursive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteNodeRpl {
+	pub node: Uuid,
+	/// How many nodes were deleted, the node itself included.
+	pub count: u64,
+}
+
+/// Copies a node below `parent` in the same volume, and with `recursive`
+/// everything below it too. Copies share their content with the originals
+/// until either side changes it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloneNodeReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	pub parent: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	#[serde(default)]
+	pub recursive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloneNodeRpl {
+	/// The copy of `node`.
+	pub node: NodeInfo,
+	/// How many nodes were copied, `node` itself included.
+	pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum NodeInfoOrError {
+	Node(Box<NodeInfo>),
+	Error(ErrorInfo),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfo {
+	pub uuid: Uuid,
+	pub name: String,
+	pub title: String,
+	pub description: String,
+	pub parents: Vec<Uuid>,
+	pub content: ContentRef,
+	#[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+	pub thumbnail: Option<Vec<u8>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub unix_perm: Option<UnixPerm>,
+	pub xattrs: HashMap<String, XattrVal>,
+	pub created: DateTime<Utc>,
+	pub changed: DateTime<Utc>,
+	pub volume: Uuid,
+	pub in_trash: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub trashed_by: Option<Uuid>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub trashed_when: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnixPerm {
+	pub mode: u16,
+	pub uid: u16,
+	pub gid: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileKind {
+	Empty,
+	Regular,
+	SymbolicLink,
+	HardLink,
+	Socket,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentRef {
+	#[serde(rename = "file-kind")]
+	pub file_kind: FileKind,
+	pub copy_on_write: bool,
+	pub stream: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct XattrVal {
+	pub format: String,
+	#[serde(with = "serde_bytes")]
+	pub value: Vec<u8>,
+}
+
+/// Digest algorithms a stream can be hashed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HashAlg {
+	#[serde(rename = "sha-256")]
+	Sha256,
+	#[serde(rename = "sha-1")]
+	Sha1,
+	#[serde(rename = "blake3")]
+	Blake3,
+	#[serde(rename = "md5")]
+	Md5,
+}
+
+impl HashAlg {
+	pub const ALL: [HashAlg; 4] = [HashAlg::Sha256, HashAlg::Sha1, HashAlg::Blake3, HashAlg::Md5];
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamHashReq {
+	pub alg: HashAlg,
+	pub nodes_or_paths: Vec<NodeOrPath>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamHashRpl {
+	pub alg: HashAlg,
+	pub values: HashMap<Uuid, ByteBuf>,
+	pub paths2uuid: HashMap<String, Uuid>,
+}
+
+/// Asks for up to `length` bytes of the content of a node, starting at `offset`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadStreamReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	pub offset: u64,
+	pub length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadStreamRpl {
+	pub node: Uuid,
+	pub offset: u64,
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+	/// Size of the whole stream.
+	pub size: u64,
+	/// Changes whenever the content does, so readers can tell if the stream
+	/// changed halfway through a download.
+	pub version: u64,
+}
+
+/// What the content of a node must still be for a change to go through,
+/// so that changes made by others in the meantime aren't overwritten.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpectedContent {
+	/// As in `readStreamRpl`, 0 for a node without content.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub expected_version: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub expected_hash: Option<StreamDigest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamDigest {
+	pub alg: HashAlg,
+	#[serde(with = "serde_bytes")]
+	pub value: Vec<u8>,
+}
+
+/// Overwrites part of the content of a node, growing it if needed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteStreamReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	pub offset: u64,
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+	#[serde(flatten)]
+	pub expect: ExpectedContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteStreamRpl {
+	pub node: Uuid,
+	pub size: u64,
+	pub version: u64,
+}
+
+/// Cuts the content of a node down to `size` bytes, or grows it with zeros.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TruncateStreamReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	pub size: u64,
+	#[serde(flatten)]
+	pub expect: ExpectedContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TruncateStreamRpl {
+	pub node: Uuid,
+	pub size: u64,
+	pub version: u64,
+}
+
+/// Adds to the end of the content of a node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppendStreamReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+	#[serde(flatten)]
+	pub expect: ExpectedContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppendStreamRpl {
+	pub node: Uuid,
+	/// Where the data went.
+	pub offset: u64,
+	pub size: u64,
+	pub version: u64,
+}
+
+/// Starts (or, with `resume`, picks up) an upload that will replace the
+/// content of a node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BeginUploadReq {
+	pub node: NodeOrPath,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Uuid>,
+	#[serde(default)]
+	pub resume: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BeginUploadRpl {
+	pub upload: Uuid,
+	pub node: Uuid,
+	/// How many bytes were already received, i.e. where to continue from.
+	pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadChunkReq {
+	pub upload: Uuid,
+	pub offset: u64,
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadChunkRpl {
+	pub upload: Uuid,
+	pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FinishUploadReq {
+	pub upload: Uuid,
+	pub alg: HashAlg,
+	#[serde(with = "serde_bytes")]
+	pub hash: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FinishUploadRpl {
+	pub node: Uuid,
+	pub size: u64,
+	pub version: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AbortUploadReq {
+	pub upload: Uuid,
+}
+
+/// What a subscription is watching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "scope", content = "uuid", rename_all = "camelCase")]
+pub enum WatchTarget {
+	/// Only the node itself.
+	Node(Uuid),
+	/// The node and everything below it.
+	Subtree(Uuid),
+	/// Every node in the volume.
+	Volume(Uuid),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeReq {
+	pub target: WatchTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeRpl {
+	pub subscription: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsubscribeReq {
+	pub subscription: u64,
+}
+
+/// Asks the node to stop working on an earlier request of the same
+/// connection, which is then answered with a `cancelled` error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelReq {
+	/// Its `reqId`.
+	pub request: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelRpl {
+	pub request: u64,
+	/// False if the request was already answered (or never arrived).
+	pub cancelled: bool,
+}
+
+/// Several requests carried out in a single transaction: either all of them
+/// take effect, or none does.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchReq {
+	/// Run in order. They have no `reqId` of their own.
+	pub ops: Vec<DVRequest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchRpl {
+	/// False if an op failed and everything was rolled back.
+	pub committed: bool,
+	/// One reply per op that was run. When the batch was rolled back, the
+	/// last one is for the op that failed and the rest were never run.
+	pub results: Vec<DVReply>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeChange {
+	Created,
+	/// Also sent for moves.
+	Renamed,
+	/// The title or description changed.
+	Retitled,
+	Trashed,
+	Deleted,
+	ContentChanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeChangedNtc {
+	pub subscription: u64,
+	pub change: NodeChange,
+	pub node: Uuid,
+	pub volume: Uuid,
+}
+
+/// Some notices were dropped because the connection could not keep up, so
+/// anything cached from the subscribed nodes should be considered stale.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoticesLostNtc {
+	pub count: u64,
+}
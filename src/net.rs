@@ -0,0 +1,237 @@
+use crate::prelude::*;
+use crate::jwt::Encoding;
+use tokio_tungstenite::tungstenite::Message;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixStream;
+use tokio_rustls::rustls;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use std::io::BufReader;
+use futures_util::future;
+use std::time::Duration;
+
+/// Any byte stream a WebSocket can run on (plain TCP, TLS, ...).
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// Puts a token in a WebSocket frame: text for JWTs, binary for CBOR and
+/// COSE.
+pub fn token_to_frame(encoding: Encoding, token: Vec<u8>) -> Message {
+	match encoding {
+		Encoding::Cbor | Encoding::Cose => Message::Binary(token),
+		Encoding::Json | Encoding::Plain => match String::from_utf8(token) {
+			Ok(text) => Message::Text(text),
+			Err(err) => Message::Binary(err.into_bytes()),
+		},
+	}
+}
+
+/// What kind of frame `frame` is, for logging.
+pub fn frame_kind(frame: &Message) -> &'static str {
+	match frame {
+		Message::Text(_) => "text",
+		Message::Binary(_) => "binary",
+		Message::Ping(_) => "ping",
+		Message::Pong(_) => "pong",
+		Message::Close(_) => "close",
+		Message::Frame(_) => "raw",
+	}
+}
+
+/// The token carried by a frame, if it is a text or binary one.
+pub fn frame_to_token(frame: Message) -> Option<Vec<u8>> {
+	match frame {
+		Message::Text(text) => Some(text.into_bytes()),
+		Message::Binary(data) => Some(data),
+		_ => None,
+	}
+}
+
+/// Who is on the other end of a Unix socket (SO_PEERCRED).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+	pub uid: u32,
+	pub gid: u32,
+	pub pid: Option<i32>,
+}
+
+impl PeerCred {
+	pub fn of(stream: &UnixStream) -> DVResult<PeerCred> {
+		let cred = stream.peer_cred()?;
+		Ok(PeerCred{
+			uid: cred.uid(),
+			gid: cred.gid(),
+			pid: cred.pid(),
+		})
+	}
+}
+
+/// How both ends of a WebSocket connection notice that the other one is
+/// gone, and how long a request may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+	/// A ping goes out after this long without hearing from the peer.
+	pub ping_interval: Duration,
+	/// The peer is declared dead after this long without hearing from it
+	/// (pongs count).
+	pub idle_timeout: Duration,
+	/// How long a request may take, from sending it to getting its reply.
+	pub request_timeout: Duration,
+}
+
+impl Default for Timeouts {
+	fn default() -> Self {
+		Timeouts{
+			ping_interval: Duration::from_secs(DEFAULT_PING_INTERVAL_SECS),
+			idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS),
+			request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+		}
+	}
+}
+
+/// Tells servers to stop accepting connections and wind down, once it holds
+/// `true`.
+pub type ShutdownSignal = tokio::sync::watch::Receiver<bool>;
+
+/// A signal that never fires, for servers nobody will stop.
+pub fn no_shutdown() -> ShutdownSignal {
+	tokio::sync::watch::channel(false).1
+}
+
+/// Resolves once `shutdown` holds `true`. A signal whose sender is gone
+/// without saying so never resolves.
+pub async fn shutdown_requested(shutdown: &mut ShutdownSignal) {
+	loop {
+		if *shutdown.borrow_and_update() {
+			return;
+		}
+		if shutdown.changed().await.is_err() {
+			future::pending::<()>().await;
+		}
+	}
+}
+
+/// Reads every certificate in a PEM file.
+pub fn load_certs(path: &Path) -> DVResult<Vec<rustls::Certificate>> {
+	let mut reader = BufReader::new(std::fs::File::open(path)?);
+	let certs = rustls_pemfile::certs(&mut reader)?;
+	if certs.is_empty() {
+		return Err(DVError::TlsError(format!("no certificates in {:?}", path)));
+	}
+	Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+/// Reads the first private key (PKCS#8, RSA or EC) in a PEM file.
+pub fn load_private_key(path: &Path) -> DVResult<rustls::PrivateKey> {
+	let mut reader = BufReader::new(std::fs::File::open(path)?);
+	for item in rustls_pemfile::read_all(&mut reader)? {
+		match item {
+			rustls_pemfile::Item::PKCS8Key(key) |
+			rustls_pemfile::Item::RSAKey(key) |
+			rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+			_ => continue,
+		}
+	}
+	Err(DVError::TlsError(format!("no private key in {:?}", path)))
+}
+
+fn load_roots(paths: &[PathBuf]) -> DVResult<rustls::RootCertStore> {
+	let mut roots = rustls::RootCertStore::empty();
+	for path in paths {
+		for cert in load_certs(path)? {
+			if let Err(err) = roots.add(&cert) {
+				return Err(DVError::TlsError(format!("bad CA certificate in {:?}: {}", path, err)));
+			}
+		}
+	}
+	Ok(roots)
+}
+
+/// Where the full node finds its TLS certificate.
+#[derive(Debug, Clone)]
+pub struct TlsServerConfig {
+	pub cert_file: PathBuf,
+	pub key_file: PathBuf,
+	/// When set, clients must present a certificate signed by one of these CAs.
+	pub client_ca_file: Option<PathBuf>,
+}
+
+impl TlsServerConfig {
+	pub fn new(cert_file: &Path, key_file: &Path) -> TlsServerConfig {
+		TlsServerConfig{
+			cert_file: cert_file.to_path_buf(),
+			key_file: key_file.to_path_buf(),
+			client_ca_file: None,
+		}
+	}
+
+	pub fn with_client_ca(mut self, client_ca_file: &Path) -> TlsServerConfig {
+		self.client_ca_file = Some(client_ca_file.to_path_buf());
+		self
+	}
+
+	pub fn acceptor(&self) -> DVResult<TlsAcceptor> {
+		let certs = load_certs(&self.cert_file)?;
+		let key = load_private_key(&self.key_file)?;
+		let builder = rustls::ServerConfig::builder().with_safe_defaults();
+		let builder = match &self.client_ca_file {
+			Some(path) => {
+				let roots = load_roots(std::slice::from_ref(path))?;
+				builder.with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots))
+			},
+			None => builder.with_no_client_auth(),
+		};
+		let config = match builder.with_single_cert(certs, key) {
+			Ok(v) => v,
+			Err(err) => return Err(DVError::TlsError(err.to_string())),
+		};
+		Ok(TlsAcceptor::from(Arc::new(config)))
+	}
+}
+
+/// How `WSClient` checks servers (and presents itself) on `wss://` URLs.
+#[derive(Debug, Clone, Default)]
+pub struct TlsClientConfig {
+	/// Trusted CAs. If empty, the ones of the operating system are used.
+	pub ca_files: Vec<PathBuf>,
+	pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsClientConfig {
+	pub fn with_ca(mut self, ca_file: &Path) -> TlsClientConfig {
+		self.ca_files.push(ca_file.to_path_buf());
+		self
+	}
+
+	pub fn with_client_cert(mut self, cert_file: &Path, key_file: &Path) -> TlsClientConfig {
+		self.client_cert = Some((cert_file.to_path_buf(), key_file.to_path_buf()));
+		self
+	}
+
+	pub fn connector(&self) -> DVResult<TlsConnector> {
+		let roots = match self.ca_files.is_empty() {
+			true => {
+				let mut roots = rustls::RootCertStore::empty();
+				for cert in rustls_native_certs::load_native_certs()? {
+					// Some systems ship certificates webpki can't parse
+					let _ = roots.add(&rustls::Certificate(cert.0));
+				}
+				roots
+			},
+			false => load_roots(&self.ca_files)?,
+		};
+		let builder = rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(roots);
+		let config = match &self.client_cert {
+			Some((cert_file, key_file)) => match builder.with_single_cert(load_certs(cert_file)?, load_private_key(key_file)?) {
+				Ok(v) => v,
+				Err(err) => return Err(DVError::TlsError(err.to_string())),
+			},
+			None => builder.with_no_client_auth(),
+		};
+		Ok(TlsConnector::from(Arc::new(config)))
+	}
+}
@@ -0,0 +1,951 @@
+use crate::prelude::*;
+use crate::messages::*;
+use crate::dispatcher::CancelToken;
+use crate::paging::{self, Cursor};
+use crate::streams;
+use crate::utils::check_filename;
+use crate::volumes::get_volume_root;
+use rusqlite::OptionalExtension;
+use serde_bytes::ByteBuf;
+
+const NODE_COLUMNS: &str = "`node_uuid`, `parent_uuid`, `filename`, `title`, `description`, \
+	`file_kind`, `copy_on_write`, `contents`, `unix_mode`, `unix_uid`, `unix_gid`, \
+	`created_at`, `changed_at`, `volume_uuid`, `trashed_by`, `trashed_when`";
+
+impl FileKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			FileKind::Empty => "empty",
+			FileKind::Regular => "regular",
+			FileKind::SymbolicLink => "symbolic-link",
+			FileKind::HardLink => "hard-link",
+			FileKind::Socket => "socket",
+		}
+	}
+
+}
+
+impl std::str::FromStr for FileKind {
+	type Err = DVError;
+
+	fn from_str(val: &str) -> DVResult<FileKind> {
+		match val {
+			"empty" => Ok(FileKind::Empty),
+			"regular" => Ok(FileKind::Regular),
+			"symbolic-link" => Ok(FileKind::SymbolicLink),
+			"hard-link" => Ok(FileKind::HardLink),
+			"socket" => Ok(FileKind::Socket),
+			_ => Err(DVError::InvalidArgument(format!("kind {:?}: unknown file kind", val))),
+		}
+	}
+}
+
+fn row_to_node(row: &rusqlite::Row) -> SQLResult<NodeInfo> {
+	let uuid: Uuid = row.get(0)?;
+	let parent: Uuid = row.get(1)?;
+	let file_kind: String = row.get(5)?;
+	let file_kind = match file_kind.parse::<FileKind>() {
+		Ok(v) => v,
+		Err(_) => return Err(SQLError::InvalidColumnType(5, file_kind, rusqlite::types::Type::Text)),
+	};
+	let stream: Option<Uuid> = row.get(7)?;
+	let unix_mode: Option<u16> = row.get(8)?;
+	let unix_uid: Option<u16> = row.get(9)?;
+	let unix_gid: Option<u16> = row.get(10)?;
+	let unix_perm = match (unix_mode, unix_uid, unix_gid) {
+		(Some(mode), Some(uid), Some(gid)) => Some(UnixPerm{mode, uid, gid}),
+		_ => None,
+	};
+	let trashed_when: Option<DateTime<Utc>> = row.get(15)?;
+	Ok(NodeInfo{
+		uuid,
+		name: row.get(2)?,
+		title: row.get(3)?,
+		description: row.get(4)?,
+		// Roots are their own parents
+		parents: if parent == uuid { vec![] } else { vec![parent] },
+		content: ContentRef{
+			file_kind,
+			copy_on_write: row.get(6)?,
+			stream: stream.unwrap_or_else(Uuid::nil),
+		},
+		thumbnail: None,
+		unix_perm,
+		xattrs: HashMap::new(),
+		created: row.get(11)?,
+		changed: row.get(12)?,
+		volume: row.get(13)?,
+		in_trash: trashed_when.is_some(),
+		trashed_by: row.get(14)?,
+		trashed_when,
+	})
+}
+
+pub fn get_xattrs(conn: &SQLConnection, node_uuid: Uuid) -> DVResult<HashMap<String, XattrVal>> {
+	let mut stmt = conn.prepare_cached("SELECT `name`, `format`, `value` FROM `xattr` WHERE `node_uuid` = ?1")?;
+	let rows = stmt.query_map(params![node_uuid], |row| {
+		Ok((row.get(0)?, XattrVal{
+			format: row.get(1)?,
+			value: row.get(2)?,
+		}))
+	})?;
+	let mut ans = HashMap::new();
+	for row in rows {
+		let (name, val) = row?;
+		ans.insert(name, val);
+	}
+	Ok(ans)
+}
+
+pub fn get_node(conn: &SQLConnection, node_uuid: Uuid) -> DVResult<Option<NodeInfo>> {
+	let node = conn.query_row(
+		&format!("SELECT {} FROM `filenode` WHERE `node_uuid` = ?1", NODE_COLUMNS),
+		params![node_uuid],
+		row_to_node,
+	).optional()?;
+	match node {
+		Some(mut node) => {
+			node.xattrs = get_xattrs(conn, node_uuid)?;
+			Ok(Some(node))
+		},
+		None => Ok(None),
+	}
+}
+
+/// One page of the nodes right below `parent` (super hidden ones left
+/// out), and the token for the next one if there is more.
+pub fn list_children(conn: &SQLConnection, parent: Uuid, page: &PageReq) -> DVResult<(Vec<NodeInfo>, Option<String>)> {
+	let trace_msg = format!("{}(parent={}, order_by={:?}, cursor={})", function!(), parent, page.order_by, page.cursor.is_some());
+	trace!("+{}", trace_msg);
+	let key = match page.order_by {
+		ListOrder::Name => "`filename`",
+		ListOrder::ChangedAt => "`changed_at`",
+		ListOrder::CreatedAt => "`created_at`",
+	};
+	let cursor = Cursor::from_page(page, parent)?;
+	let limit = page.limit();
+	let after = match cursor {
+		Some(_) => format!("AND ({}, `node_uuid`) > (?3, ?4)", key),
+		None => String::new(),
+	};
+	let mut stmt = conn.prepare(&format!(
+		"SELECT {}, {} FROM `filenode` \
+			WHERE `parent_uuid` = ?1 AND `node_uuid` != `parent_uuid` AND `super_hidden` = 0 {} \
+			ORDER BY {}, `node_uuid` LIMIT ?2",
+		NODE_COLUMNS, key, after, key))?;
+	// Only one page is ever read from the database
+	let to_row = |row: &rusqlite::Row| Ok((row_to_node(row)?, row.get(16)?, row.get(0)?));
+	let rows = match &cursor {
+		Some(cursor) => stmt.query_map(params![parent, limit + 1, cursor.key(), cursor.uuid()], to_row)?,
+		None => stmt.query_map(params![parent, limit + 1], to_row)?,
+	};
+	let mut ans = Vec::new();
+	for row in rows {
+		ans.push(row?);
+	}
+	let (mut children, next) = paging::finish_page(ans, limit, parent, page.order_by)?;
+	for node in children.iter_mut() {
+		node.xattrs = get_xattrs(conn, node.uuid)?;
+	}
+	trace!("-{} -> Ok({} children, more: {})", trace_msg, children.len(), next.is_some());
+	Ok((children, next))
+}
+
+/// Finds the child of `parent` called `name`.
+pub fn get_child(conn: &SQLConnection, parent: Uuid, name: &str) -> DVResult<Option<Uuid>> {
+	let mut stmt = conn.prepare_cached(
+		"SELECT `node_uuid` FROM `filenode` WHERE `parent_uuid` = ?1 AND `filename` = ?2 AND `node_uuid` != `parent_uuid`")?;
+	Ok(stmt.query_row(params![parent, name], |row| row.get(0)).optional()?)
+}
+
+/// Turns a path like `a/b/c` (relative to the volume root, a leading `/` is
+/// optional) into the UUID of the node it points to.
+pub fn resolve_path(conn: &SQLConnection, volume: Uuid, path: &str) -> DVResult<Option<Uuid>> {
+	let trace_msg = format!("{}(volume={}, path={:?})", function!(), volume, path);
+	trace!("+{}", trace_msg);
+	let mut current = match get_volume_root(conn, volume)? {
+		Some(v) => v,
+		None => {
+			trace!("-{} -> no such volume", trace_msg);
+			return Err(DVError::NotFound(format!("volume {}", volume)));
+		}
+	};
+	for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+		current = match get_child(conn, current, name)? {
+			Some(v) => v,
+			None => {
+				trace!("-{} -> None (stopped at {:?})", trace_msg, name);
+				return Ok(None);
+			}
+		};
+	}
+	trace!("-{} -> Ok({})", trace_msg, current);
+	Ok(Some(current))
+}
+
+/// One UUID (or why there is none) per item, and the UUID of each path that
+/// could be resolved.
+pub type Resolved = (Vec<DVResult<Uuid>>, HashMap<String, Uuid>);
+
+/// Resolves a mix of UUIDs and paths. The second value maps each path that
+/// could be resolved to its UUID.
+pub fn resolve_nodes_or_paths(conn: &SQLConnection, items: &[NodeOrPath], volume: Option<Uuid>) -> DVResult<Resolved> {
+	let mut ans = Vec::with_capacity(items.len());
+	let mut paths2uuid = HashMap::new();
+	for item in items {
+		let res = match item {
+			NodeOrPath::Node(uuid) => Ok(*uuid),
+			NodeOrPath::Path(path) => match volume {
+				None => Err(DVError::VolumeRequired(path.clone())),
+				Some(volume) => match resolve_path(conn, volume, path)? {
+					Some(uuid) => {
+						paths2uuid.insert(path.clone(), uuid);
+						Ok(uuid)
+					},
+					None => Err(DVError::NotFound(path.clone())),
+				},
+			},
+		};
+		ans.push(res);
+	}
+	Ok((ans, paths2uuid))
+}
+
+/// Looks up a single node, which must belong to `volume` if one is given.
+pub fn resolve_node(conn: &SQLConnection, item: &NodeOrPath, volume: Option<Uuid>) -> DVResult<NodeInfo> {
+	let (uuids, _) = resolve_nodes_or_paths(conn, std::slice::from_ref(item), volume)?;
+	let uuid = match uuids.into_iter().next() {
+		Some(uuid) => uuid?,
+		None => return Err(DVError::NotFound(format!("{:?}", item))),
+	};
+	match get_node(conn, uuid)? {
+		Some(node) if volume.is_none() || volume == Some(node.volume) => Ok(node),
+		_ => Err(DVError::NotFound(uuid.to_string())),
+	}
+}
+
+/// Every node above `node_uuid`, nearest first.
+pub fn get_ancestors(conn: &SQLConnection, node_uuid: Uuid) -> DVResult<Vec<Uuid>> {
+	let mut ans = Vec::new();
+	let mut current = node_uuid;
+	loop {
+		let parent: Option<Uuid> = conn.query_row(
+			"SELECT `parent_uuid` FROM `filenode` WHERE `node_uuid` = ?1",
+			params![current],
+			|row| row.get(0),
+		).optional()?;
+		match parent {
+			// Roots are their own parents, and a broken tree must not hang us
+			Some(parent) if parent != current && !ans.contains(&parent) => {
+				ans.push(parent);
+				current = parent;
+			},
+			_ => return Ok(ans),
+		}
+	}
+}
+
+/// Makes `stream_uuid` the content of a node, and of every hard link
+/// sharing its old content, deleting the stream it had before unless
+/// copy-on-write clones still use it. Empty nodes become regular files.
+pub fn set_node_stream(conn: &SQLConnection, node_uuid: Uuid, stream_uuid: Uuid) -> DVResult<()> {
+	let (old, copy_on_write): (Option<Uuid>, bool) = conn.query_row(
+		"SELECT `contents`, `copy_on_write` FROM `filenode` WHERE `node_uuid` = ?1",
+		params![node_uuid],
+		|row| Ok((row.get(0)?, row.get(1)?)),
+	)?;
+	// A clone has no hard links, while the clones of a node must keep the
+	// old content
+	let links = match copy_on_write {
+		true => None,
+		false => old,
+	};
+	conn.execute(
+		"UPDATE `filenode` SET `contents` = ?2, `changed_at` = ?3, `copy_on_write` = 0, \
+			`file_kind` = CASE `file_kind` WHEN 'empty' THEN 'regular' ELSE `file_kind` END \
+			WHERE `node_uuid` = ?1 OR (`contents` = ?4 AND `copy_on_write` = 0)",
+		params![node_uuid, stream_uuid, Utc::now(), links],
+	)?;
+	if let Some(old) = old {
+		if old != stream_uuid {
+			release_stream(conn, old)?;
+		}
+	}
+	Ok(())
+}
+
+/// Deletes a stream once no node or overlay uses it, and then its base if
+/// nothing else needs that either. A base left with a single overlay and no
+/// node takes the blocks of that overlay back, so chains of overlays only
+/// last while both sides of a clone do.
+fn release_stream(conn: &SQLConnection, stream_uuid: Uuid) -> DVResult<()> {
+	let mut current = stream_uuid;
+	loop {
+		let users: i64 = conn.query_row(
+			"SELECT COUNT(*) FROM `filenode` WHERE `contents` = ?1",
+			params![current],
+			|row| row.get(0),
+		)?;
+		if users > 0 {
+			return Ok(());
+		}
+		let overlays = streams::overlays_of(conn, current)?;
+		match overlays[..] {
+			[] => {
+				let base = streams::base_of(conn, current)?;
+				streams::delete_stream(conn, current)?;
+				match base {
+					Some(base) => current = base,
+					None => return Ok(()),
+				}
+			},
+			[overlay] => {
+				streams::merge_overlay(conn, overlay)?;
+				conn.execute("UPDATE `filenode` SET `contents` = ?2 WHERE `contents` = ?1", params![overlay, current])?;
+				return Ok(());
+			},
+			_ => return Ok(()),
+		}
+	}
+}
+
+/// Makes sure changing the content of `node` (which is `stream`) won't
+/// change any copy-on-write clone, and returns the stream to write to.
+///
+/// A stream shared with a clone, or that is the base of an overlay, must not
+/// change anymore: `node` gets an overlay of it instead, along with its hard
+/// links. An overlay only holds the blocks written to it, so this takes the
+/// same time whatever the size of the stream; what it costs instead is one
+/// more lookup per block read for each generation of clones.
+fn unshare_stream(conn: &SQLConnection, node: &NodeInfo, stream: &streams::StreamInfo) -> DVResult<streams::StreamInfo> {
+	let (others, clones): (i64, i64) = conn.query_row(
+		"SELECT COUNT(*), COALESCE(SUM(`copy_on_write` != 0), 0) FROM `filenode` WHERE `contents` = ?1 AND `node_uuid` != ?2",
+		params![stream.uuid, node.uuid],
+		|row| Ok((row.get(0)?, row.get(1)?)),
+	)?;
+	let frozen = !streams::overlays_of(conn, stream.uuid)?.is_empty();
+	if node.content.copy_on_write {
+		if others == 0 && !frozen {
+			conn.execute("UPDATE `filenode` SET `copy_on_write` = 0 WHERE `node_uuid` = ?1", params![node.uuid])?;
+			return Ok(stream.clone());
+		}
+		let overlay = streams::overlay_stream(conn, stream)?;
+		conn.execute(
+			"UPDATE `filenode` SET `contents` = ?2, `copy_on_write` = 0 WHERE `node_uuid` = ?1",
+			params![node.uuid, overlay.uuid],
+		)?;
+		return Ok(overlay);
+	}
+	if clones == 0 && !frozen {
+		return Ok(stream.clone());
+	}
+	// The clones stay on `stream`, which they no longer share with a writer
+	let overlay = streams::overlay_stream(conn, stream)?;
+	conn.execute(
+		"UPDATE `filenode` SET `contents` = ?2 WHERE `contents` = ?1 AND `copy_on_write` = 0",
+		params![stream.uuid, overlay.uuid],
+	)?;
+	Ok(overlay)
+}
+
+pub fn get_nodes_info(conn: &SQLConnection, req: &NodeInfoReq) -> DVResult<NodeInfoRpl> {
+	let (uuids, paths2uuid) = resolve_nodes_or_paths(conn, &req.nodes_or_paths, req.volume)?;
+	let mut nodes = Vec::with_capacity(uuids.len());
+	for uuid in uuids {
+		let item = match uuid {
+			Ok(uuid) => match get_node(conn, uuid)? {
+				Some(node) if req.volume.is_none() || req.volume == Some(node.volume) => NodeInfoOrError::Node(Box::new(node)),
+				_ => NodeInfoOrError::Error(ErrorInfo::from(&DVError::NotFound(uuid.to_string()))),
+			},
+			Err(err) => NodeInfoOrError::Error(ErrorInfo::from(&err)),
+		};
+		nodes.push(item);
+	}
+	Ok(NodeInfoRpl{
+		nodes,
+		paths2uuid,
+	})
+}
+
+/// Digests of the content of each node. Nodes that could not be found are
+/// left out of the reply.
+pub fn get_nodes_hash(conn: &SQLConnection, req: &StreamHashReq, cancel: &CancelToken) -> DVResult<StreamHashRpl> {
+	let (uuids, paths2uuid) = resolve_nodes_or_paths(conn, &req.nodes_or_paths, req.volume)?;
+	let mut values = HashMap::new();
+	for uuid in uuids.into_iter().flatten() {
+		let node = match get_node(conn, uuid)? {
+			Some(node) if req.volume.is_none() || req.volume == Some(node.volume) => node,
+			_ => continue,
+		};
+		let value = if node.content.stream.is_nil() {
+			req.alg.digest(&[])
+		} else {
+			streams::stream_hash(conn, node.content.stream, req.alg, cancel)?
+		};
+		values.insert(uuid, ByteBuf::from(value));
+	}
+	Ok(StreamHashRpl{
+		alg: req.alg,
+		values,
+		paths2uuid,
+	})
+}
+
+/// Fails with `DVError::Conflict` if `parent` already has a child called
+/// `name` (other than `except`).
+fn check_name_free(conn: &SQLConnection, parent: Uuid, name: &str, except: Option<Uuid>) -> DVResult<()> {
+	match get_child(conn, parent, name)? {
+		Some(other) if Some(other) != except => Err(DVError::Conflict(format!("{:?} already exists", name))),
+		_ => Ok(()),
+	}
+}
+
+fn get_existing_node(conn: &SQLConnection, node_uuid: Uuid) -> DVResult<NodeInfo> {
+	match get_node(conn, node_uuid)? {
+		Some(v) => Ok(v),
+		None => Err(DVError::NotFound(node_uuid.to_string())),
+	}
+}
+
+/// Entries changing count as a change of their parent.
+fn touch_node(conn: &SQLConnection, node_uuid: Uuid, now: DateTime<Utc>) -> DVResult<()> {
+	conn.execute("UPDATE `filenode` SET `changed_at` = ?2 WHERE `node_uuid` = ?1", params![node_uuid, now])?;
+	Ok(())
+}
+
+/// The content a new node of `req.file_kind` starts with.
+fn initial_stream(conn: &SQLConnection, req: &CreateNodeReq, parent: &NodeInfo) -> DVResult<Option<Uuid>> {
+	match (req.file_kind, &req.target, &req.link_to) {
+		(FileKind::SymbolicLink, Some(target), None) => {
+			if target.is_empty() || target.len() > crate::utils::MAX_FILENAME_LEN {
+				return Err(DVError::InvalidName(format!("bad link target {:?}", target)));
+			}
+			let stream_uuid = streams::create_stream(conn)?;
+			let stream = match streams::get_stream(conn, stream_uuid)? {
+				Some(v) => v,
+				None => return Err(DVError::NotFound(format!("stream {}", stream_uuid))),
+			};
+			streams::write_at(conn, &stream, 0, target.as_bytes())?;
+			Ok(Some(stream_uuid))
+		},
+		(FileKind::HardLink, None, Some(link_to)) => {
+			let other = resolve_node(conn, link_to, Some(parent.volume))?;
+			if other.parents.is_empty() {
+				return Err(DVError::Conflict("can't link to the root of a volume".to_string()));
+			}
+			if !other.content.stream.is_nil() {
+				// Clones have no hard links: it must own its content first
+				let stream = match streams::get_stream(conn, other.content.stream)? {
+					Some(v) => v,
+					None => return Err(DVError::NotFound(format!("stream {}", other.content.stream))),
+				};
+				return Ok(Some(unshare_stream(conn, &other, &stream)?.uuid));
+			}
+			// Both ends need something to share
+			let stream_uuid = streams::create_stream(conn)?;
+			set_node_stream(conn, other.uuid, stream_uuid)?;
+			Ok(Some(stream_uuid))
+		},
+		(FileKind::Empty, None, None)
+		| (FileKind::Regular, None, None)
+		| (FileKind::Socket, None, None) => Ok(None),
+		(FileKind::SymbolicLink, _, _) => Err(DVError::Conflict("a symbolic link needs a target (and nothing to link to)".to_string())),
+		(FileKind::HardLink, _, _) => Err(DVError::Conflict("a hard link needs a node to link to (and no target)".to_string())),
+		(kind, _, _) => Err(DVError::Conflict(format!("{} nodes take no target", kind.as_str()))),
+	}
+}
+
+/// Creates a node as asked by `req`.
+pub fn create_node(conn: &SQLConnection, req: &CreateNodeReq) -> DVResult<NodeInfo> {
+	let trace_msg = format!("{}(parent={:?}, name={:?}, file_kind={:?})", function!(), req.parent, req.name, req.file_kind);
+	trace!("+{}", trace_msg);
+	check_filename(&req.name)?;
+	let parent = resolve_node(conn, &req.parent, req.volume)?;
+	check_name_free(conn, parent.uuid, &req.name, None)?;
+	let now = Utc::now();
+	let node_uuid = match req.uuid {
+		Some(uuid) if !uuid.is_nil() => {
+			if get_node(conn, uuid)?.is_some() {
+				return Err(DVError::Conflict(format!("node {} already exists", uuid)));
+			}
+			uuid
+		},
+		_ => new_uuid_at(now),
+	};
+	let stream_uuid = initial_stream(conn, req, &parent)?;
+	let unix_perm = req.unix_perm.as_ref();
+	conn.execute(
+		"INSERT INTO `filenode` (`node_uuid`, `parent_uuid`, `filename`, `contents`, `super_hidden`, `changed_at`, `created_at`, \
+			`volume_uuid`, `title`, `description`, `file_kind`, `unix_mode`, `unix_uid`, `unix_gid`) \
+			VALUES (?1, ?2, ?3, ?4, 0, ?5, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+		params![node_uuid, parent.uuid, req.name, stream_uuid, now, parent.volume, req.title, req.description, req.file_kind.as_str(),
+			unix_perm.map(|perm| perm.mode), unix_perm.map(|perm| perm.uid), unix_perm.map(|perm| perm.gid)],
+	)?;
+	touch_node(conn, parent.uuid, now)?;
+	let node = get_existing_node(conn, node_uuid)?;
+	debug!("Created {} node {:?} ({}) in {}", req.file_kind.as_str(), req.name, node_uuid, parent.uuid);
+	trace!("-{} -> Ok({})", trace_msg, node_uuid);
+	Ok(node)
+}
+
+/// Changes whichever of the name, title and description of a node are given.
+pub fn rename_node(conn: &SQLConnection, node_uuid: Uuid, name: Option<&str>, title: Option<&str>, description: Option<&str>) -> DVResult<NodeInfo> {
+	let trace_msg = format!("{}(node_uuid={}, name={:?}, title={:?})", function!(), node_uuid, name, title);
+	trace!("+{}", trace_msg);
+	let node = get_existing_node(conn, node_uuid)?;
+	let now = Utc::now();
+	if let Some(name) = name {
+		let parent = match node.parents.first() {
+			Some(v) => *v,
+			None => return Err(DVError::Conflict("the root of a volume has no name".to_string())),
+		};
+		check_filename(name)?;
+		check_name_free(conn, parent, name, Some(node_uuid))?;
+		touch_node(conn, parent, now)?;
+	}
+	conn.execute(
+		"UPDATE `filenode` SET `filename` = COALESCE(?2, `filename`), `title` = COALESCE(?3, `title`), \
+			`description` = COALESCE(?4, `description`), `changed_at` = ?5 WHERE `node_uuid` = ?1",
+		params![node_uuid, name, title, description, now],
+	)?;
+	trace!("-{} -> Ok", trace_msg);
+	get_existing_node(conn, node_uuid)
+}
+
+/// Moves a node below `parent_uuid`, renaming it to `name` if given. Also
+/// returns the ancestors it had before.
+pub fn move_node(conn: &SQLConnection, node_uuid: Uuid, parent_uuid: Uuid, name: Option<&str>) -> DVResult<(NodeInfo, Vec<Uuid>)> {
+	let trace_msg = format!("{}(node_uuid={}, parent_uuid={}, name={:?})", function!(), node_uuid, parent_uuid, name);
+	trace!("+{}", trace_msg);
+	let node = get_existing_node(conn, node_uuid)?;
+	let parent = get_existing_node(conn, parent_uuid)?;
+	let old_parent = match node.parents.first() {
+		Some(v) => *v,
+		None => return Err(DVError::Conflict("the root of a volume can't be moved".to_string())),
+	};
+	if parent.volume != node.volume {
+		return Err(DVError::Conflict("nodes can't be moved to another volume".to_string()));
+	}
+	let new_ancestors = get_ancestors(conn, parent_uuid)?;
+	if parent_uuid == node_uuid || new_ancestors.contains(&node_uuid) {
+		return Err(DVError::Conflict(format!("{:?} can't be moved below itself", node.name)));
+	}
+	let name = name.unwrap_or(&node.name);
+	check_filename(name)?;
+	check_name_free(conn, parent_uuid, name, Some(node_uuid))?;
+	let old_ancestors = get_ancestors(conn, node_uuid)?;
+	let now = Utc::now();
+	conn.execute(
+		"UPDATE `filenode` SET `parent_uuid` = ?2, `filename` = ?3, `changed_at` = ?4 WHERE `node_uuid` = ?1",
+		params![node_uuid, parent_uuid, name, now],
+	)?;
+	touch_node(conn, old_parent, now)?;
+	touch_node(conn, parent_uuid, now)?;
+	debug!("Moved node {} from {} to {} as {:?}", node_uuid, old_parent, parent_uuid, name);
+	trace!("-{} -> Ok", trace_msg);
+	Ok((get_existing_node(conn, node_uuid)?, old_ancestors))
+}
+
+/// Deletes a node along with its extended attributes, pending uploads and
+/// content (unless a hard link or a clone still uses it).
+pub(crate) fn delete_one_node(conn: &SQLConnection, node_uuid: Uuid) -> DVResult<()> {
+	let stream: Option<Uuid> = conn.query_row(
+		"SELECT `contents` FROM `filenode` WHERE `node_uuid` = ?1",
+		params![node_uuid],
+		|row| row.get(0),
+	)?;
+	let uploads = {
+		let mut stmt = conn.prepare_cached("SELECT `upload_uuid` FROM `upload` WHERE `node_uuid` = ?1")?;
+		let rows = stmt.query_map(params![node_uuid], |row| row.get::<_, Uuid>(0))?;
+		rows.collect::<SQLResult<Vec<_>>>()?
+	};
+	for upload in uploads {
+		streams::end_upload(conn, upload, false)?;
+	}
+	conn.execute("DELETE FROM `xattr` WHERE `node_uuid` = ?1", params![node_uuid])?;
+	conn.execute("DELETE FROM `filenode` WHERE `node_uuid` = ?1", params![node_uuid])?;
+	if let Some(stream) = stream {
+		release_stream(conn, stream)?;
+	}
+	Ok(())
+}
+
+/// Deletes a node, which must have no children unless `recursive` is set.
+/// Returns every node that was deleted along with its ancestors (as they
+/// were), the node itself first.
+pub fn delete_node(conn: &SQLConnection, node_uuid: Uuid, recursive: bool) -> DVResult<Vec<(Uuid, Vec<Uuid>)>> {
+	let trace_msg = format!("{}(node_uuid={}, recursive={})", function!(), node_uuid, recursive);
+	trace!("+{}", trace_msg);
+	let node = get_existing_node(conn, node_uuid)?;
+	let parent = match node.parents.first() {
+		Some(v) => *v,
+		None => return Err(DVError::Conflict("the root of a volume can only go with the volume".to_string())),
+	};
+	let mut deleted = vec![(node_uuid, get_ancestors(conn, node_uuid)?)];
+	// Breadth first, each node is followed by its children
+	let mut next = 0;
+	while next < deleted.len() {
+		let (current, ancestors) = deleted[next].clone();
+		let children = {
+			let mut stmt = conn.prepare_cached(
+				"SELECT `node_uuid` FROM `filenode` WHERE `parent_uuid` = ?1 AND `node_uuid` != `parent_uuid`")?;
+			let rows = stmt.query_map(params![current], |row| row.get::<_, Uuid>(0))?;
+			rows.collect::<SQLResult<Vec<_>>>()?
+		};
+		if !children.is_empty() && !recursive {
+			return Err(DVError::DirNotClear(PathBuf::from(&node.name)));
+		}
+		for child in children {
+			let mut child_ancestors = Vec::with_capacity(ancestors.len() + 1);
+			child_ancestors.push(current);
+			child_ancestors.extend_from_slice(&ancestors);
+			deleted.push((child, child_ancestors));
+		}
+		next += 1;
+	}
+	for (uuid, _) in deleted.iter() {
+		delete_one_node(conn, *uuid)?;
+	}
+	touch_node(conn, parent, Utc::now())?;
+	info!("Deleted node {:?} ({}) and {} below it", node.name, node_uuid, deleted.len() - 1);
+	trace!("-{} -> Ok({})", trace_msg, deleted.len());
+	Ok(deleted)
+}
+
+/// The stream holding the content of `node`, once checked against what the
+/// client expects it to be (`DVError::Conflict` otherwise). Nodes without
+/// content get an empty stream.
+pub fn writable_stream(conn: &SQLConnection, node: &NodeInfo, expect: &ExpectedContent, cancel: &CancelToken) -> DVResult<streams::StreamInfo> {
+	let current = match node.content.stream.is_nil() {
+		true => None,
+		false => streams::get_stream(conn, node.content.stream)?,
+	};
+	if let Some(expected) = expect.expected_version {
+		let version = current.as_ref().map(|stream| stream.version).unwrap_or(0);
+		if version != expected {
+			return Err(DVError::Conflict(format!("node {} is at version {}, not {}", node.uuid, version, expected)));
+		}
+	}
+	if let Some(expected) = &expect.expected_hash {
+		let value = match &current {
+			Some(stream) => streams::stream_hash(conn, stream.uuid, expected.alg, cancel)?,
+			None => expected.alg.digest(&[]),
+		};
+		if value != expected.value {
+			return Err(DVError::Conflict(format!("content of node {} does not have the expected {} digest", node.uuid, expected.alg.as_str())));
+		}
+	}
+	if let Some(stream) = current {
+		return unshare_stream(conn, node, &stream);
+	}
+	let stream_uuid = streams::create_stream(conn)?;
+	set_node_stream(conn, node.uuid, stream_uuid)?;
+	match streams::get_stream(conn, stream_uuid)? {
+		Some(stream) => Ok(stream),
+		None => Err(DVError::NotFound(format!("stream {}", stream_uuid))),
+	}
+}
+
+/// Updates the `changed` time of every node showing the content of
+/// `stream_uuid` (hard links share it).
+pub fn touch_stream_nodes(conn: &SQLConnection, stream_uuid: Uuid) -> DVResult<()> {
+	conn.execute("UPDATE `filenode` SET `changed_at` = ?2 WHERE `contents` = ?1", params![stream_uuid, Utc::now()])?;
+	Ok(())
+}
+
+/// Copies a node below `parent_uuid`, under `name` if given, and with
+/// `recursive` everything below it too. Copies share their content with the
+/// originals until either side changes it (see `unshare_stream`), so even
+/// large files are cloned at once. Returns every new node along with its
+/// ancestors, the copy of `node_uuid` first.
+pub fn clone_node(conn: &SQLConnection, node_uuid: Uuid, parent_uuid: Uuid, name: Option<&str>, recursive: bool) -> DVResult<Vec<(Uuid, Vec<Uuid>)>> {
+	let trace_msg = format!("{}(node_uuid={}, parent_uuid={}, name={:?}, recursive={})", function!(), node_uuid, parent_uuid, name, recursive);
+	trace!("+{}", trace_msg);
+	let node = get_existing_node(conn, node_uuid)?;
+	let parent = get_existing_node(conn, parent_uuid)?;
+	if node.parents.is_empty() {
+		return Err(DVError::Conflict("the root of a volume can't be cloned".to_string()));
+	}
+	if parent.volume != node.volume {
+		return Err(DVError::Conflict("nodes can't be cloned to another volume".to_string()));
+	}
+	let name = name.unwrap_or(&node.name);
+	check_filename(name)?;
+	check_name_free(conn, parent_uuid, name, None)?;
+
+	// Every node to copy along with the index of its parent, listed before
+	// anything is added so cloning a node into itself can't go on forever
+	let mut sources: Vec<(Uuid, usize)> = vec![(node_uuid, 0)];
+	let mut next = 0;
+	while recursive && next < sources.len() {
+		let mut stmt = conn.prepare_cached(
+			"SELECT `node_uuid` FROM `filenode` WHERE `parent_uuid` = ?1 AND `node_uuid` != `parent_uuid`")?;
+		let rows = stmt.query_map(params![sources[next].0], |row| row.get::<_, Uuid>(0))?;
+		for child in rows {
+			sources.push((child?, next));
+		}
+		next += 1;
+	}
+
+	let now = Utc::now();
+	let mut ancestors = vec![parent_uuid];
+	ancestors.extend(get_ancestors(conn, parent_uuid)?);
+	let mut cloned: Vec<(Uuid, Vec<Uuid>)> = Vec::with_capacity(sources.len());
+	for (index, (source, source_parent)) in sources.iter().enumerate() {
+		// The clock sequence of a single timestamp would run out on big trees
+		let clone_uuid = new_uuid_at(Utc::now());
+		let (clone_parent, clone_name, clone_ancestors) = match index {
+			0 => (parent_uuid, Some(name), ancestors.clone()),
+			_ => {
+				let (above, above_ancestors) = &cloned[*source_parent];
+				let mut clone_ancestors = Vec::with_capacity(above_ancestors.len() + 1);
+				clone_ancestors.push(*above);
+				clone_ancestors.extend_from_slice(above_ancestors);
+				(*above, None, clone_ancestors)
+			},
+		};
+		// A copy of a hard link is just a file with the same content
+		conn.execute(
+			"INSERT INTO `filenode` (`node_uuid`, `parent_uuid`, `filename`, `contents`, `super_hidden`, `changed_at`, `created_at`, \
+				`volume_uuid`, `title`, `description`, `file_kind`, `copy_on_write`, `unix_mode`, `unix_uid`, `unix_gid`) \
+				SELECT ?2, ?3, COALESCE(?4, `filename`), `contents`, `super_hidden`, ?5, ?5, \
+				`volume_uuid`, `title`, `description`, CASE `file_kind` WHEN 'hard-link' THEN 'regular' ELSE `file_kind` END, \
+				`contents` IS NOT NULL, `unix_mode`, `unix_uid`, `unix_gid` \
+				FROM `filenode` WHERE `node_uuid` = ?1",
+			params![source, clone_uuid, clone_parent, clone_name, now],
+		)?;
+		conn.execute(
+			"INSERT INTO `xattr` (`node_uuid`, `name`, `format`, `value`) \
+				SELECT ?2, `name`, `format`, `value` FROM `xattr` WHERE `node_uuid` = ?1",
+			params![source, clone_uuid],
+		)?;
+		cloned.push((clone_uuid, clone_ancestors));
+	}
+	touch_node(conn, parent_uuid, now)?;
+	info!("Cloned node {:?} ({}) into {} as {} ({} nodes)", node.name, node_uuid, parent_uuid, cloned[0].0, cloned.len());
+	trace!("-{} -> Ok({})", trace_msg, cloned.len());
+	Ok(cloned)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::schema::open_test_database;
+	use crate::streams::STREAM_BLOCK_SIZE;
+	use crate::volumes;
+
+	fn new_volume(conn: &SQLConnection) -> Uuid {
+		let volume = VolumeInfo{
+			uuid: Uuid::nil(),
+			title: "Test".to_string(),
+			name: "test".to_string(),
+			is_real: true,
+			uid2name: HashMap::new(),
+			gid2name: HashMap::new(),
+		};
+		let volume = volumes::create_volume(conn, &volume).unwrap().1.unwrap();
+		volumes::get_volume_root(conn, volume.uuid).unwrap().unwrap()
+	}
+
+	fn new_node(conn: &SQLConnection, parent: Uuid, name: &str, link_to: Option<Uuid>) -> Uuid {
+		let req = CreateNodeReq{
+			parent: NodeOrPath::Node(parent),
+			volume: None,
+			name: name.to_string(),
+			uuid: None,
+			file_kind: match link_to {
+				Some(_) => FileKind::HardLink,
+				None => FileKind::Empty,
+			},
+			title: String::new(),
+			description: String::new(),
+			unix_perm: None,
+			target: None,
+			link_to: link_to.map(NodeOrPath::Node),
+		};
+		create_node(conn, &req).unwrap().uuid
+	}
+
+	fn write(conn: &SQLConnection, node_uuid: Uuid, offset: u64, data: &[u8]) {
+		let node = get_existing_node(conn, node_uuid).unwrap();
+		let stream = writable_stream(conn, &node, &ExpectedContent::default(), &CancelToken::new()).unwrap();
+		streams::write_at(conn, &stream, offset, data).unwrap();
+	}
+
+	fn read(conn: &SQLConnection, node_uuid: Uuid) -> Vec<u8> {
+		let node = get_existing_node(conn, node_uuid).unwrap();
+		let stream = streams::get_stream(conn, node.content.stream).unwrap().unwrap();
+		streams::read_at(conn, &stream, 0, stream.size).unwrap()
+	}
+
+	fn count(conn: &SQLConnection, sql: &str) -> i64 {
+		conn.query_row(sql, [], |row| row.get(0)).unwrap()
+	}
+
+	/// Every block is referenced exactly `refcount` times, and every stream
+	/// is used by some node or overlay.
+	fn assert_consistent(conn: &SQLConnection) {
+		assert_eq!(count(conn,
+			"SELECT COUNT(*) FROM `block` WHERE `refcount` != \
+				(SELECT COUNT(*) FROM `stream_block` WHERE `stream_block`.`block_hash` = `block`.`block_hash`)"), 0);
+		assert_eq!(count(conn,
+			"SELECT COUNT(*) FROM `stream_block` WHERE `block_hash` NOT IN (SELECT `block_hash` FROM `block`)"), 0);
+		assert_eq!(count(conn,
+			"SELECT COUNT(*) FROM `stream` WHERE `stream_uuid` NOT IN \
+				(SELECT `contents` FROM `filenode` WHERE `contents` IS NOT NULL) \
+				AND `stream_uuid` NOT IN (SELECT `base_uuid` FROM `stream` WHERE `base_uuid` IS NOT NULL)"), 0);
+	}
+
+	/// `blocks` blocks, each filled with a byte of its own.
+	fn blocks(blocks: u8) -> Vec<u8> {
+		(1..=blocks).flat_map(|i| vec![i; STREAM_BLOCK_SIZE]).collect()
+	}
+
+	#[test]
+	fn clone_write_delete_keeps_refcounts() {
+		let conn = open_test_database();
+		let root = new_volume(&conn);
+		let original = new_node(&conn, root, "original", None);
+		let data = blocks(3);
+		write(&conn, original, 0, &data);
+
+		let clone = clone_node(&conn, original, root, Some("clone"), false).unwrap()[0].0;
+		let cloned = get_existing_node(&conn, clone).unwrap();
+		assert!(cloned.content.copy_on_write);
+		assert_eq!(cloned.content.stream, get_existing_node(&conn, original).unwrap().content.stream);
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `block`"), 3);
+		assert_consistent(&conn);
+
+		// Only the block that was written is new
+		write(&conn, clone, 0, &[0xff; 16]);
+		assert_eq!(read(&conn, original), data);
+		let mut changed = data.clone();
+		changed[..16].copy_from_slice(&[0xff; 16]);
+		assert_eq!(read(&conn, clone), changed);
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `block`"), 4);
+		assert_eq!(count(&conn, "SELECT SUM(`refcount`) FROM `block`"), 4);
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `stream_block`"), 4);
+		assert_consistent(&conn);
+
+		delete_node(&conn, original, false).unwrap();
+		assert_eq!(read(&conn, clone), changed);
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `block`"), 3);
+		assert_consistent(&conn);
+
+		delete_node(&conn, clone, false).unwrap();
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `block`"), 0);
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `stream`"), 0);
+	}
+
+	#[test]
+	fn writing_the_original_leaves_clones_alone() {
+		let conn = open_test_database();
+		let root = new_volume(&conn);
+		let original = new_node(&conn, root, "original", None);
+		let data = blocks(2);
+		write(&conn, original, 0, &data);
+		let clone = clone_node(&conn, original, root, Some("clone"), false).unwrap()[0].0;
+
+		write(&conn, original, STREAM_BLOCK_SIZE as u64, &[0; 8]);
+		assert_eq!(read(&conn, clone), data);
+		assert!(get_existing_node(&conn, clone).unwrap().content.copy_on_write);
+		assert!(!get_existing_node(&conn, original).unwrap().content.copy_on_write);
+		assert_consistent(&conn);
+	}
+
+	#[test]
+	fn truncated_clones_do_not_show_the_original_again() {
+		let conn = open_test_database();
+		let root = new_volume(&conn);
+		let original = new_node(&conn, root, "original", None);
+		let data = blocks(3);
+		write(&conn, original, 0, &data);
+		let clone = clone_node(&conn, original, root, Some("clone"), false).unwrap()[0].0;
+
+		let cut = STREAM_BLOCK_SIZE + 10;
+		for size in [cut, data.len()] {
+			let node = get_existing_node(&conn, clone).unwrap();
+			let stream = writable_stream(&conn, &node, &ExpectedContent::default(), &CancelToken::new()).unwrap();
+			streams::truncate(&conn, &stream, size as u64).unwrap();
+		}
+		let mut expected = data.clone();
+		expected[cut..].fill(0);
+		assert_eq!(read(&conn, clone), expected);
+		assert_eq!(read(&conn, original), data);
+		assert_consistent(&conn);
+	}
+
+	#[test]
+	fn clones_of_clones_read_through_and_merge_back() {
+		let conn = open_test_database();
+		let root = new_volume(&conn);
+		let first = new_node(&conn, root, "first", None);
+		let data = blocks(3);
+		write(&conn, first, 0, &data);
+		let second = clone_node(&conn, first, root, Some("second"), false).unwrap()[0].0;
+		write(&conn, second, 0, b"second");
+		let third = clone_node(&conn, second, root, Some("third"), false).unwrap()[0].0;
+		write(&conn, third, STREAM_BLOCK_SIZE as u64, b"third");
+
+		let mut expected = data.clone();
+		expected[..6].copy_from_slice(b"second");
+		expected[STREAM_BLOCK_SIZE..STREAM_BLOCK_SIZE + 5].copy_from_slice(b"third");
+		assert_eq!(read(&conn, third), expected);
+		assert_consistent(&conn);
+
+		delete_node(&conn, second, false).unwrap();
+		delete_node(&conn, first, false).unwrap();
+		assert_eq!(read(&conn, third), expected);
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `stream`"), 1);
+		assert_eq!(count(&conn, "SELECT COUNT(*) FROM `block`"), 3);
+		assert_consistent(&conn);
+	}
+
+	#[test]
+	fn clone_of_hard_link_is_regular() {
+		let conn = open_test_database();
+		let root = new_volume(&conn);
+		let original = new_node(&conn, root, "original", None);
+		let data = blocks(1);
+		write(&conn, original, 0, &data);
+		let link = new_node(&conn, root, "link", Some(original));
+
+		let clone = clone_node(&conn, link, root, Some("clone"), false).unwrap()[0].0;
+		let cloned = get_existing_node(&conn, clone).unwrap();
+		assert_eq!(cloned.content.file_kind, FileKind::Regular);
+		assert!(cloned.content.copy_on_write);
+
+		// The clone goes its own way, the hard link stays with the original
+		write(&conn, clone, 0, b"clone");
+		write(&conn, original, 0, b"original");
+		assert_eq!(&read(&conn, clone)[..5], b"clone");
+		assert_eq!(&read(&conn, link)[..8], b"original");
+		assert_eq!(&read(&conn, original)[..8], b"original");
+		assert_consistent(&conn);
+	}
+
+	#[test]
+	fn sibling_names_are_unique_in_the_database() {
+		let conn = open_test_database();
+		let root = new_volume(&conn);
+		new_node(&conn, root, "a", None);
+		let b = new_node(&conn, root, "b", None);
+		// Even when `check_name_free` is bypassed
+		let err: DVError = conn.execute("UPDATE `filenode` SET `filename` = 'a' WHERE `node_uuid` = ?1", params![b]).unwrap_err().into();
+		assert_eq!(ErrorInfo::from(&err).code, "conflict");
+		// Paths skip ".", so nothing could reach it
+		let req = CreateNodeReq{
+			parent: NodeOrPath::Node(root),
+			volume: None,
+			name: ".".to_string(),
+			uuid: None,
+			file_kind: FileKind::Empty,
+			title: String::new(),
+			description: String::new(),
+			unix_perm: None,
+			target: None,
+			link_to: None,
+		};
+		assert!(matches!(create_node(&conn, &req), Err(DVError::InvalidName(_))));
+	}
+}
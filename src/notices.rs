@@ -0,0 +1,70 @@
+use crate::prelude::*;
+use crate::messages::*;
+use tokio::sync::broadcast;
+
+/// How many change events may pile up for a slow connection before it
+/// starts losing them.
+pub const NOTICE_QUEUE_SIZE: usize = 1024;
+
+/// Something happened to a node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+	pub change: NodeChange,
+	pub node: Uuid,
+	pub volume: Uuid,
+	/// Every node above `node` (for moves: both before and after the move),
+	/// so subtree subscriptions can be matched without touching the database.
+	pub ancestors: Vec<Uuid>,
+}
+
+impl ChangeEvent {
+	pub fn new(change: NodeChange, node: Uuid, volume: Uuid, ancestors: Vec<Uuid>) -> ChangeEvent {
+		ChangeEvent{
+			change,
+			node,
+			volume,
+			ancestors,
+		}
+	}
+}
+
+impl WatchTarget {
+	pub fn matches(&self, event: &ChangeEvent) -> bool {
+		match self {
+			WatchTarget::Node(uuid) => event.node == *uuid,
+			WatchTarget::Subtree(uuid) => event.node == *uuid || event.ancestors.contains(uuid),
+			WatchTarget::Volume(uuid) => event.volume == *uuid,
+		}
+	}
+}
+
+/// Fans change events out to every open connection.
+#[derive(Debug, Clone)]
+pub struct NoticeHub {
+	tx: broadcast::Sender<Arc<ChangeEvent>>,
+}
+
+impl Default for NoticeHub {
+	fn default() -> Self {
+		NoticeHub::new(NOTICE_QUEUE_SIZE)
+	}
+}
+
+impl NoticeHub {
+	pub fn new(capacity: usize) -> NoticeHub {
+		let (tx, _) = broadcast::channel(capacity);
+		NoticeHub{
+			tx,
+		}
+	}
+
+	pub fn publish(&self, event: ChangeEvent) {
+		trace!("Publishing {:?}", event);
+		// An error only means nobody is listening right now
+		let _ = self.tx.send(Arc::new(event));
+	}
+
+	pub fn listen(&self) -> broadcast::Receiver<Arc<ChangeEvent>> {
+		self.tx.subscribe()
+	}
+}
@@ -0,0 +1,93 @@
+use crate::prelude::*;
+use crate::messages::{ListOrder, PageReq};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+
+/// Items per page when the request doesn't say.
+pub const DEFAULT_PAGE_LIMIT: u32 = 1000;
+/// Larger limits are lowered to this.
+pub const MAX_PAGE_LIMIT: u32 = 10_000;
+
+/// Where a page ended. Listings are sorted by a key and then by UUID, so
+/// the next page starts right after this pair even if rows were added or
+/// removed in the meantime.
+///
+/// Clients only see it as an opaque continuation token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+	/// What was listed (the parent node, or nil for volumes), so a token
+	/// can't be used to continue another listing.
+	scope: Uuid,
+	order: ListOrder,
+	key: String,
+	uuid: Uuid,
+}
+
+impl Cursor {
+	pub fn new(scope: Uuid, order: ListOrder, key: String, uuid: Uuid) -> Cursor {
+		Cursor{
+			scope,
+			order,
+			key,
+			uuid,
+		}
+	}
+
+	pub fn to_token(&self) -> DVResult<String> {
+		Ok(URL_SAFE_NO_PAD.encode(serde_json::to_vec(self)?))
+	}
+
+	/// Reads the `cursor` of `page`, which must come from the same listing
+	/// in the same order.
+	pub fn from_page(page: &PageReq, scope: Uuid) -> DVResult<Option<Cursor>> {
+		let token = match &page.cursor {
+			Some(v) => v,
+			None => return Ok(None),
+		};
+		let bytes = match URL_SAFE_NO_PAD.decode(token) {
+			Ok(v) => v,
+			Err(err) => return Err(DVError::BadCursor(err.to_string())),
+		};
+		let cursor: Cursor = match serde_json::from_slice(&bytes) {
+			Ok(v) => v,
+			Err(err) => return Err(DVError::BadCursor(err.to_string())),
+		};
+		if cursor.scope != scope || cursor.order != page.order_by {
+			return Err(DVError::BadCursor("token is for another listing".to_string()));
+		}
+		Ok(Some(cursor))
+	}
+
+	pub fn key(&self) -> &str {
+		&self.key
+	}
+
+	pub fn uuid(&self) -> Uuid {
+		self.uuid
+	}
+}
+
+impl PageReq {
+	/// How many items to return at most.
+	pub fn limit(&self) -> u32 {
+		self.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT)
+	}
+}
+
+/// Cuts the rows fetched for a page (one more than `limit`, to know whether
+/// there is a next one) down to size. Each row comes with its sort key and
+/// UUID, and the last one kept becomes the token for the next page.
+pub fn finish_page<T>(mut rows: Vec<(T, String, Uuid)>, limit: u32, scope: Uuid, order: ListOrder) -> DVResult<(Vec<T>, Option<String>)> {
+	let next = match rows.len() > limit as usize {
+		true => {
+			rows.truncate(limit as usize);
+			match rows.last() {
+				Some((_, key, uuid)) => Some(Cursor::new(scope, order, key.clone(), *uuid).to_token()?),
+				None => None,
+			}
+		},
+		false => None,
+	};
+	Ok((rows.into_iter().map(|(item, _, _)| item).collect(), next))
+}
@@ -31,6 +31,16 @@ pub use chrono::DateTime;
 
 pub const DEFAULT_WS_ADDR: &str = "127.0.0.1:8081";
 pub const DEFAULT_WS_ADDR_URL: &str = "ws://127.0.0.1:8081";
+pub const DEFAULT_COAP_ADDR: &str = "127.0.0.1:5683";
+pub const DEFAULT_KEY_FILE: &str = "datavir.key";
+pub const DEFAULT_DB_FILE: &str = "datavir.db";
+pub const DEFAULT_SOCKET_FILE: &str = ".datavir.socket";
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_PING_INTERVAL_SECS: u64 = 30;
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 90;
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+pub const DEFAULT_USER_UUID: &str = "00000000-0000-0000-0000-000000000000";
 
 static mut UUID_NODE_ID: [u8;6] = [1, 2, 3, 4, 5, 6];
 static mut UUID_CONTEXT: Option<UuidContext> = None;
@@ -71,6 +81,13 @@ pub unsafe fn init_uuid_context() {
     }
 }
 
+/// `init_uuid_context` for tests, which run on several threads at once.
+#[cfg(test)]
+pub fn init_test_uuid_context() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| unsafe { init_uuid_context() });
+}
+
 
 pub const DATAVIR_PKG_NAME: &str = env!("CARGO_PKG_NAME");
 pub const DATAVIR_PKG_PREIX: &str = concat!(env!("CARGO_PKG_NAME"), "::");
@@ -81,16 +98,46 @@ pub const DATAVIR_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub enum DVError {
     SQLError(SQLError),
     IOError(IOError),
-    RawWsError(RawWsError),
+    RawWsError(Box<RawWsError>),
     SystemTimeError(SystemTimeError),
     TimeConversionErrorFromSecs(u64),
     UuidParseError(String),
     DirNotClear(PathBuf),
     MpscRecvError(mpsc::RecvError),
     MpscSendError(String),
+    OneshotRecvError(tokio::sync::oneshot::error::RecvError),
     InvalidUrl(String),
+    JsonError(serde_json::Error),
+    CborError(String),
+    UnexpectedMessage(String),
+    UnknownMessage(String),
+    RemoteError(crate::messages::ErrorInfo),
+    JwtError(jsonwebtoken::errors::Error),
+    InvalidSignature,
+    InvalidKey(String),
+    InvalidIssuer(String),
+    MissingClaim(String),
+    StaleMessage(i64),
+    UnknownSubscription(u64),
+    UnsupportedProtocol(String),
+    Timeout(String),
+    ConnectionLost(String),
+    Cancelled(String),
+    NotBatchable(&'static str),
+    InvalidName(String),
+    InvalidArgument(String),
+    NotFound(String),
+    VolumeRequired(String),
+    BadOffset{expected: u64, got: u64},
+    OffsetTooLarge(u64),
+    ChunkTooLarge(usize),
+    HashMismatch,
+    Conflict(String),
+    TlsError(String),
+    CoapError(String),
     NotImplemented,
     NoMoreResults,
+    BadCursor(String),
     NotReady(String)
 }
 
@@ -108,6 +155,7 @@ impl DVError {
                 std::io::ErrorKind::NotFound => true,
                 _ => false,
             },
+            DVError::NotFound(_) => true,
             _ => false,
         }
     }
@@ -121,6 +169,11 @@ pub fn is_sql_err_not_found(err: &SQLError) -> bool {
     }
 }
 
+/// A UNIQUE (or other) constraint turned the change down.
+pub fn is_sql_err_conflict(err: &SQLError) -> bool {
+    matches!(err, rusqlite::Error::SqliteFailure(err, _) if err.code == rusqlite::ErrorCode::ConstraintViolation)
+}
+
 impl std::convert::From<SystemTimeError> for DVError {
     fn from(err: SystemTimeError) -> Self {
         DVError::SystemTimeError(err)
@@ -141,7 +194,22 @@ impl std::convert::From<SQLError> for DVError {
 
 impl std::convert::From<RawWsError> for DVError {
     fn from(err: RawWsError) -> Self {
-        DVError::RawWsError(err)
+        DVError::RawWsError(Box::new(err))
+    }
+}
+
+impl std::convert::From<serde_json::Error> for DVError {
+    fn from(err: serde_json::Error) -> Self {
+        DVError::JsonError(err)
+    }
+}
+
+impl std::convert::From<jsonwebtoken::errors::Error> for DVError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            jsonwebtoken::errors::ErrorKind::InvalidSignature => DVError::InvalidSignature,
+            _ => DVError::JwtError(err),
+        }
     }
 }
 
@@ -157,6 +225,18 @@ impl<T> std::convert::From<mpsc::SendError<T>> for DVError {
     }
 }
 
+impl<T> std::convert::From<tokio::sync::mpsc::error::SendError<T>> for DVError {
+    fn from(err: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        DVError::MpscSendError(format!("{:?}", err))
+    }
+}
+
+impl std::convert::From<tokio::sync::oneshot::error::RecvError> for DVError {
+    fn from(err: tokio::sync::oneshot::error::RecvError) -> Self {
+        DVError::OneshotRecvError(err)
+    }
+}
+
 impl<T> std::convert::From<DVError> for DVResult<T> {
     fn from(err: DVError) -> Self {
         Err(err)
@@ -59,6 +59,245 @@ fn schema_upgrade_to_v1(conn: &SQLConnection) -> SQLResult<()> {
     }
 }
 
+fn schema_upgrade_to_v2(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    let v2_schema = vec![
+        SchemaItem {
+            name: "volume",
+            kind: "table",
+            code: "CREATE TABLE `volume` (\
+                `volume_uuid` NOT NULL UNIQUE,\
+                `name` NOT NULL UNIQUE,\
+                `title` NOT NULL,\
+                `is_real` NOT NULL DEFAULT 1,\
+                `uid2name` NOT NULL DEFAULT '{}',\
+                `gid2name` NOT NULL DEFAULT '{}',\
+                `root_uuid` NOT NULL,\
+                `changed_at` NOT NULL,\
+                `created_at` NOT NULL\
+                );",
+        },
+        SchemaItem {
+            name: "filenode.volume_uuid",
+            kind: "column",
+            code: "ALTER TABLE `filenode` ADD COLUMN `volume_uuid` NULL;",
+        },
+        SchemaItem {
+            name: "filenode_volume",
+            kind: "index",
+            code: "CREATE INDEX `filenode_volume` ON `filenode` (`volume_uuid`);",
+        },
+    ];
+    apply_schema_items(conn, v2_schema, 2, &trace_msg)
+}
+
+fn schema_upgrade_to_v3(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    let mut v3_schema: Vec<SchemaItem> = vec![];
+    let new_columns = [
+        ("filenode.title", "ALTER TABLE `filenode` ADD COLUMN `title` NOT NULL DEFAULT '';"),
+        ("filenode.description", "ALTER TABLE `filenode` ADD COLUMN `description` NOT NULL DEFAULT '';"),
+        ("filenode.file_kind", "ALTER TABLE `filenode` ADD COLUMN `file_kind` NOT NULL DEFAULT 'empty';"),
+        ("filenode.copy_on_write", "ALTER TABLE `filenode` ADD COLUMN `copy_on_write` NOT NULL DEFAULT 0;"),
+        ("filenode.unix_mode", "ALTER TABLE `filenode` ADD COLUMN `unix_mode` NULL;"),
+        ("filenode.unix_uid", "ALTER TABLE `filenode` ADD COLUMN `unix_uid` NULL;"),
+        ("filenode.unix_gid", "ALTER TABLE `filenode` ADD COLUMN `unix_gid` NULL;"),
+        ("filenode.trashed_by", "ALTER TABLE `filenode` ADD COLUMN `trashed_by` NULL;"),
+        ("filenode.trashed_when", "ALTER TABLE `filenode` ADD COLUMN `trashed_when` NULL;"),
+    ];
+    for (name, code) in new_columns {
+        v3_schema.push(SchemaItem {
+            name,
+            kind: "column",
+            code,
+        });
+    }
+    v3_schema.push(SchemaItem {
+        name: "filenode_parent",
+        kind: "index",
+        code: "CREATE INDEX `filenode_parent` ON `filenode` (`parent_uuid`, `filename`);",
+    });
+    v3_schema.push(SchemaItem {
+        name: "xattr",
+        kind: "table",
+        code: "CREATE TABLE `xattr` (\
+            `node_uuid` NOT NULL,\
+            `name` NOT NULL,\
+            `format` NOT NULL,\
+            `value` NOT NULL,\
+            UNIQUE (`node_uuid`, `name`)\
+            );",
+    });
+    apply_schema_items(conn, v3_schema, 3, &trace_msg)
+}
+
+fn schema_upgrade_to_v4(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    let v4_schema = vec![
+        SchemaItem {
+            name: "stream",
+            kind: "table",
+            code: "CREATE TABLE `stream` (\
+                `stream_uuid` NOT NULL UNIQUE,\
+                `size` NOT NULL DEFAULT 0,\
+                `version` NOT NULL DEFAULT 0,\
+                `changed_at` NOT NULL,\
+                `created_at` NOT NULL\
+                );",
+        },
+        // Blocks are content addressed (by SHA-256) and shared between streams
+        SchemaItem {
+            name: "block",
+            kind: "table",
+            code: "CREATE TABLE `block` (\
+                `block_hash` NOT NULL UNIQUE,\
+                `data` NOT NULL,\
+                `refcount` NOT NULL DEFAULT 0\
+                );",
+        },
+        SchemaItem {
+            name: "stream_block",
+            kind: "table",
+            code: "CREATE TABLE `stream_block` (\
+                `stream_uuid` NOT NULL,\
+                `block_index` NOT NULL,\
+                `block_hash` NOT NULL,\
+                UNIQUE (`stream_uuid`, `block_index`)\
+                );",
+        },
+        SchemaItem {
+            name: "stream_hash",
+            kind: "table",
+            code: "CREATE TABLE `stream_hash` (\
+                `stream_uuid` NOT NULL,\
+                `alg` NOT NULL,\
+                `version` NOT NULL,\
+                `value` NOT NULL,\
+                UNIQUE (`stream_uuid`, `alg`)\
+                );",
+        },
+    ];
+    apply_schema_items(conn, v4_schema, 4, &trace_msg)
+}
+
+fn schema_upgrade_to_v5(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    let v5_schema = vec![
+        // Unfinished uploads, their data lives in the stream `upload_uuid`
+        SchemaItem {
+            name: "upload",
+            kind: "table",
+            code: "CREATE TABLE `upload` (\
+                `upload_uuid` NOT NULL UNIQUE,\
+                `node_uuid` NOT NULL,\
+                `created_at` NOT NULL\
+                );",
+        },
+    ];
+    apply_schema_items(conn, v5_schema, 5, &trace_msg)
+}
+
+fn schema_upgrade_to_v6(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    let v6_schema = vec![
+        // Directory listings sorted by date (`filenode_parent` covers names)
+        SchemaItem {
+            name: "filenode_parent_changed",
+            kind: "index",
+            code: "CREATE INDEX `filenode_parent_changed` ON `filenode` (`parent_uuid`, `changed_at`, `node_uuid`);",
+        },
+        SchemaItem {
+            name: "filenode_parent_created",
+            kind: "index",
+            code: "CREATE INDEX `filenode_parent_created` ON `filenode` (`parent_uuid`, `created_at`, `node_uuid`);",
+        },
+    ];
+    apply_schema_items(conn, v6_schema, 6, &trace_msg)
+}
+
+fn schema_upgrade_to_v7(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    // Sibling names were only kept unique by `nodes::check_name_free`. Roots
+    // are their own parents, so their empty names never clash.
+    let v7_schema = vec![
+        SchemaItem {
+            name: "filenode_parent",
+            kind: "index",
+            code: "DROP INDEX `filenode_parent`;",
+        },
+        SchemaItem {
+            name: "filenode_parent",
+            kind: "unique index",
+            code: "CREATE UNIQUE INDEX `filenode_parent` ON `filenode` (`parent_uuid`, `filename`);",
+        },
+    ];
+    apply_schema_items(conn, v7_schema, 7, &trace_msg)
+}
+
+fn schema_upgrade_to_v8(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    // A stream may only hold the blocks that differ from its base: the first
+    // `base_blocks` blocks it lacks are read from there instead of as holes
+    let v8_schema = vec![
+        SchemaItem {
+            name: "stream.base_uuid",
+            kind: "column",
+            code: "ALTER TABLE `stream` ADD COLUMN `base_uuid` NULL;",
+        },
+        SchemaItem {
+            name: "stream.base_blocks",
+            kind: "column",
+            code: "ALTER TABLE `stream` ADD COLUMN `base_blocks` NOT NULL DEFAULT 0;",
+        },
+        SchemaItem {
+            name: "stream_base",
+            kind: "index",
+            code: "CREATE INDEX `stream_base` ON `stream` (`base_uuid`);",
+        },
+    ];
+    apply_schema_items(conn, v8_schema, 8, &trace_msg)
+}
+
+/// Runs every item and then records `new_schema_version`.
+fn apply_schema_items(conn: &SQLConnection, items: Vec<SchemaItem>, new_schema_version: i32, trace_msg: &str) -> SQLResult<()> {
+    for item in items {
+        debug!("Adding {} {} with code: {}", item.kind, item.name, item.code);
+        let res = conn.execute(item.code, params![]);
+        if let Err(err) = res {
+            error!("Failed to create {} {}: {:?}", item.name, item.kind, err);
+            trace!("-{} -> {:?}", trace_msg, err);
+            return Err(err);
+        }
+    }
+
+    let res = conn.execute(
+        "UPDATE `app_config` SET `value` = ?1 WHERE `key` = 'schema_version';",
+        params![new_schema_version],
+    );
+    match res {
+        Ok(_) => {
+            debug!("Just set schema_version to {}", new_schema_version);
+            trace!("-{} -> Ok", trace_msg);
+            Ok(())
+        }
+        Err(err) => {
+            error!(
+                "Failed to set schema_version to {}, {:?}",
+                new_schema_version, err
+            );
+            trace!("-{} -> {:?}", trace_msg, err);
+            Err(err)
+        }
+    }
+}
+
 fn get_schema_version(conn: &SQLConnection) -> SQLResult<i32> {
     let trace_msg = format!("{}()", function!());
     trace!("+{}", trace_msg);
@@ -109,6 +348,13 @@ fn upgrade_schema(conn: &SQLConnection) -> SQLResult<()> {
         let schema_version = get_schema_version(conn)?;
         match schema_version {
             0 => schema_upgrade_to_v1(conn)?,
+            1 => schema_upgrade_to_v2(conn)?,
+            2 => schema_upgrade_to_v3(conn)?,
+            3 => schema_upgrade_to_v4(conn)?,
+            4 => schema_upgrade_to_v5(conn)?,
+            5 => schema_upgrade_to_v6(conn)?,
+            6 => schema_upgrade_to_v7(conn)?,
+            7 => schema_upgrade_to_v8(conn)?,
             _ => break,
         }
         if safety_counter > 100 {
@@ -202,3 +448,35 @@ pub fn open_database(db_path: &Path) -> SQLResult<SQLConnection> {
         }
     }
 }
+
+/// A fresh database that only lives in memory, for tests.
+#[cfg(test)]
+pub(crate) fn open_test_database() -> SQLConnection {
+    init_test_uuid_context();
+    open_database(Path::new(":memory:")).expect("failed to open in-memory database")
+}
+
+/// Moves everything in the write-ahead log back into the database file, so
+/// the node leaves a self-contained database behind when it stops.
+pub fn checkpoint_database(conn: &SQLConnection) -> SQLResult<()> {
+    let trace_msg = format!("{}()", function!());
+    trace!("+{}", trace_msg);
+    // Returns (busy, log pages, checkpointed pages), all -1 when not in WAL mode
+    let ans = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+    });
+    match ans {
+        Ok((busy, log, checkpointed)) => {
+            if busy != 0 {
+                warn!("Database checkpoint could not complete: the database is busy");
+            }
+            trace!("-{} -> Ok (log={}, checkpointed={})", trace_msg, log, checkpointed);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Failed to checkpoint database: {:?}", err);
+            trace!("-{} -> {:?}", trace_msg, err);
+            Err(err)
+        }
+    }
+}
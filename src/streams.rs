@@ -0,0 +1,448 @@
+use crate::prelude::*;
+use crate::messages::HashAlg;
+use crate::dispatcher::CancelToken;
+use rusqlite::OptionalExtension;
+use sha2::Digest;
+
+/// Streams are stored as a sequence of blocks of this size (only the last
+/// one may be shorter).
+pub const STREAM_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk that can be read or uploaded with a single message.
+pub const MAX_STREAM_CHUNK: usize = 1024 * 1024;
+
+/// Sizes and offsets are stored as SQLite integers, so no stream may grow
+/// past this.
+pub const MAX_STREAM_SIZE: u64 = i64::MAX as u64;
+
+/// Where `len` bytes written at `offset` end, if that still fits in a stream.
+pub fn end_of_write(offset: u64, len: usize) -> DVResult<u64> {
+	match offset.checked_add(len as u64) {
+		Some(end) if end <= MAX_STREAM_SIZE => Ok(end),
+		_ => Err(DVError::OffsetTooLarge(offset)),
+	}
+}
+
+/// Incremental digest for any of the supported algorithms.
+pub enum StreamHasher {
+	Sha256(sha2::Sha256),
+	Sha1(sha1::Sha1),
+	Blake3(Box<blake3::Hasher>),
+	Md5(md5::Md5),
+}
+
+impl StreamHasher {
+	pub fn new(alg: HashAlg) -> StreamHasher {
+		match alg {
+			HashAlg::Sha256 => StreamHasher::Sha256(sha2::Sha256::new()),
+			HashAlg::Sha1 => StreamHasher::Sha1(sha1::Sha1::new()),
+			HashAlg::Blake3 => StreamHasher::Blake3(Box::new(blake3::Hasher::new())),
+			HashAlg::Md5 => StreamHasher::Md5(md5::Md5::new()),
+		}
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		match self {
+			StreamHasher::Sha256(h) => h.update(data),
+			StreamHasher::Sha1(h) => h.update(data),
+			StreamHasher::Blake3(h) => { h.update(data); },
+			StreamHasher::Md5(h) => h.update(data),
+		}
+	}
+
+	pub fn finalize(self) -> Vec<u8> {
+		match self {
+			StreamHasher::Sha256(h) => h.finalize().to_vec(),
+			StreamHasher::Sha1(h) => h.finalize().to_vec(),
+			StreamHasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+			StreamHasher::Md5(h) => h.finalize().to_vec(),
+		}
+	}
+}
+
+impl HashAlg {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			HashAlg::Sha256 => "sha-256",
+			HashAlg::Sha1 => "sha-1",
+			HashAlg::Blake3 => "blake3",
+			HashAlg::Md5 => "md5",
+		}
+	}
+
+	pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+		let mut hasher = StreamHasher::new(*self);
+		hasher.update(data);
+		hasher.finalize()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+	pub uuid: Uuid,
+	pub size: u64,
+	/// Bumped on every change to the contents.
+	pub version: u64,
+}
+
+pub fn get_stream(conn: &SQLConnection, stream_uuid: Uuid) -> DVResult<Option<StreamInfo>> {
+	let ans = conn.query_row(
+		"SELECT `stream_uuid`, `size`, `version` FROM `stream` WHERE `stream_uuid` = ?1",
+		params![stream_uuid],
+		|row| Ok(StreamInfo{
+			uuid: row.get(0)?,
+			size: i64_to_u64(row.get(1)?),
+			version: i64_to_u64(row.get(2)?),
+		}),
+	).optional()?;
+	Ok(ans)
+}
+
+pub fn create_stream(conn: &SQLConnection) -> DVResult<Uuid> {
+	let now = Utc::now();
+	let stream_uuid = new_uuid_at(now);
+	conn.execute(
+		"INSERT INTO `stream` (`stream_uuid`, `size`, `version`, `changed_at`, `created_at`) VALUES (?1, 0, 0, ?2, ?2)",
+		params![stream_uuid, now],
+	)?;
+	Ok(stream_uuid)
+}
+
+/// Stores `data` (if it is not there yet) and takes a reference to it.
+fn put_block(conn: &SQLConnection, data: &[u8]) -> DVResult<Vec<u8>> {
+	let block_hash = HashAlg::Sha256.digest(data);
+	conn.execute(
+		"INSERT INTO `block` (`block_hash`, `data`, `refcount`) VALUES (?1, ?2, 1) \
+			ON CONFLICT (`block_hash`) DO UPDATE SET `refcount` = `refcount` + 1",
+		params![block_hash, data],
+	)?;
+	Ok(block_hash)
+}
+
+/// Drops a reference to a block, deleting it once nobody uses it.
+fn release_block(conn: &SQLConnection, block_hash: &[u8]) -> DVResult<()> {
+	conn.execute("UPDATE `block` SET `refcount` = `refcount` - 1 WHERE `block_hash` = ?1", params![block_hash])?;
+	conn.execute("DELETE FROM `block` WHERE `block_hash` = ?1 AND `refcount` <= 0", params![block_hash])?;
+	Ok(())
+}
+
+fn get_block_hash(conn: &SQLConnection, stream_uuid: Uuid, index: u64) -> DVResult<Option<Vec<u8>>> {
+	let mut stmt = conn.prepare_cached("SELECT `block_hash` FROM `stream_block` WHERE `stream_uuid` = ?1 AND `block_index` = ?2")?;
+	Ok(stmt.query_row(params![stream_uuid, u64_to_i64(index)], |row| row.get(0)).optional()?)
+}
+
+/// Contents of block number `index` (empty if the stream has no such block).
+///
+/// A block missing from an overlay (see [`overlay_stream`]) comes from its
+/// base, and from the base of that, unless it was cut off by a truncation.
+pub fn read_block(conn: &SQLConnection, stream_uuid: Uuid, index: u64) -> DVResult<Vec<u8>> {
+	let mut stmt = conn.prepare_cached(
+		"WITH RECURSIVE `chain` (`stream_uuid`, `depth`) AS ( \
+				SELECT ?1, 0 \
+				UNION ALL SELECT `stream`.`base_uuid`, `chain`.`depth` + 1 FROM `stream` JOIN `chain` USING (`stream_uuid`) \
+					WHERE `stream`.`base_uuid` IS NOT NULL AND ?2 < `stream`.`base_blocks`) \
+			SELECT `block`.`data` FROM `chain` JOIN `stream_block` USING (`stream_uuid`) JOIN `block` USING (`block_hash`) \
+			WHERE `stream_block`.`block_index` = ?2 ORDER BY `chain`.`depth` LIMIT 1")?;
+	let ans: Option<Vec<u8>> = stmt.query_row(params![stream_uuid, u64_to_i64(index)], |row| row.get(0)).optional()?;
+	Ok(ans.unwrap_or_default())
+}
+
+/// Replaces block number `index`.
+///
+/// Callers must finish with [`mark_changed`] so the size, version and
+/// cached digests are kept right.
+pub fn write_block(conn: &SQLConnection, stream_uuid: Uuid, index: u64, data: &[u8]) -> DVResult<()> {
+	let new_hash = put_block(conn, data)?;
+	if let Some(old_hash) = get_block_hash(conn, stream_uuid, index)? {
+		release_block(conn, &old_hash)?;
+	}
+	conn.execute(
+		"INSERT INTO `stream_block` (`stream_uuid`, `block_index`, `block_hash`) VALUES (?1, ?2, ?3) \
+			ON CONFLICT (`stream_uuid`, `block_index`) DO UPDATE SET `block_hash` = ?3",
+		params![stream_uuid, u64_to_i64(index), new_hash],
+	)?;
+	Ok(())
+}
+
+/// Drops every block from number `first` onwards.
+pub fn drop_blocks_from(conn: &SQLConnection, stream_uuid: Uuid, first: u64) -> DVResult<()> {
+	let hashes = {
+		let mut stmt = conn.prepare_cached("SELECT `block_hash` FROM `stream_block` WHERE `stream_uuid` = ?1 AND `block_index` >= ?2")?;
+		let rows = stmt.query_map(params![stream_uuid, u64_to_i64(first)], |row| row.get::<_, Vec<u8>>(0))?;
+		rows.collect::<SQLResult<Vec<_>>>()?
+	};
+	for block_hash in hashes {
+		release_block(conn, &block_hash)?;
+	}
+	conn.execute("DELETE FROM `stream_block` WHERE `stream_uuid` = ?1 AND `block_index` >= ?2", params![stream_uuid, u64_to_i64(first)])?;
+	Ok(())
+}
+
+/// Reads up to `length` bytes starting at `offset`.
+pub fn read_at(conn: &SQLConnection, stream: &StreamInfo, offset: u64, length: u64) -> DVResult<Vec<u8>> {
+	let end = std::cmp::min(stream.size, offset.saturating_add(length));
+	let mut ans = Vec::with_capacity(end.saturating_sub(offset) as usize);
+	let mut pos = offset;
+	while pos < end {
+		let index = pos / STREAM_BLOCK_SIZE as u64;
+		let start = (pos % STREAM_BLOCK_SIZE as u64) as usize;
+		let mut block = read_block(conn, stream.uuid, index)?;
+		// Holes read as zeros
+		block.resize(STREAM_BLOCK_SIZE, 0);
+		let stop = std::cmp::min(STREAM_BLOCK_SIZE as u64, end - index * STREAM_BLOCK_SIZE as u64) as usize;
+		ans.extend_from_slice(&block[start..stop]);
+		pos = index * STREAM_BLOCK_SIZE as u64 + stop as u64;
+	}
+	Ok(ans)
+}
+
+/// Writes `data` at `offset`, growing the stream if needed, and returns the
+/// new version of the stream.
+pub fn write_at(conn: &SQLConnection, stream: &StreamInfo, offset: u64, data: &[u8]) -> DVResult<u64> {
+	let end = end_of_write(offset, data.len())?;
+	let mut pos = 0;
+	while pos < data.len() {
+		let abs = offset + pos as u64;
+		let index = abs / STREAM_BLOCK_SIZE as u64;
+		let start = (abs % STREAM_BLOCK_SIZE as u64) as usize;
+		let n = std::cmp::min(STREAM_BLOCK_SIZE - start, data.len() - pos);
+		let mut block = if start == 0 && n == STREAM_BLOCK_SIZE {
+			Vec::new()
+		} else {
+			read_block(conn, stream.uuid, index)?
+		};
+		if block.len() < start + n {
+			block.resize(start + n, 0);
+		}
+		block[start..start + n].copy_from_slice(&data[pos..pos + n]);
+		write_block(conn, stream.uuid, index, &block)?;
+		pos += n;
+	}
+	mark_changed(conn, stream.uuid, std::cmp::max(stream.size, end))
+}
+
+/// A new stream with the same content (and version) as `stream`, which
+/// becomes its base. The overlay starts out without a block of its own and
+/// only stores the ones written to it later, so this takes the same time
+/// whatever the size of `stream`. Nothing may change `stream` from then on.
+pub fn overlay_stream(conn: &SQLConnection, stream: &StreamInfo) -> DVResult<StreamInfo> {
+	let now = Utc::now();
+	let overlay_uuid = new_uuid_at(now);
+	conn.execute(
+		"INSERT INTO `stream` (`stream_uuid`, `size`, `version`, `changed_at`, `created_at`, `base_uuid`, `base_blocks`) \
+			VALUES (?1, ?2, ?3, ?4, ?4, ?5, ?6)",
+		params![overlay_uuid, u64_to_i64(stream.size), u64_to_i64(stream.version), now, stream.uuid,
+			u64_to_i64(stream.size.div_ceil(STREAM_BLOCK_SIZE as u64))],
+	)?;
+	conn.execute(
+		"INSERT INTO `stream_hash` (`stream_uuid`, `alg`, `version`, `value`) \
+			SELECT ?2, `alg`, `version`, `value` FROM `stream_hash` WHERE `stream_uuid` = ?1",
+		params![stream.uuid, overlay_uuid],
+	)?;
+	debug!("Laid stream {} over {}", overlay_uuid, stream.uuid);
+	Ok(StreamInfo{
+		uuid: overlay_uuid,
+		size: stream.size,
+		version: stream.version,
+	})
+}
+
+/// The stream `stream_uuid` is an overlay of, if any.
+pub fn base_of(conn: &SQLConnection, stream_uuid: Uuid) -> DVResult<Option<Uuid>> {
+	let ans: Option<Option<Uuid>> = conn.query_row(
+		"SELECT `base_uuid` FROM `stream` WHERE `stream_uuid` = ?1",
+		params![stream_uuid],
+		|row| row.get(0),
+	).optional()?;
+	Ok(ans.flatten())
+}
+
+/// Every overlay of `stream_uuid`.
+pub fn overlays_of(conn: &SQLConnection, stream_uuid: Uuid) -> DVResult<Vec<Uuid>> {
+	let mut stmt = conn.prepare_cached("SELECT `stream_uuid` FROM `stream` WHERE `base_uuid` = ?1")?;
+	let rows = stmt.query_map(params![stream_uuid], |row| row.get::<_, Uuid>(0))?;
+	Ok(rows.collect::<SQLResult<Vec<_>>>()?)
+}
+
+/// Moves the blocks of an overlay into its base, which takes over its
+/// content (and version) while the overlay is deleted, and returns the base.
+/// Only for a base nothing else uses anymore. This costs as much as the
+/// blocks the overlay replaced or cut off, not the size of the stream.
+pub fn merge_overlay(conn: &SQLConnection, overlay_uuid: Uuid) -> DVResult<Uuid> {
+	let (base_uuid, base_blocks, size, version, changed_at): (Option<Uuid>, i64, i64, i64, DateTime<Utc>) = conn.query_row(
+		"SELECT `base_uuid`, `base_blocks`, `size`, `version`, `changed_at` FROM `stream` WHERE `stream_uuid` = ?1",
+		params![overlay_uuid],
+		|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+	)?;
+	let base_uuid = match base_uuid {
+		Some(v) => v,
+		None => return Err(DVError::NotFound(format!("base of stream {}", overlay_uuid))),
+	};
+	drop_blocks_from(conn, base_uuid, i64_to_u64(base_blocks))?;
+	let replaced = {
+		let mut stmt = conn.prepare_cached(
+			"SELECT `base`.`block_hash` FROM `stream_block` AS `overlay` JOIN `stream_block` AS `base` \
+				ON `base`.`stream_uuid` = ?2 AND `base`.`block_index` = `overlay`.`block_index` \
+				WHERE `overlay`.`stream_uuid` = ?1")?;
+		let rows = stmt.query_map(params![overlay_uuid, base_uuid], |row| row.get::<_, Vec<u8>>(0))?;
+		rows.collect::<SQLResult<Vec<_>>>()?
+	};
+	for block_hash in replaced {
+		release_block(conn, &block_hash)?;
+	}
+	conn.execute(
+		"DELETE FROM `stream_block` WHERE `stream_uuid` = ?2 AND `block_index` IN \
+			(SELECT `block_index` FROM `stream_block` WHERE `stream_uuid` = ?1)",
+		params![overlay_uuid, base_uuid],
+	)?;
+	conn.execute("UPDATE `stream_block` SET `stream_uuid` = ?2 WHERE `stream_uuid` = ?1", params![overlay_uuid, base_uuid])?;
+	conn.execute(
+		"UPDATE `stream` SET `size` = ?2, `version` = ?3, `changed_at` = ?4, `base_blocks` = MIN(`base_blocks`, ?5) WHERE `stream_uuid` = ?1",
+		params![base_uuid, size, version, changed_at, base_blocks],
+	)?;
+	conn.execute("DELETE FROM `stream_hash` WHERE `stream_uuid` = ?1", params![base_uuid])?;
+	conn.execute("UPDATE `stream_hash` SET `stream_uuid` = ?2 WHERE `stream_uuid` = ?1", params![overlay_uuid, base_uuid])?;
+	conn.execute("UPDATE `stream` SET `base_uuid` = ?2 WHERE `base_uuid` = ?1", params![overlay_uuid, base_uuid])?;
+	conn.execute("DELETE FROM `stream` WHERE `stream_uuid` = ?1", params![overlay_uuid])?;
+	debug!("Merged stream {} into its base {}", overlay_uuid, base_uuid);
+	Ok(base_uuid)
+}
+
+/// Cuts a stream down to `size` bytes, or grows it with a hole of zeros,
+/// and returns the new version of the stream.
+pub fn truncate(conn: &SQLConnection, stream: &StreamInfo, size: u64) -> DVResult<u64> {
+	if size > MAX_STREAM_SIZE {
+		return Err(DVError::OffsetTooLarge(size));
+	}
+	if size < stream.size {
+		let block_size = STREAM_BLOCK_SIZE as u64;
+		let kept = size.div_ceil(block_size);
+		drop_blocks_from(conn, stream.uuid, kept)?;
+		conn.execute(
+			"UPDATE `stream` SET `base_blocks` = MIN(`base_blocks`, ?2) WHERE `stream_uuid` = ?1",
+			params![stream.uuid, u64_to_i64(kept)],
+		)?;
+		// Growing it back later must not bring the old bytes back, from
+		// this stream or its base
+		let rest = (size % block_size) as usize;
+		if rest != 0 {
+			let mut block = read_block(conn, stream.uuid, kept - 1)?;
+			if block.len() > rest {
+				block.truncate(rest);
+				write_block(conn, stream.uuid, kept - 1, &block)?;
+			}
+		}
+	}
+	mark_changed(conn, stream.uuid, size)
+}
+
+/// Records that the contents of a stream changed: sets its new size, bumps
+/// its version and forgets every cached digest.
+pub fn mark_changed(conn: &SQLConnection, stream_uuid: Uuid, size: u64) -> DVResult<u64> {
+	conn.execute(
+		"UPDATE `stream` SET `size` = ?2, `version` = `version` + 1, `changed_at` = ?3 WHERE `stream_uuid` = ?1",
+		params![stream_uuid, u64_to_i64(size), Utc::now()],
+	)?;
+	conn.execute("DELETE FROM `stream_hash` WHERE `stream_uuid` = ?1", params![stream_uuid])?;
+	match get_stream(conn, stream_uuid)? {
+		Some(stream) => Ok(stream.version),
+		None => Err(DVError::NotFound(format!("stream {}", stream_uuid))),
+	}
+}
+
+/// Deletes a stream and releases all of its blocks. Its base, if it has
+/// one, is left to the caller.
+pub fn delete_stream(conn: &SQLConnection, stream_uuid: Uuid) -> DVResult<()> {
+	drop_blocks_from(conn, stream_uuid, 0)?;
+	conn.execute("DELETE FROM `stream_hash` WHERE `stream_uuid` = ?1", params![stream_uuid])?;
+	conn.execute("DELETE FROM `stream` WHERE `stream_uuid` = ?1", params![stream_uuid])?;
+	Ok(())
+}
+
+/// Digest of the whole stream.
+///
+/// Digests are cached per stream version, so a stream is only read again
+/// after its contents change.
+pub fn stream_hash(conn: &SQLConnection, stream_uuid: Uuid, alg: HashAlg, cancel: &CancelToken) -> DVResult<Vec<u8>> {
+	let trace_msg = format!("{}(stream_uuid={}, alg={})", function!(), stream_uuid, alg.as_str());
+	trace!("+{}", trace_msg);
+	let stream = match get_stream(conn, stream_uuid)? {
+		Some(v) => v,
+		None => {
+			trace!("-{} -> NotFound", trace_msg);
+			return Err(DVError::NotFound(format!("stream {}", stream_uuid)));
+		}
+	};
+	let cached: Option<Vec<u8>> = conn.query_row(
+		"SELECT `value` FROM `stream_hash` WHERE `stream_uuid` = ?1 AND `alg` = ?2 AND `version` = ?3",
+		params![stream_uuid, alg.as_str(), u64_to_i64(stream.version)],
+		|row| row.get(0),
+	).optional()?;
+	if let Some(value) = cached {
+		trace!("-{} -> Ok (cached)", trace_msg);
+		return Ok(value);
+	}
+
+	let mut hasher = StreamHasher::new(alg);
+	let n_blocks = stream.size.div_ceil(STREAM_BLOCK_SIZE as u64);
+	for index in 0..n_blocks {
+		cancel.check()?;
+		let mut block = read_block(conn, stream_uuid, index)?;
+		// Short blocks in the middle are followed by a hole of zeros
+		let len = std::cmp::min(STREAM_BLOCK_SIZE as u64, stream.size - index * STREAM_BLOCK_SIZE as u64);
+		block.resize(len as usize, 0);
+		hasher.update(&block);
+	}
+	let value = hasher.finalize();
+	conn.execute(
+		"INSERT INTO `stream_hash` (`stream_uuid`, `alg`, `version`, `value`) VALUES (?1, ?2, ?3, ?4) \
+			ON CONFLICT (`stream_uuid`, `alg`) DO UPDATE SET `version` = ?3, `value` = ?4",
+		params![stream_uuid, alg.as_str(), u64_to_i64(stream.version), value],
+	)?;
+	trace!("-{} -> Ok ({} blocks hashed)", trace_msg, n_blocks);
+	Ok(value)
+}
+
+/// Creates an upload for `node_uuid` and the stream that will hold its data.
+pub fn begin_upload(conn: &SQLConnection, node_uuid: Uuid) -> DVResult<Uuid> {
+	let upload_uuid = create_stream(conn)?;
+	conn.execute(
+		"INSERT INTO `upload` (`upload_uuid`, `node_uuid`, `created_at`) VALUES (?1, ?2, ?3)",
+		params![upload_uuid, node_uuid, Utc::now()],
+	)?;
+	debug!("Began upload {} for node {}", upload_uuid, node_uuid);
+	Ok(upload_uuid)
+}
+
+/// The most recent unfinished upload for `node_uuid`.
+pub fn find_upload(conn: &SQLConnection, node_uuid: Uuid) -> DVResult<Option<Uuid>> {
+	let ans = conn.query_row(
+		"SELECT `upload_uuid` FROM `upload` WHERE `node_uuid` = ?1 ORDER BY `created_at` DESC LIMIT 1",
+		params![node_uuid],
+		|row| row.get(0),
+	).optional()?;
+	Ok(ans)
+}
+
+/// The node an upload is meant for, along with the stream holding its data.
+pub fn get_upload(conn: &SQLConnection, upload_uuid: Uuid) -> DVResult<(Uuid, StreamInfo)> {
+	let node_uuid: Option<Uuid> = conn.query_row(
+		"SELECT `node_uuid` FROM `upload` WHERE `upload_uuid` = ?1",
+		params![upload_uuid],
+		|row| row.get(0),
+	).optional()?;
+	match (node_uuid, get_stream(conn, upload_uuid)?) {
+		(Some(node_uuid), Some(stream)) => Ok((node_uuid, stream)),
+		_ => Err(DVError::NotFound(format!("upload {}", upload_uuid))),
+	}
+}
+
+/// Forgets about an upload. With `keep_data` its stream is left alone (it
+/// is about to become the content of the node).
+pub fn end_upload(conn: &SQLConnection, upload_uuid: Uuid, keep_data: bool) -> DVResult<()> {
+	conn.execute("DELETE FROM `upload` WHERE `upload_uuid` = ?1", params![upload_uuid])?;
+	if !keep_data {
+		delete_stream(conn, upload_uuid)?;
+	}
+	Ok(())
+}
@@ -80,3 +80,38 @@ pub fn ensure_dir_exists(what: &str, path: &Path) -> IOResult<()> {
         return Ok(());
     }
 }
+
+/// Characters a filename may neither start nor end with.
+const FILENAME_EDGE_CHARS: &[char] = &['!', '@', '#', '$', '%', '&', ':', '~', '-'];
+
+/// Filenames are limited to 4096 bytes including the final NULL.
+pub const MAX_FILENAME_LEN: usize = 4095;
+
+/// Checks the FilePath syntax rules from DESIGN.old.md.
+///
+/// The same rules apply to volume names and metadata entry names.
+pub fn check_filename(name: &str) -> DVResult<()> {
+    use unicode_normalization::is_nfc;
+    let reason = if name.is_empty() {
+        "is empty"
+    } else if name.len() > MAX_FILENAME_LEN {
+        "is too long"
+    } else if !is_nfc(name) {
+        "is not in NFC"
+    } else if name.starts_with(FILENAME_EDGE_CHARS) || name.ends_with(FILENAME_EDGE_CHARS) {
+        "starts or ends with a reserved character"
+    } else if name.starts_with("._") {
+        "starts with \"._\""
+    } else if name.contains('/') {
+        "contains \"/\""
+    } else if name.contains("..") {
+        "contains \"..\""
+    } else if name == "." {
+        "is \".\", which paths skip"
+    } else if name.starts_with(char::is_whitespace) || name.ends_with(char::is_whitespace) {
+        "starts or ends with white space"
+    } else {
+        return Ok(());
+    };
+    Err(DVError::InvalidName(format!("{:?} {}", name, reason)))
+}
@@ -0,0 +1,182 @@
+use crate::prelude::*;
+use crate::messages::{ListOrder, PageReq, VolumeInfo, VolumeStatus};
+use crate::paging::{self, Cursor};
+use crate::nodes;
+use crate::utils::check_filename;
+use rusqlite::OptionalExtension;
+
+const VOLUME_COLUMNS: &str = "`volume_uuid`, `title`, `name`, `is_real`, `uid2name`, `gid2name`";
+
+fn id_map_from_sql(val: String) -> SQLResult<HashMap<u16, String>> {
+	match serde_json::from_str(&val) {
+		Ok(v) => Ok(v),
+		Err(err) => Err(SQLError::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))),
+	}
+}
+
+fn id_map_to_sql(val: &HashMap<u16, String>) -> DVResult<String> {
+	Ok(serde_json::to_string(val)?)
+}
+
+fn row_to_volume(row: &rusqlite::Row) -> SQLResult<VolumeInfo> {
+	Ok(VolumeInfo{
+		uuid: row.get(0)?,
+		title: row.get(1)?,
+		name: row.get(2)?,
+		is_real: row.get(3)?,
+		uid2name: id_map_from_sql(row.get(4)?)?,
+		gid2name: id_map_from_sql(row.get(5)?)?,
+	})
+}
+
+/// One page of volumes, and the token for the next one if there is more.
+pub fn list_volumes(conn: &SQLConnection, page: &PageReq) -> DVResult<(Vec<VolumeInfo>, Option<String>)> {
+	let trace_msg = format!("{}(order_by={:?}, cursor={})", function!(), page.order_by, page.cursor.is_some());
+	trace!("+{}", trace_msg);
+	let key = match page.order_by {
+		ListOrder::Name => "`name`",
+		ListOrder::ChangedAt => "`changed_at`",
+		ListOrder::CreatedAt => "`created_at`",
+	};
+	let cursor = Cursor::from_page(page, Uuid::nil())?;
+	let limit = page.limit();
+	let after = match cursor {
+		Some(_) => format!("WHERE ({}, `volume_uuid`) > (?2, ?3)", key),
+		None => String::new(),
+	};
+	let mut stmt = conn.prepare(&format!(
+		"SELECT {}, {} FROM `volume` {} ORDER BY {}, `volume_uuid` LIMIT ?1",
+		VOLUME_COLUMNS, key, after, key))?;
+	let to_row = |row: &rusqlite::Row| Ok((row_to_volume(row)?, row.get(6)?, row.get(0)?));
+	let rows = match &cursor {
+		Some(cursor) => stmt.query_map(params![limit + 1, cursor.key(), cursor.uuid()], to_row)?,
+		None => stmt.query_map(params![limit + 1], to_row)?,
+	};
+	let mut ans = Vec::new();
+	for row in rows {
+		ans.push(row?);
+	}
+	let (volumes, next) = paging::finish_page(ans, limit, Uuid::nil(), page.order_by)?;
+	trace!("-{} -> Ok({} volumes, more: {})", trace_msg, volumes.len(), next.is_some());
+	Ok((volumes, next))
+}
+
+pub fn get_volume(conn: &SQLConnection, volume_uuid: Uuid) -> DVResult<Option<VolumeInfo>> {
+	let ans = conn.query_row(
+		&format!("SELECT {} FROM `volume` WHERE `volume_uuid` = ?1", VOLUME_COLUMNS),
+		params![volume_uuid],
+		row_to_volume,
+	).optional()?;
+	Ok(ans)
+}
+
+/// UUID of the root node of a volume.
+pub fn get_volume_root(conn: &SQLConnection, volume_uuid: Uuid) -> DVResult<Option<Uuid>> {
+	let ans = conn.query_row(
+		"SELECT `root_uuid` FROM `volume` WHERE `volume_uuid` = ?1",
+		params![volume_uuid],
+		|row| row.get(0),
+	).optional()?;
+	Ok(ans)
+}
+
+fn is_name_taken(conn: &SQLConnection, name: &str, except: Option<Uuid>) -> DVResult<bool> {
+	let other: Option<Uuid> = conn.query_row(
+		"SELECT `volume_uuid` FROM `volume` WHERE `name` = ?1",
+		params![name],
+		|row| row.get(0),
+	).optional()?;
+	Ok(match other {
+		Some(other) => Some(other) != except,
+		None => false,
+	})
+}
+
+/// Creates a volume along with its (empty) root node.
+///
+/// A nil UUID means "pick one for me".
+pub fn create_volume(conn: &SQLConnection, volume: &VolumeInfo) -> DVResult<(VolumeStatus, Option<VolumeInfo>)> {
+	let trace_msg = format!("{}(name={:?})", function!(), volume.name);
+	trace!("+{}", trace_msg);
+	if check_filename(&volume.name).is_err() {
+		trace!("-{} -> InvalidName", trace_msg);
+		return Ok((VolumeStatus::InvalidName, None));
+	}
+	let now = Utc::now();
+	let mut volume = volume.clone();
+	if volume.uuid.is_nil() {
+		volume.uuid = new_uuid_at(now);
+	} else if get_volume(conn, volume.uuid)?.is_some() {
+		trace!("-{} -> UuidTaken", trace_msg);
+		return Ok((VolumeStatus::UuidTaken, None));
+	}
+	if is_name_taken(conn, &volume.name, None)? {
+		trace!("-{} -> NameTaken", trace_msg);
+		return Ok((VolumeStatus::NameTaken, None));
+	}
+
+	let root_uuid = new_uuid_at(now);
+	conn.execute(
+		"INSERT INTO `volume` (`volume_uuid`, `title`, `name`, `is_real`, `uid2name`, `gid2name`, `root_uuid`, `changed_at`, `created_at`) \
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)",
+		params![volume.uuid, volume.title, volume.name, volume.is_real, id_map_to_sql(&volume.uid2name)?, id_map_to_sql(&volume.gid2name)?, root_uuid, now],
+	)?;
+	// The root is its own parent
+	conn.execute(
+		"INSERT INTO `filenode` (`node_uuid`, `parent_uuid`, `filename`, `contents`, `super_hidden`, `changed_at`, `created_at`, `volume_uuid`) \
+			VALUES (?1, ?1, '', NULL, 0, ?2, ?2, ?3)",
+		params![root_uuid, now, volume.uuid],
+	)?;
+	info!("Created volume {:?} ({})", volume.name, volume.uuid);
+	trace!("-{} -> Ok", trace_msg);
+	Ok((VolumeStatus::Ok, Some(volume)))
+}
+
+pub fn rename_volume(conn: &SQLConnection, volume_uuid: Uuid, name: Option<&str>, title: Option<&str>) -> DVResult<(VolumeStatus, Option<VolumeInfo>)> {
+	let trace_msg = format!("{}(volume_uuid={}, name={:?}, title={:?})", function!(), volume_uuid, name, title);
+	trace!("+{}", trace_msg);
+	if get_volume(conn, volume_uuid)?.is_none() {
+		trace!("-{} -> NotFound", trace_msg);
+		return Ok((VolumeStatus::NotFound, None));
+	}
+	if let Some(name) = name {
+		if check_filename(name).is_err() {
+			trace!("-{} -> InvalidName", trace_msg);
+			return Ok((VolumeStatus::InvalidName, None));
+		}
+		if is_name_taken(conn, name, Some(volume_uuid))? {
+			trace!("-{} -> NameTaken", trace_msg);
+			return Ok((VolumeStatus::NameTaken, None));
+		}
+	}
+	conn.execute(
+		"UPDATE `volume` SET `name` = COALESCE(?2, `name`), `title` = COALESCE(?3, `title`), `changed_at` = ?4 WHERE `volume_uuid` = ?1",
+		params![volume_uuid, name, title, Utc::now()],
+	)?;
+	trace!("-{} -> Ok", trace_msg);
+	Ok((VolumeStatus::Ok, get_volume(conn, volume_uuid)?))
+}
+
+/// Removes a volume and every node in it, as `deleteNodeReq` would.
+pub fn delete_volume(conn: &SQLConnection, volume_uuid: Uuid) -> DVResult<VolumeStatus> {
+	let trace_msg = format!("{}(volume_uuid={})", function!(), volume_uuid);
+	trace!("+{}", trace_msg);
+	let n = conn.execute("DELETE FROM `volume` WHERE `volume_uuid` = ?1", params![volume_uuid])?;
+	if n == 0 {
+		trace!("-{} -> NotFound", trace_msg);
+		return Ok(VolumeStatus::NotFound);
+	}
+	let node_uuids = {
+		let mut stmt = conn.prepare("SELECT `node_uuid` FROM `filenode` WHERE `volume_uuid` = ?1")?;
+		let rows = stmt.query_map(params![volume_uuid], |row| row.get::<_, Uuid>(0))?;
+		rows.collect::<SQLResult<Vec<_>>>()?
+	};
+	// One at a time, so content that a clone elsewhere still uses is kept
+	let n = node_uuids.len();
+	for node_uuid in node_uuids {
+		nodes::delete_one_node(conn, node_uuid)?;
+	}
+	info!("Deleted volume {} and its {} nodes", volume_uuid, n);
+	trace!("-{} -> Ok", trace_msg);
+	Ok(VolumeStatus::Ok)
+}
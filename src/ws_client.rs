@@ -1,126 +1,740 @@
 #[allow(unused_imports)]
-use tokio_tungstenite::{WebSocketStream, MaybeTlsStream};
-use tokio::net::TcpStream;
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::WebSocketStream;
+use tokio::net::{TcpStream, UnixStream};
+use tokio_tungstenite::client_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::prelude::*;
+use crate::messages::*;
+use crate::coap_client::CoapClientInner;
+use crate::jwt::{Encoding, JwtCodec};
+use crate::net::{self, BoxedStream, Timeouts, TlsClientConfig};
+use crate::notices::NOTICE_QUEUE_SIZE;
+use crate::streams::{StreamHasher, STREAM_BLOCK_SIZE};
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use futures_util::stream::{self, Stream, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::task;
+use std::time::{Duration, Instant};
 
-type WSReturn = String;
+pub(crate) type WSReturn = DVResult<Vec<u8>>;
+type WSConnection = WebSocketStream<BoxedStream>;
 
 #[derive(Debug)]
 pub struct WSRequestBundle {
-	return_ch: mpsc::Sender<WSReturn>,
-	data: Vec<u8>,
-	close_ws: bool,
+	pub(crate) return_ch: oneshot::Sender<WSReturn>,
+	pub(crate) req_id: u64,
+	pub(crate) encoding: Encoding,
+	pub(crate) data: Vec<u8>,
+	pub(crate) close_ws: bool,
+	/// The request itself, for the ones that may be sent again after a
+	/// reconnect.
+	pub(crate) replay: Option<DVRequest>,
 }
 
-unsafe impl Send for WSRequestBundle {}
-unsafe impl Sync for WSRequestBundle {}
-
 impl WSRequestBundle {
-	pub fn new(data: Vec<u8>) -> (Self, mpsc::Receiver<WSReturn>) {
-		let (tx, rx) = mpsc::channel();
+	pub fn new(req_id: u64, encoding: Encoding, data: Vec<u8>) -> (Self, oneshot::Receiver<WSReturn>) {
+		let (tx, rx) = oneshot::channel();
 		return (WSRequestBundle{
 			return_ch: tx,
-			data: data,
-			close_ws: false
+			req_id,
+			encoding,
+			data,
+			close_ws: false,
+			replay: None,
 		}, rx);
 	}
 
-	fn new_close() -> (Self, mpsc::Receiver<WSReturn>) {
-		let (tx, rx) = mpsc::channel();
+	fn new_close() -> (Self, oneshot::Receiver<WSReturn>) {
+		let (tx, rx) = oneshot::channel();
 		return (WSRequestBundle{
 			return_ch: tx,
+			req_id: 0,
+			encoding: Encoding::default(),
 			data: Vec::new(),
-			close_ws: true
+			close_ws: true,
+			replay: None,
 		}, rx);
 	}
 
 	pub fn raw_msg(&self) -> RawWsMessage {
-		RawWsMessage::Binary(self.data.clone())
+		net::token_to_frame(self.encoding, self.data.clone())
+	}
+}
+
+
+/// Everything about how `WSClient` connects that isn't the address.
+#[derive(Debug, Clone, Default)]
+pub struct WSClientConfig {
+	/// How `wss://` servers are checked.
+	pub tls: TlsClientConfig,
+	/// Encoding to ask for. The server may fall back to JSON.
+	pub encoding: Encoding,
+	/// When to ping the server, give up on it and stop waiting for replies.
+	pub timeouts: Timeouts,
+	/// Whether to connect again when the connection is lost (only for
+	/// WebSocket connections, CoAP has none to lose).
+	pub reconnect: Option<Reconnect>,
+}
+
+/// How `WSClient` gets back to a node it lost, waiting twice as long after
+/// each failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reconnect {
+	pub initial_delay: Duration,
+	pub max_delay: Duration,
+	/// `None` keeps trying forever.
+	pub max_attempts: Option<u32>,
+}
+
+impl Default for Reconnect {
+	fn default() -> Self {
+		Reconnect{
+			initial_delay: Duration::from_millis(500),
+			max_delay: Duration::from_secs(30),
+			max_attempts: None,
+		}
 	}
 }
 
+/// Where a `WSClient` stands with its node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+	Connected,
+	/// The connection was lost and this is the attempt to get it back
+	/// (counting from 1). Requests wait until it is back.
+	Reconnecting{attempt: u32},
+	/// Closed, or out of attempts to reconnect. Requests fail from now on.
+	Disconnected,
+}
+
+/// Subscriptions by the ID their caller got, which stays the same when the
+/// node hands out new ones after a reconnect.
+#[derive(Debug, Default)]
+struct SubscriptionTable {
+	next_id: u64,
+	targets: HashMap<u64, WatchTarget>,
+	/// From the ID the node uses to the caller's.
+	by_node_id: HashMap<u64, u64>,
+}
+
+impl SubscriptionTable {
+	fn add(&mut self, node_id: u64, target: WatchTarget) -> u64 {
+		self.next_id += 1;
+		self.targets.insert(self.next_id, target);
+		self.by_node_id.insert(node_id, self.next_id);
+		self.next_id
+	}
+
+	fn node_id(&self, id: u64) -> Option<u64> {
+		self.by_node_id.iter().find(|(_, caller_id)| **caller_id == id).map(|(node_id, _)| *node_id)
+	}
+
+	fn remove(&mut self, id: u64) {
+		self.targets.remove(&id);
+		self.by_node_id.retain(|_, caller_id| *caller_id != id);
+	}
+}
+
+/// What `WSClient` and its `WSClientInner` both keep an eye on.
+#[derive(Debug)]
+struct InnerShared {
+	next_req_id: Arc<AtomicU64>,
+	subscriptions: Arc<Mutex<SubscriptionTable>>,
+	state_tx: watch::Sender<ConnectionState>,
+}
+
+/// How many chunks may be on their way before we wait for the oldest one.
+pub const TRANSFER_WINDOW: usize = 8;
+
+/// A request that was sent but whose reply was not received yet.
+#[derive(Debug)]
+pub struct PendingReply {
+	req_id: u64,
+	rx: oneshot::Receiver<WSReturn>,
+}
+
+impl PendingReply {
+	/// What to pass to `WSClient::cancel`.
+	pub fn req_id(&self) -> u64 {
+		self.req_id
+	}
+}
 
 #[derive(Debug)]
 pub struct WSClient {
 	addr: String,
 	closed: Arc<Mutex<bool>>,
-	send_ch: mpsc::SyncSender<WSRequestBundle>,
+	codec: Arc<JwtCodec>,
+	/// What the server agreed to.
+	encoding: Encoding,
+	request_timeout: Duration,
+	next_req_id: Arc<AtomicU64>,
+	subscriptions: Arc<Mutex<SubscriptionTable>>,
+	state_rx: watch::Receiver<ConnectionState>,
+	send_ch: mpsc::Sender<WSRequestBundle>,
+	notices_tx: broadcast::Sender<DVNotice>,
+	/// What the node said it can do when we connected.
+	capabilities: Option<HelloRpl>,
 	_marker: PhantomPinned,
 }
 
-unsafe impl Send for WSClient {}
-
 impl WSClient {
 	#[allow(dead_code)]
 	pub fn get_addr(&self) -> String {
 		self.addr.clone()
 	}
 
-	pub async fn new(addr: &str) -> DVResult<WSClient> {
+	pub fn encoding(&self) -> Encoding {
+		self.encoding
+	}
+
+	/// What the node told us about itself (only `None` while connecting).
+	pub fn capabilities(&self) -> Option<&HelloRpl> {
+		self.capabilities.as_ref()
+	}
+
+	/// Changes as the connection is lost and found again (see
+	/// `WSClientConfig::reconnect`).
+	pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+		self.state_rx.clone()
+	}
+
+	/// The protocol version agreed on with the node.
+	pub fn protocol_version(&self) -> u32 {
+		match &self.capabilities {
+			Some(hello) => hello.protocol_version,
+			None => PROTOCOL_VERSIONS[0],
+		}
+	}
+
+	pub async fn new(addr: &str, codec: JwtCodec) -> DVResult<WSClient> {
+		WSClient::new_with_config(addr, codec, WSClientConfig::default()).await
+	}
+
+	/// Besides `ws://` and `wss://`, `coap://` URLs talk to a `CoapServer`
+	/// and `unix:///path` ones to a `WSServer` on a Unix socket.
+	///
+	/// The node is greeted with a `helloReq` first, and nodes we share no
+	/// protocol version with are refused with `DVError::UnsupportedProtocol`.
+	pub async fn new_with_config(addr: &str, codec: JwtCodec, config: WSClientConfig) -> DVResult<WSClient> {
 		let url = url::Url::parse(addr);
 		if url.is_err() {
 			return Err(DVError::InvalidUrl(addr.to_string()))
 		}
 
-		let (tx1, rx1) = mpsc::sync_channel(1);
-		let (tx2, rx2) = mpsc::sync_channel(1);
-		task::spawn(WSClient::spawn_async(addr.to_string(), tx1.clone(), tx2.clone()));
-		match rx1.recv() {
-			Ok(v) => if let Some(err) = v {
-				return Err(err);
+		let codec = Arc::new(codec);
+		let (notices_tx, _) = broadcast::channel(NOTICE_QUEUE_SIZE);
+		let next_req_id = Arc::new(AtomicU64::new(1));
+		let subscriptions = Arc::new(Mutex::new(SubscriptionTable::default()));
+		let (state_tx, state_rx) = watch::channel(ConnectionState::Connected);
+		let shared = InnerShared{
+			next_req_id: next_req_id.clone(),
+			subscriptions: subscriptions.clone(),
+			state_tx,
+		};
+		let (send_ch, encoding) = match url.map(|url| url.scheme() == "coap") {
+			Ok(true) => match CoapClientInner::new(addr.to_string(), &config, codec.clone(), notices_tx.clone()).await {
+				Ok((inner, send_ch, encoding)) => {
+					task::spawn(inner.run());
+					(send_ch, encoding)
+				},
+				Err(err) => {
+					error!("Failed to create CoapClientInner: {:?}", err);
+					return Err(err);
+				}
+			},
+			_ => match WSClientInner::new(addr.to_string(), &config, codec.clone(), notices_tx.clone(), shared).await {
+				Ok((inner, send_ch, encoding)) => {
+					task::spawn(inner.run());
+					(send_ch, encoding)
+				},
+				Err(err) => {
+					error!("Failed to create WSClientInner: {:?}", err);
+					return Err(err);
+				}
 			},
-			Err(err) => {
-				error!("{:?}", err);
-				return Err(err)?;
-			}
 		};
 
-		let send_ch = match rx2.recv() {
-			Ok(v) => v,
+		let mut client = WSClient{
+			addr: addr.to_string(),
+			send_ch,
+			notices_tx,
+			codec,
+			encoding,
+			request_timeout: config.timeouts.request_timeout,
+			next_req_id,
+			subscriptions,
+			state_rx,
+			closed: Arc::new(Mutex::new(false)),
+			capabilities: None,
+			_marker: PhantomPinned,
+		};
+		match client.hello().await {
+			Ok(hello) => {
+				info!("Connected to datavir {} at {} (protocol version {})", hello.datavir_version, addr, hello.protocol_version);
+				client.capabilities = Some(hello);
+				Ok(client)
+			},
 			Err(err) => {
-				error!("{:?}", err);
-				return Err(err)?;
+				error!("Failed to greet {}: {:?}", addr, err);
+				if let Err(err) = client.close().await {
+					debug!("Failed to close WSClient: {:?}", err);
+				}
+				Err(err)
 			}
+		}
+	}
+
+	async fn hello(&self) -> DVResult<HelloRpl> {
+		check_hello(self.request(DVRequest::HelloReq(HelloReq::new())).await)
+	}
+
+	/// Sends a request and waits for the matching reply.
+	///
+	/// An `errorRpl` from the other side is turned into `DVError::RemoteError`.
+	pub async fn request(&self, req: DVRequest) -> DVResult<DVReply> {
+		let pending = self.send_request(req).await?;
+		self.wait_reply(pending).await
+	}
+
+	/// Sends a request without waiting for its reply, so several requests
+	/// can be in flight at once. Requests leave in the order they are sent.
+	pub async fn send_request(&self, req: DVRequest) -> DVResult<PendingReply> {
+		// 1. Make message
+		let req_id = self.next_req_id.fetch_add(1, Ordering::Relaxed);
+		let replay = match req.is_idempotent() {
+			true => Some(req.clone()),
+			false => None,
 		};
+		let raw_msg = self.codec.encode_as(self.encoding, Some(req_id), DVMessage::Req(req))?;
+		let (mut msg, rx) = WSRequestBundle::new(req_id, self.encoding, raw_msg);
+		msg.replay = replay;
 
-		Ok(WSClient{
-			addr: addr.to_string(),
-			send_ch: send_ch,
-			closed: Arc::new(Mutex::new(false)),
-			_marker: PhantomPinned,
+		// 3. Send message
+		self.send_ch.send(msg).await?;
+		Ok(PendingReply{
+			req_id,
+			rx,
 		})
 	}
 
-	async fn spawn_async(addr: String, tx1: mpsc::SyncSender<Option<DVError>>, tx2: mpsc::SyncSender<mpsc::SyncSender<WSRequestBundle>>) {
-		let mut inner = match WSClientInner::new(addr).await {
-			Ok(v) => v,
-			Err(err) => {
-				error!("Failed to create WSClientInner: {:?}", err);
-				tx1.send(Some(err)).expect("Failed to send error through channel");
-				return;
+	pub async fn wait_reply(&self, pending: PendingReply) -> DVResult<DVReply> {
+		let req_id = pending.req_id;
+
+		// 4. Wait for return value
+		let ans = match tokio::time::timeout(self.request_timeout, pending.rx).await {
+			Ok(ans) => ans??,
+			Err(_) => return Err(DVError::Timeout(format!("no reply to reqId={} from {} within {:?}", req_id, self.addr, self.request_timeout))),
+		};
+		reply_of(&self.codec, self.encoding, &ans, req_id)
+	}
+
+	/// Every notice pushed by the other side from now on.
+	pub fn notices(&self) -> broadcast::Receiver<DVNotice> {
+		self.notices_tx.subscribe()
+	}
+
+	/// The ID it returns is the one notices will carry, even after the
+	/// subscription had to be made again on a new connection.
+	pub async fn subscribe(&self, target: WatchTarget) -> DVResult<u64> {
+		match self.request(DVRequest::SubscribeReq(SubscribeReq{target})).await? {
+			DVReply::SubscribeRpl(rpl) => Ok(self.subscriptions.lock().unwrap().add(rpl.subscription, target)),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn unsubscribe(&self, subscription: u64) -> DVResult<()> {
+		let node_id = match self.subscriptions.lock().unwrap().node_id(subscription) {
+			Some(v) => v,
+			None => return Err(DVError::UnknownSubscription(subscription)),
+		};
+		match self.request(DVRequest::UnsubscribeReq(UnsubscribeReq{subscription: node_id})).await? {
+			DVReply::UnsubscribeRpl => {
+				self.subscriptions.lock().unwrap().remove(subscription);
+				Ok(())
+			},
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Asks the node to stop working on the request sent as `req_id`, which
+	/// then fails with a `cancelled` error. Returns false if it was too late.
+	pub async fn cancel(&self, req_id: u64) -> DVResult<bool> {
+		match self.request(DVRequest::CancelReq(CancelReq{request: req_id})).await? {
+			DVReply::CancelRpl(rpl) => Ok(rpl.cancelled),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn ask_time(&self) -> DVResult<DateTime<Utc>> {
+		match self.request(DVRequest::GetTimeReq).await? {
+			DVReply::GetTimeRpl(rpl) => Ok(rpl.current_time),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Every volume, however many pages it takes.
+	pub async fn list_volumes(&self) -> DVResult<Vec<VolumeInfo>> {
+		self.volumes(ListOrder::Name).try_collect().await
+	}
+
+	/// Every volume, asking for the next page once the previous one was
+	/// consumed.
+	pub fn volumes(&self, order_by: ListOrder) -> impl Stream<Item = DVResult<VolumeInfo>> + '_ {
+		let first = PageReq{
+			order_by,
+			..PageReq::default()
+		};
+		stream::try_unfold(Some(first), move |page| async move {
+			let page = match page {
+				Some(v) => v,
+				None => return DVResult::Ok(None),
+			};
+			let rpl = self.list_volumes_page(page.clone()).await?;
+			let next = rpl.next.map(|next| page.after(next));
+			Ok(Some((stream::iter(rpl.volumes.into_iter().map(Ok)), next)))
+		}).try_flatten()
+	}
+
+	pub async fn list_volumes_page(&self, page: PageReq) -> DVResult<ListVolumesRpl> {
+		match self.request(DVRequest::ListVolumesReq(ListVolumesReq{page})).await? {
+			DVReply::ListVolumesRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn new_volume(&self, volume: VolumeInfo) -> DVResult<NewVolumeRpl> {
+		match self.request(DVRequest::NewVolumeReq(NewVolumeReq{volume})).await? {
+			DVReply::NewVolumeRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn rename_volume(&self, volume: Uuid, name: Option<String>, title: Option<String>) -> DVResult<RenameVolumeRpl> {
+		let req = RenameVolumeReq{
+			volume,
+			name,
+			title,
+		};
+		match self.request(DVRequest::RenameVolumeReq(req)).await? {
+			DVReply::RenameVolumeRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn delete_volume(&self, volume: Uuid) -> DVResult<VolumeStatus> {
+		match self.request(DVRequest::DeleteVolumeReq(DeleteVolumeReq{volume})).await? {
+			DVReply::DeleteVolumeRpl(rpl) => Ok(rpl.status),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Runs `ops` in a single transaction. A failed op is not an error
+	/// here: it shows up in the reply, which is then not `committed`.
+	pub async fn batch(&self, ops: Vec<DVRequest>) -> DVResult<BatchRpl> {
+		match self.request(DVRequest::BatchReq(BatchReq{ops})).await? {
+			DVReply::BatchRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn create_node(&self, req: CreateNodeReq) -> DVResult<NodeInfo> {
+		match self.request(DVRequest::CreateNodeReq(req)).await? {
+			DVReply::CreateNodeRpl(rpl) => Ok(rpl.node),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn rename_node(&self, req: RenameNodeReq) -> DVResult<NodeInfo> {
+		match self.request(DVRequest::RenameNodeReq(req)).await? {
+			DVReply::RenameNodeRpl(rpl) => Ok(rpl.node),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn move_node(&self, node: NodeOrPath, parent: NodeOrPath, volume: Option<Uuid>, name: Option<String>) -> DVResult<NodeInfo> {
+		let req = MoveNodeReq{
+			node,
+			volume,
+			parent,
+			name,
+		};
+		match self.request(DVRequest::MoveNodeReq(req)).await? {
+			DVReply::MoveNodeRpl(rpl) => Ok(rpl.node),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Returns how many nodes were deleted.
+	pub async fn delete_node(&self, node: NodeOrPath, volume: Option<Uuid>, recursive: bool) -> DVResult<u64> {
+		let req = DeleteNodeReq{
+			node,
+			volume,
+			recursive,
+		};
+		match self.request(DVRequest::DeleteNodeReq(req)).await? {
+			DVReply::DeleteNodeRpl(rpl) => Ok(rpl.count),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Copies `node` below `parent`, cheaply since contents are shared until
+	/// changed. Returns the copy and how many nodes were copied.
+	pub async fn clone_node(&self, node: NodeOrPath, parent: NodeOrPath, volume: Option<Uuid>, name: Option<String>, recursive: bool) -> DVResult<(NodeInfo, u64)> {
+		let req = CloneNodeReq{
+			node,
+			volume,
+			parent,
+			name,
+			recursive,
+		};
+		match self.request(DVRequest::CloneNodeReq(req)).await? {
+			DVReply::CloneNodeRpl(rpl) => Ok((rpl.node, rpl.count)),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn node_info(&self, nodes_or_paths: Vec<NodeOrPath>, volume: Option<Uuid>) -> DVResult<NodeInfoRpl> {
+		let req = NodeInfoReq{
+			nodes_or_paths,
+			volume,
+		};
+		match self.request(DVRequest::NodeInfoReq(req)).await? {
+			DVReply::NodeInfoRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Every node right below `node`, a page at a time like `volumes`.
+	pub fn children(&self, node: NodeOrPath, volume: Option<Uuid>, order_by: ListOrder) -> impl Stream<Item = DVResult<NodeInfo>> + '_ {
+		let first = PageReq{
+			order_by,
+			..PageReq::default()
+		};
+		stream::try_unfold(Some(first), move |page| {
+			let node = node.clone();
+			async move {
+				let page = match page {
+					Some(v) => v,
+					None => return DVResult::Ok(None),
+				};
+				let rpl = self.list_children_page(node, volume, page.clone()).await?;
+				let next = rpl.next.map(|next| page.after(next));
+				Ok(Some((stream::iter(rpl.children.into_iter().map(Ok)), next)))
 			}
+		}).try_flatten()
+	}
+
+	pub async fn list_children_page(&self, node: NodeOrPath, volume: Option<Uuid>, page: PageReq) -> DVResult<ListChildrenRpl> {
+		let req = ListChildrenReq{
+			node,
+			volume,
+			page,
 		};
-		tx1.send(None).expect("Failed to send non-error through channel");
-		tx2.send(inner.send_ch.clone()).expect("Failed to send send channel through channel");
+		match self.request(DVRequest::ListChildrenReq(req)).await? {
+			DVReply::ListChildrenRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
 
-		inner.run().await;
-		return;
+	pub async fn stream_hash(&self, alg: HashAlg, nodes_or_paths: Vec<NodeOrPath>, volume: Option<Uuid>) -> DVResult<StreamHashRpl> {
+		let req = StreamHashReq{
+			alg,
+			nodes_or_paths,
+			volume,
+		};
+		match self.request(DVRequest::StreamHashReq(req)).await? {
+			DVReply::StreamHashRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
 	}
 
-	pub async fn ask_time(&self) -> DVResult<String> {
-		// 1. Make message
-		let raw_msg = "get_time".as_bytes().to_vec();
-		let (msg, rx) = WSRequestBundle::new(raw_msg);
+	/// Replaces the content of a node with everything in `file`.
+	///
+	/// With `resume`, an unfinished upload to the same node is continued from
+	/// where it stopped. The server checks the SHA-256 of the result before
+	/// the node is changed.
+	pub async fn upload<F>(&self, node: NodeOrPath, volume: Option<Uuid>, resume: bool, file: &mut F) -> DVResult<FinishUploadRpl>
+	where F: AsyncRead + AsyncSeek + Unpin {
+		let req = BeginUploadReq{
+			node,
+			volume,
+			resume,
+		};
+		let begin = match self.request(DVRequest::BeginUploadReq(req)).await? {
+			DVReply::BeginUploadRpl(rpl) => rpl,
+			other => return Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		};
+		info!("Uploading to node {} from offset {}", begin.node, begin.offset);
 
-		// 3. Send message
-		self.send_ch.send(msg)?;
+		// Whatever was sent before still counts towards the hash
+		let mut hasher = StreamHasher::new(HashAlg::Sha256);
+		let mut buf = vec![0u8; STREAM_BLOCK_SIZE];
+		let mut offset = 0;
+		file.seek(SeekFrom::Start(0)).await?;
+		let mut pending = VecDeque::new();
+		loop {
+			let want = match offset < begin.offset {
+				true => std::cmp::min(buf.len() as u64, begin.offset - offset) as usize,
+				false => buf.len(),
+			};
+			let n = file.read(&mut buf[..want]).await?;
+			if n == 0 {
+				break;
+			}
+			hasher.update(&buf[..n]);
+			if offset >= begin.offset {
+				let req = UploadChunkReq{
+					upload: begin.upload,
+					offset,
+					data: buf[..n].to_vec(),
+				};
+				pending.push_back(self.send_request(DVRequest::UploadChunkReq(req)).await?);
+				if pending.len() >= TRANSFER_WINDOW {
+					self.wait_chunk(pending.pop_front()).await?;
+				}
+			}
+			offset += n as u64;
+		}
+		if offset < begin.offset {
+			return Err(DVError::BadOffset{expected: begin.offset, got: offset});
+		}
+		while let Some(chunk) = pending.pop_front() {
+			self.wait_chunk(Some(chunk)).await?;
+		}
 
-		// 4. Wait for return value
-		let ans = rx.recv()?;
+		let req = FinishUploadReq{
+			upload: begin.upload,
+			alg: HashAlg::Sha256,
+			hash: hasher.finalize(),
+		};
+		match self.request(DVRequest::FinishUploadReq(req)).await? {
+			DVReply::FinishUploadRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn abort_upload(&self, upload: Uuid) -> DVResult<()> {
+		match self.request(DVRequest::AbortUploadReq(AbortUploadReq{upload})).await? {
+			DVReply::AbortUploadRpl => Ok(()),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Writes `data` (at most `MAX_STREAM_CHUNK` bytes) at `offset` in the
+	/// content of a node, failing with a `conflict` error if it is not as
+	/// `expect`ed.
+	pub async fn write_stream(&self, node: NodeOrPath, volume: Option<Uuid>, offset: u64, data: Vec<u8>, expect: ExpectedContent) -> DVResult<WriteStreamRpl> {
+		let req = WriteStreamReq{
+			node,
+			volume,
+			offset,
+			data,
+			expect,
+		};
+		match self.request(DVRequest::WriteStreamReq(req)).await? {
+			DVReply::WriteStreamRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
 
-		Ok(ans.to_string())
+	pub async fn truncate_stream(&self, node: NodeOrPath, volume: Option<Uuid>, size: u64, expect: ExpectedContent) -> DVResult<TruncateStreamRpl> {
+		let req = TruncateStreamReq{
+			node,
+			volume,
+			size,
+			expect,
+		};
+		match self.request(DVRequest::TruncateStreamReq(req)).await? {
+			DVReply::TruncateStreamRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	pub async fn append_stream(&self, node: NodeOrPath, volume: Option<Uuid>, data: Vec<u8>, expect: ExpectedContent) -> DVResult<AppendStreamRpl> {
+		let req = AppendStreamReq{
+			node,
+			volume,
+			data,
+			expect,
+		};
+		match self.request(DVRequest::AppendStreamReq(req)).await? {
+			DVReply::AppendStreamRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	async fn wait_chunk(&self, pending: Option<PendingReply>) -> DVResult<()> {
+		match pending {
+			Some(pending) => match self.wait_reply(pending).await? {
+				DVReply::UploadChunkRpl(_) => Ok(()),
+				other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+			},
+			None => Ok(()),
+		}
+	}
+
+	async fn wait_read(&self, pending: PendingReply) -> DVResult<ReadStreamRpl> {
+		match self.wait_reply(pending).await? {
+			DVReply::ReadStreamRpl(rpl) => Ok(rpl),
+			other => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		}
+	}
+
+	/// Copies the content of a node into `file`, continuing after whatever
+	/// `file` already holds, and checks the SHA-256 of the result against the
+	/// one the server has. Returns the size of the content.
+	pub async fn download<F>(&self, node: NodeOrPath, volume: Option<Uuid>, file: &mut F) -> DVResult<u64>
+	where F: AsyncRead + AsyncWrite + AsyncSeek + Unpin {
+		let read_req = |node: NodeOrPath, offset: u64| DVRequest::ReadStreamReq(ReadStreamReq{
+			node,
+			volume,
+			offset,
+			length: STREAM_BLOCK_SIZE as u64,
+		});
+		let start = file.seek(SeekFrom::End(0)).await?;
+		let first = self.send_request(read_req(node, start)).await?;
+		let first = self.wait_read(first).await?;
+		let (node_uuid, size, version) = (first.node, first.size, first.version);
+		info!("Downloading node {} ({} bytes) from offset {}", node_uuid, size, start);
+		file.write_all(&first.data).await?;
+
+		let mut next = start + first.data.len() as u64;
+		let mut pending = VecDeque::new();
+		while next < size || !pending.is_empty() {
+			while next < size && pending.len() < TRANSFER_WINDOW {
+				pending.push_back(self.send_request(read_req(NodeOrPath::Node(node_uuid), next)).await?);
+				next += STREAM_BLOCK_SIZE as u64;
+			}
+			if let Some(chunk) = pending.pop_front() {
+				let rpl = self.wait_read(chunk).await?;
+				if rpl.version != version {
+					return Err(DVError::Conflict(format!("node {} changed during the download", node_uuid)));
+				}
+				file.write_all(&rpl.data).await?;
+			}
+		}
+		file.flush().await?;
+
+		let mut hasher = StreamHasher::new(HashAlg::Sha256);
+		let mut buf = vec![0u8; STREAM_BLOCK_SIZE];
+		file.seek(SeekFrom::Start(0)).await?;
+		loop {
+			let n = file.read(&mut buf).await?;
+			if n == 0 {
+				break;
+			}
+			hasher.update(&buf[..n]);
+		}
+		let remote = self.stream_hash(HashAlg::Sha256, vec![NodeOrPath::Node(node_uuid)], volume).await?;
+		if remote.values.get(&node_uuid).map(|v| v.as_slice()) != Some(hasher.finalize().as_slice()) {
+			return Err(DVError::HashMismatch);
+		}
+		Ok(size)
 	}
 
 	pub async fn close(&self) -> DVResult<()> {
@@ -130,10 +744,11 @@ impl WSClient {
 
 		// 3. Send message
 		info!("Closing WSClient (Waiting for WSClientInner)");
-		self.send_ch.send(msg)?;
-
-		// 4. Wait for return value
-		rx.recv()?;
+		match self.send_ch.send(msg).await {
+			// 4. Wait for return value
+			Ok(()) => { rx.await??; },
+			Err(_) => debug!("WSClientInner was already gone"),
+		}
 
 		info!("Closed WSClient");
 
@@ -146,6 +761,37 @@ impl WSClient {
 	}
 }
 
+/// The reply to `req_id` in `token`, or the error it carries.
+fn reply_of(codec: &JwtCodec, encoding: Encoding, token: &[u8], req_id: u64) -> DVResult<DVReply> {
+	// Only Unix socket connections agree on unsigned messages
+	let claims = match encoding {
+		Encoding::Plain => codec.decode_local(token)?,
+		_ => codec.decode(token)?,
+	};
+	if claims.req_id != Some(req_id) {
+		return Err(DVError::UnexpectedMessage(format!("reply to reqId={:?} while waiting for {}", claims.req_id, req_id)));
+	}
+	match claims.msg {
+		DVMessage::Rpl(DVReply::ErrorRpl(info)) => Err(DVError::RemoteError(info.for_request(claims.req_id))),
+		DVMessage::Rpl(rpl) => Ok(rpl),
+		DVMessage::Req(req) => Err(DVError::UnexpectedMessage(req.msg_type().to_string())),
+		DVMessage::Ntc(ntc) => Err(DVError::UnexpectedMessage(format!("{:?}", ntc))),
+	}
+}
+
+/// Whether the node answered `helloReq` with a protocol version we speak.
+fn check_hello(ans: DVResult<DVReply>) -> DVResult<HelloRpl> {
+	match ans {
+		Ok(DVReply::HelloRpl(rpl)) if PROTOCOL_VERSIONS.contains(&rpl.protocol_version) => Ok(rpl),
+		Ok(DVReply::HelloRpl(rpl)) => Err(DVError::UnsupportedProtocol(format!("node picked unknown protocol version {}", rpl.protocol_version))),
+		Ok(other) => Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+		Err(DVError::RemoteError(info)) if info.code == "unsupportedProtocol" => Err(DVError::UnsupportedProtocol(info.message)),
+		// Nodes older than helloReq can't make sense of it
+		Err(DVError::RemoteError(info)) if info.code == "unknownMessage" => Err(DVError::UnsupportedProtocol(format!("node does not know helloReq ({})", info.message))),
+		Err(err) => Err(err),
+	}
+}
+
 impl Drop for WSClient {
     fn drop(&mut self) {
     	debug!("DROP");
@@ -158,64 +804,368 @@ impl Drop for WSClient {
 }
 
 
+/// Whatever woke up `WSClientInner::serve`.
+enum WSEvent {
+	Request(Option<WSRequestBundle>),
+	Frame(Option<Result<RawWsMessage, RawWsError>>),
+	Heartbeat,
+}
+
+/// Why a connection ended without anybody closing it.
+enum Lost {
+	/// Nothing was heard from the node for too long.
+	Silent(String),
+	/// The connection failed or the node closed it.
+	Dropped(String),
+}
+
+impl Lost {
+	/// What the requests that can't wait for a new connection get.
+	fn error(&self) -> DVError {
+		match self {
+			Lost::Silent(reason) => DVError::Timeout(reason.clone()),
+			Lost::Dropped(reason) => DVError::ConnectionLost(reason.clone()),
+		}
+	}
+}
+
+/// How trying to get the node back ended.
+enum Reconnected {
+	Yes,
+	GaveUp,
+	/// `WSClient` was closed (or dropped) in the meantime.
+	Closed,
+}
+
+/// A request that went out and is waiting for its reply.
+#[derive(Debug)]
+struct InFlight {
+	return_ch: oneshot::Sender<WSReturn>,
+	/// Set if it may be sent again on a new connection.
+	replay: Option<DVRequest>,
+}
+
 #[derive(Debug)]
 struct WSClientInner {
 	addr: String,
+	config: WSClientConfig,
+	codec: Arc<JwtCodec>,
 	recv_ch: mpsc::Receiver<WSRequestBundle>,
-	send_ch: mpsc::SyncSender<WSRequestBundle>,
-	ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+	notices_tx: broadcast::Sender<DVNotice>,
+	ws_stream: WSConnection,
+	encoding: Encoding,
+	pending: HashMap<u64, InFlight>,
+	/// Requests that came while there was no connection.
+	backlog: VecDeque<WSRequestBundle>,
+	next_req_id: Arc<AtomicU64>,
+	subscriptions: Arc<Mutex<SubscriptionTable>>,
+	state_tx: watch::Sender<ConnectionState>,
+	/// Anything from the server (pongs included) shows it is still there.
+	last_heard: Instant,
 	_marker: PhantomPinned,
 }
 
 impl WSClientInner {
-	pub async fn new(addr: String) -> DVResult<WSClientInner> {
-		let ws_stream = WSClientInner::make_connection(&addr).await?;
-		let (tx, rx) = mpsc::sync_channel(10);
-		return Ok(WSClientInner{
+	pub async fn new(addr: String, config: &WSClientConfig, codec: Arc<JwtCodec>, notices_tx: broadcast::Sender<DVNotice>, shared: InnerShared) -> DVResult<(WSClientInner, mpsc::Sender<WSRequestBundle>, Encoding)> {
+		let (ws_stream, encoding) = WSClientInner::make_connection(&addr, config).await?;
+		let (tx, rx) = mpsc::channel(10);
+		return Ok((WSClientInner{
 			addr: addr.to_string(),
+			config: config.clone(),
+			codec,
 			recv_ch: rx,
-			send_ch: tx,
-			ws_stream: ws_stream,
+			notices_tx,
+			ws_stream,
+			encoding,
+			pending: HashMap::new(),
+			backlog: VecDeque::new(),
+			next_req_id: shared.next_req_id,
+			subscriptions: shared.subscriptions,
+			state_tx: shared.state_tx,
+			last_heard: Instant::now(),
 			_marker: PhantomPinned,
-		})
+		}, tx, encoding))
 	}
 
-	pub async fn run(&mut self) {
-		// TODO: use select! to listen both on self.recv_ch and self.ws_stream
+	pub async fn run(mut self) {
+		loop {
+			let lost = match self.serve().await {
+				Some(v) => v,
+				None => return,
+			};
+			let reconnect = match self.config.reconnect {
+				Some(v) => v,
+				None => return self.give_up(&lost),
+			};
+			// The rest were sent once already, and may have been acted on
+			let pending = std::mem::take(&mut self.pending);
+			for (req_id, in_flight) in pending {
+				match in_flight.replay {
+					Some(_) => { self.pending.insert(req_id, in_flight); },
+					None => { let _ = in_flight.return_ch.send(Err(lost.error())); },
+				}
+			}
+			match self.reconnect(reconnect).await {
+				Reconnected::Yes => continue,
+				Reconnected::GaveUp => return self.give_up(&lost),
+				Reconnected::Closed => return,
+			}
+		}
+	}
 
-		// Process messages
+	/// Runs the current connection until it is closed (`None`) or lost.
+	async fn serve(&mut self) -> Option<Lost> {
+		let mut heartbeat = tokio::time::interval(self.timeouts().ping_interval);
+		heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 		loop {
-			// Todo: add better way to stop this loop
-			let item = self.recv_ch.recv();
-			match item {
-				Ok(msg) => {
-					info!("got msg = {:?}", msg);
+			let event = tokio::select! {
+				item = self.recv_ch.recv() => WSEvent::Request(item),
+				frame = self.ws_stream.next() => WSEvent::Frame(frame),
+				_ = heartbeat.tick() => WSEvent::Heartbeat,
+			};
+			match event {
+				WSEvent::Request(Some(msg)) => {
+					trace!("Sending reqId={} ({} bytes)", msg.req_id, msg.data.len());
 					if msg.close_ws {
 						info!("Closing WSClientInner");
 						if let Err(err) = self.close().await {
 							error!("Failed to close WebSocket: {:?}", err);
 						}
-						if let Err(err) = msg.return_ch.send("".to_string()) {
-							error!("Failed to notify of WebSocket closure: {:?}", err);
-						}
+						self.closed(Some(msg));
 						info!("Closed WSClientInner");
-						return
+						return None;
 					}
-
-					if let Err(err) = self.ws_stream.send(msg.raw_msg()).await {
-						error!("Failed to send message through WebSocket: {:?}", err);
-					} else {
-						let ans = self.ws_stream.next().await;
-						if let Err(err) = msg.return_ch.send(ans.unwrap().unwrap().to_string()) {
-							error!("Failed to get message from WebSocket: {:?}", err);
+					self.send_bundle(msg).await;
+				},
+				WSEvent::Request(None) => {
+					warn!("WSClient is gone, closing WSClientInner");
+					if let Err(err) = self.close().await {
+						error!("Failed to close WebSocket: {:?}", err);
+					}
+					self.closed(None);
+					return None;
+				},
+				WSEvent::Frame(Some(Ok(frame))) => {
+					self.last_heard = Instant::now();
+					self.route_reply(frame);
+				},
+				WSEvent::Frame(Some(Err(err))) => {
+					error!("Failed to get message from WebSocket: {:?}", err);
+					return Some(Lost::Dropped(format!("connection to {} failed: {}", self.addr, err)));
+				},
+				WSEvent::Frame(None) => {
+					warn!("WebSocket connection to {} was closed by the other side", self.addr);
+					return Some(Lost::Dropped(format!("{} closed the connection", self.addr)));
+				},
+				WSEvent::Heartbeat => {
+					let silent = self.last_heard.elapsed();
+					if silent >= self.timeouts().idle_timeout {
+						warn!("Giving up on {}: nothing heard for {:?}", self.addr, silent);
+						return Some(Lost::Silent(format!("{} stopped answering", self.addr)));
+					}
+					if silent >= self.timeouts().ping_interval {
+						trace!("Pinging {}", self.addr);
+						if let Err(err) = self.ws_stream.send(RawWsMessage::Ping(Vec::new())).await {
+							error!("Failed to ping {}: {:?}", self.addr, err);
+							return Some(Lost::Dropped(format!("connection to {} failed: {}", self.addr, err)));
 						}
 					}
 				},
-				Err(err) => {
-					error!("Failed to get msg: {:?}", err);
-					break;
+			};
+		}
+	}
+
+	fn timeouts(&self) -> Timeouts {
+		self.config.timeouts
+	}
+
+	/// Sends a request and keeps it until its reply comes.
+	async fn send_bundle(&mut self, msg: WSRequestBundle) {
+		if let Err(err) = self.ws_stream.send(msg.raw_msg()).await {
+			error!("Failed to send message through WebSocket: {:?}", err);
+			// Losing the connection will be noticed when reading from it,
+			// and this one can go again on the next
+			if msg.replay.is_none() || self.config.reconnect.is_none() {
+				let _ = msg.return_ch.send(Err(DVError::from(err)));
+				return;
+			}
+		}
+		self.pending.insert(msg.req_id, InFlight{
+			return_ch: msg.return_ch,
+			replay: msg.replay,
+		});
+	}
+
+	/// Fails whatever is still waiting, after a close that was asked for.
+	fn closed(&mut self, close_msg: Option<WSRequestBundle>) {
+		for (_, in_flight) in self.pending.drain() {
+			let _ = in_flight.return_ch.send(Err(DVError::ConnectionLost("WSClient was closed".to_string())));
+		}
+		for msg in self.backlog.drain(..) {
+			let _ = msg.return_ch.send(Err(DVError::ConnectionLost("WSClient was closed".to_string())));
+		}
+		let _ = self.state_tx.send(ConnectionState::Disconnected);
+		if let Some(msg) = close_msg {
+			if msg.return_ch.send(Ok(Vec::new())).is_err() {
+				error!("Failed to notify of WebSocket closure");
+			}
+		}
+	}
+
+	fn give_up(&mut self, lost: &Lost) {
+		for (_, in_flight) in self.pending.drain() {
+			let _ = in_flight.return_ch.send(Err(lost.error()));
+		}
+		for msg in self.backlog.drain(..) {
+			let _ = msg.return_ch.send(Err(lost.error()));
+		}
+		let _ = self.state_tx.send(ConnectionState::Disconnected);
+	}
+
+	/// Tries to connect again, waiting twice as long after each failure.
+	async fn reconnect(&mut self, reconnect: Reconnect) -> Reconnected {
+		let mut delay = reconnect.initial_delay;
+		let mut attempt = 0;
+		loop {
+			attempt += 1;
+			if reconnect.max_attempts.is_some_and(|max| attempt > max) {
+				warn!("Giving up on {} after {} attempts to reconnect", self.addr, attempt - 1);
+				return Reconnected::GaveUp;
+			}
+			let _ = self.state_tx.send(ConnectionState::Reconnecting{attempt});
+			info!("Reconnecting to {} in {:?} (attempt {})", self.addr, delay, attempt);
+			let wait = tokio::time::sleep(delay);
+			tokio::pin!(wait);
+			loop {
+				tokio::select! {
+					_ = &mut wait => break,
+					item = self.recv_ch.recv() => match item {
+						Some(msg) if msg.close_ws => {
+							self.closed(Some(msg));
+							return Reconnected::Closed;
+						},
+						Some(msg) => self.backlog.push_back(msg),
+						None => {
+							self.closed(None);
+							return Reconnected::Closed;
+						},
+					},
 				}
+			}
+			delay = std::cmp::min(delay * 2, reconnect.max_delay);
+			match self.reestablish().await {
+				Ok(()) => {
+					info!("Reconnected to {}", self.addr);
+					let _ = self.state_tx.send(ConnectionState::Connected);
+					return Reconnected::Yes;
+				},
+				Err(err) => warn!("Failed to reconnect to {}: {:?}", self.addr, err),
+			}
+		}
+	}
+
+	/// Opens a new connection and brings it to where the old one was: same
+	/// subscriptions, and the requests that can be replayed sent again.
+	async fn reestablish(&mut self) -> DVResult<()> {
+		let (ws_stream, encoding) = WSClientInner::make_connection(&self.addr, &self.config).await?;
+		self.ws_stream = ws_stream;
+		self.encoding = encoding;
+		self.last_heard = Instant::now();
+		// It may not be the same node anymore
+		let hello = self.request_now(DVRequest::HelloReq(HelloReq::new())).await;
+		check_hello(hello)?;
+		let targets: Vec<(u64, WatchTarget)> = {
+			let subscriptions = self.subscriptions.lock().unwrap();
+			subscriptions.targets.iter().map(|(id, target)| (*id, *target)).collect()
+		};
+		let mut by_node_id = HashMap::new();
+		for (id, target) in targets {
+			match self.request_now(DVRequest::SubscribeReq(SubscribeReq{target})).await? {
+				DVReply::SubscribeRpl(rpl) => { by_node_id.insert(rpl.subscription, id); },
+				other => return Err(DVError::UnexpectedMessage(format!("{:?}", other))),
+			}
+		}
+		self.subscriptions.lock().unwrap().by_node_id = by_node_id;
+		let replays: Vec<(u64, DVRequest)> = self.pending.iter()
+			.filter_map(|(req_id, in_flight)| in_flight.replay.clone().map(|req| (*req_id, req)))
+			.collect();
+		for (req_id, req) in replays {
+			debug!("Replaying {} (reqId={}) to {}", req.msg_type(), req_id, self.addr);
+			// Signed again, the old token may be stale by now
+			let token = self.codec.encode_as(self.encoding, Some(req_id), DVMessage::Req(req))?;
+			self.ws_stream.send(net::token_to_frame(self.encoding, token)).await?;
+		}
+		while let Some(msg) = self.backlog.pop_front() {
+			self.send_bundle(msg).await;
+		}
+		Ok(())
+	}
+
+	/// Sends a request of our own and waits for its reply, on a connection
+	/// nothing else has gone through yet.
+	async fn request_now(&mut self, req: DVRequest) -> DVResult<DVReply> {
+		let req_id = self.next_req_id.fetch_add(1, Ordering::Relaxed);
+		let token = self.codec.encode_as(self.encoding, Some(req_id), DVMessage::Req(req))?;
+		self.ws_stream.send(net::token_to_frame(self.encoding, token)).await?;
+		let deadline = tokio::time::Instant::now() + self.timeouts().request_timeout;
+		loop {
+			let frame = match tokio::time::timeout_at(deadline, self.ws_stream.next()).await {
+				Ok(Some(frame)) => frame?,
+				Ok(None) => return Err(DVError::ConnectionLost(format!("{} closed the connection", self.addr))),
+				Err(_) => return Err(DVError::Timeout(format!("no reply to reqId={} from {}", req_id, self.addr))),
+			};
+			let token = match net::frame_to_token(frame) {
+				Some(v) => v,
+				None => continue,
 			};
+			if self.codec.peek_req_id(&token) == Some(req_id) {
+				return reply_of(&self.codec, self.encoding, &token, req_id);
+			}
+			self.route_token(token);
+		}
+	}
+
+	/// Hands a reply to whoever is waiting for its reqId and notices to
+	/// whoever is listening for them.
+	fn route_reply(&mut self, frame: RawWsMessage) {
+		if let Some(token) = net::frame_to_token(frame) {
+			self.route_token(token);
+		}
+	}
+
+	fn route_token(&mut self, token: Vec<u8>) {
+		let req_id = match self.codec.peek_req_id(&token) {
+			Some(v) => v,
+			None => return self.route_notice(&token),
+		};
+		match self.pending.remove(&req_id) {
+			Some(in_flight) => if in_flight.return_ch.send(Ok(token)).is_err() {
+				warn!("Nobody is waiting for reqId={} anymore", req_id);
+			},
+			None => warn!("Got reply for unknown reqId={} from {}", req_id, self.addr),
+		}
+	}
+
+	fn route_notice(&self, token: &[u8]) {
+		let claims = match self.encoding {
+			Encoding::Plain => self.codec.decode_local(token),
+			_ => self.codec.decode(token),
+		};
+		match claims {
+			Ok(claims) => match claims.msg {
+				DVMessage::Ntc(mut ntc) => {
+					// Callers know subscriptions by the ID they got first
+					if let DVNotice::NodeChangedNtc(ntc) = &mut ntc {
+						if let Some(id) = self.subscriptions.lock().unwrap().by_node_id.get(&ntc.subscription) {
+							ntc.subscription = *id;
+						}
+					}
+					// An error only means nobody is listening right now
+					let _ = self.notices_tx.send(ntc);
+				},
+				other => warn!("Got message without reqId from {}: {:?}", self.addr, other),
+			},
+			Err(err) => warn!("Rejected message from {}: {:?}", self.addr, err),
 		}
 	}
 
@@ -233,15 +1183,64 @@ impl WSClientInner {
 		Ok(())
 	}
 
-	async fn make_connection(addr: &str) -> DVResult<WebSocketStream<MaybeTlsStream<TcpStream>>> {
-		let (ws_stream, _) = match connect_async(addr.to_string()).await {
+	fn host_port(url: &url::Url) -> DVResult<(String, u16)> {
+		match url.host_str() {
+			Some(host) => Ok((host.to_string(), url.port_or_known_default().unwrap_or(80))),
+			None => Err(DVError::InvalidUrl(url.to_string())),
+		}
+	}
+
+	async fn make_connection(addr: &str, config: &WSClientConfig) -> DVResult<(WSConnection, Encoding)> {
+		let url = match url::Url::parse(addr) {
+			Ok(v) => v,
+			Err(_) => return Err(DVError::InvalidUrl(addr.to_string())),
+		};
+		let stream: BoxedStream = match url.scheme() {
+			"unix" => Box::new(UnixStream::connect(url.path()).await?),
+			"ws" => Box::new(TcpStream::connect(WSClientInner::host_port(&url)?).await?),
+			"wss" => {
+				let (host, port) = WSClientInner::host_port(&url)?;
+				let tcp_stream = TcpStream::connect((host.as_str(), port)).await?;
+				let server_name = match tokio_rustls::rustls::ServerName::try_from(host.as_str()) {
+					Ok(v) => v,
+					Err(_) => return Err(DVError::InvalidUrl(addr.to_string())),
+				};
+				match config.tls.connector()?.connect(server_name, tcp_stream).await {
+					Ok(v) => Box::new(v),
+					Err(err) => {
+						error!("TLS handshake with {} failed: {}", addr, err);
+						return Err(err)?
+					}
+				}
+			},
+			_ => return Err(DVError::InvalidUrl(addr.to_string())),
+		};
+		// Offer the encoding we want, with JSON as the fallback
+		let mut offer = vec![config.encoding.subprotocol()];
+		if config.encoding != Encoding::Json {
+			offer.push(Encoding::Json.subprotocol());
+		}
+		// The handshake still wants an HTTP URL, even if nobody looks at it
+		let mut request = match url.scheme() {
+			"unix" => "ws://localhost/".into_client_request()?,
+			_ => addr.into_client_request()?,
+		};
+		match HeaderValue::from_str(&offer.join(", ")) {
+			Ok(val) => { request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, val); },
+			Err(_) => return Err(DVError::InvalidUrl(addr.to_string())),
+		}
+		let (ws_stream, response) = match client_async(request, stream).await {
 			Ok(v) => v,
 			Err(err) => {
 				error!("Failed to connect to {}: {}", addr, err);
 				return Err(err)?
 			}
 		};
-		debug!("Opened WebSocket connection to {}", addr);
-		return Ok(ws_stream);
+		let encoding = response.headers().get(SEC_WEBSOCKET_PROTOCOL)
+			.and_then(|val| val.to_str().ok())
+			.and_then(Encoding::from_subprotocol)
+			.unwrap_or(Encoding::Json);
+		debug!("Opened WebSocket connection to {} (encoding: {:?})", addr, encoding);
+		return Ok((ws_stream, encoding));
 	}
-}
\ No newline at end of file
+}
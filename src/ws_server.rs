@@ -1,34 +1,149 @@
 #![allow(unused_imports)]
 use crate::prelude::*;
+use crate::messages::*;
+use crate::dispatcher::{CancelToken, Dispatcher, Session};
+use crate::jwt::{Encoding, JwtCodec};
+use crate::net::{self, BoxedStream, PeerCred, ShutdownSignal, Timeouts};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::tungstenite::Message;
-use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use futures_util::{future, StreamExt, TryStreamExt};
+use std::time::{Duration, Instant};
+
+/// Where connections come from.
+enum Listener {
+	Tcp(TcpListener),
+	Unix(UnixListener),
+}
+
+/// A connection fresh out of a `Listener`.
+enum Accepted {
+	Tcp(TcpStream, std::net::SocketAddr),
+	Unix(UnixStream),
+}
+
+/// Held by a connection until it has closed.
+type DoneSender = tokio::sync::mpsc::Sender<()>;
+
+/// What each connection takes from its `WSServer`.
+struct ConnectionContext {
+	dispatcher: Arc<Dispatcher>,
+	codec: Arc<JwtCodec>,
+	shutdown: ShutdownSignal,
+	timeouts: Timeouts,
+	max_in_flight: usize,
+	done_tx: DoneSender,
+}
 
-#[derive(Debug)]
 pub struct WSServer {
 	addr: String,
-	listener: Option<TcpListener>,
-	open: bool
+	listener: Option<Listener>,
+	dispatcher: Arc<Dispatcher>,
+	codec: Arc<JwtCodec>,
+	tls: Option<TlsAcceptor>,
+	/// Permissions of the socket file, for servers made with `new_unix`.
+	unix_mode: Option<u32>,
+	/// Users allowed on the Unix socket. Empty lets in anyone who can open
+	/// the socket file.
+	allowed_uids: Vec<u32>,
+	shutdown: ShutdownSignal,
+	/// How long connections get to finish what they are doing once
+	/// `shutdown` fires.
+	drain_timeout: Duration,
+	timeouts: Timeouts,
+	/// Requests a single connection may have running at once.
+	max_in_flight: usize,
 }
 
-impl Default for WSServer {
-	fn default() -> Self {
-		WSServer::new(DEFAULT_WS_ADDR)
+impl std::fmt::Debug for WSServer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("WSServer")
+			.field("addr", &self.addr)
+			.field("tls", &self.tls.is_some())
+			.field("unix_mode", &self.unix_mode)
+			.field("allowed_uids", &self.allowed_uids)
+			.field("shutdown", &*self.shutdown.borrow())
+			.field("drain_timeout", &self.drain_timeout)
+			.field("timeouts", &self.timeouts)
+			.field("max_in_flight", &self.max_in_flight)
+			.finish_non_exhaustive()
 	}
 }
 
 impl WSServer {
-	pub fn new(addr: &str) -> WSServer {
+	pub fn new(addr: &str, codec: JwtCodec, dispatcher: Arc<Dispatcher>) -> WSServer {
 		WSServer{
 			addr: addr.to_string(),
 			listener: None,
-			open: false
+			dispatcher,
+			codec: Arc::new(codec),
+			tls: None,
+			unix_mode: None,
+			allowed_uids: Vec::new(),
+			shutdown: net::no_shutdown(),
+			drain_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+			timeouts: Timeouts::default(),
+			max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+		}
+	}
+
+	/// Listens on a Unix socket instead of TCP. Whoever can open the socket
+	/// file (see `mode`) may connect, and may do so without signing messages.
+	pub fn new_unix(path: &Path, mode: u32, codec: JwtCodec, dispatcher: Arc<Dispatcher>) -> WSServer {
+		WSServer{
+			unix_mode: Some(mode),
+			..WSServer::new(&path.to_string_lossy(), codec, dispatcher)
 		}
 	}
 
+	/// Only lets these users connect to the Unix socket, whatever the
+	/// permissions of the socket file.
+	pub fn with_allowed_uids(mut self, allowed_uids: Vec<u32>) -> WSServer {
+		self.allowed_uids = allowed_uids;
+		self
+	}
+
+	/// Serves `wss://` instead of `ws://`.
+	pub fn with_tls(mut self, acceptor: TlsAcceptor) -> WSServer {
+		self.tls = Some(acceptor);
+		self
+	}
+
+	/// Pings quiet clients, drops the ones that stay silent and answers
+	/// requests that take too long with a `timeout` error.
+	pub fn with_timeouts(mut self, timeouts: Timeouts) -> WSServer {
+		self.timeouts = timeouts;
+		self
+	}
+
+	/// Lets each connection run up to `max_in_flight` requests at once, and
+	/// have as many more waiting for their turn. Past that the node stops
+	/// reading from the connection until some are answered.
+	pub fn with_max_in_flight(mut self, max_in_flight: usize) -> WSServer {
+		self.max_in_flight = max_in_flight;
+		self
+	}
+
+	/// Stops accepting connections once `shutdown` fires, then gives open
+	/// ones up to `drain_timeout` to answer the requests they are handling
+	/// before closing them.
+	pub fn with_shutdown(mut self, shutdown: ShutdownSignal, drain_timeout: Duration) -> WSServer {
+		self.shutdown = shutdown;
+		self.drain_timeout = drain_timeout;
+		self
+	}
+
 	pub async fn prepare(&mut self) -> DVResult<()> {
-		let try_socket = TcpListener::bind(&self.addr).await;
-		self.listener = match try_socket {
+		let listener = match self.unix_mode {
+			Some(mode) => self.bind_unix(mode),
+			None => TcpListener::bind(&self.addr).await.map(Listener::Tcp),
+		};
+		self.listener = match listener {
 			Ok(v) => {
 				info!("Bound on {}", self.addr);
 				Some(v)
@@ -38,60 +153,434 @@ impl WSServer {
 				return Err(err)?
 			}
 		};
-		self.open = true;
 		Ok(())
 	}
 
+	fn bind_unix(&self, mode: u32) -> IOResult<Listener> {
+		use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+		let path = Path::new(&self.addr);
+		// A socket left behind by a previous run would make bind() fail, but
+		// one that still answers belongs to somebody else
+		if let Ok(meta) = fs::symlink_metadata(path) {
+			if meta.file_type().is_socket() {
+				if std::os::unix::net::UnixStream::connect(path).is_ok() {
+					return Err(IOError::new(IOErrorKind::AddrInUse, "another node is listening on this socket"));
+				}
+				fs::remove_file(path)?;
+			}
+		}
+		// The socket file must not be open to others before it gets `mode`,
+		// so it is made in a directory nobody else can enter and then moved
+		// into place, which doesn't disturb the listener
+		let private_dir = match path.parent() {
+			Some(parent) if !parent.as_os_str().is_empty() => parent.join(format!(".dv-{}", std::process::id())),
+			_ => PathBuf::from(format!(".dv-{}", std::process::id())),
+		};
+		let private_path = private_dir.join("socket");
+		let _ = fs::remove_file(&private_path);
+		let _ = fs::remove_dir(&private_dir);
+		fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+		let listener = UnixListener::bind(&private_path)
+			.and_then(|listener| {
+				fs::set_permissions(&private_path, fs::Permissions::from_mode(mode))?;
+				fs::rename(&private_path, path)?;
+				Ok(listener)
+			});
+		if listener.is_err() {
+			let _ = fs::remove_file(&private_path);
+		}
+		if let Err(err) = fs::remove_dir(&private_dir) {
+			warn!("Failed to remove {:?}: {}", private_dir, err);
+		}
+		Ok(Listener::Unix(listener?))
+	}
+
 	pub async fn main_loop(&self) -> DVResult<()> {
 		let listener = match &self.listener {
 			Some(v) => v,
 			None => return Err(DVError::NotReady("run WSServer.prepare() first".to_string()))?
 		};
 		info!("Listening on {}", self.addr);
-		while self.open {
-			match listener.accept().await {
-				Ok((stream, socket_addr)) => {
-					info!("stream = {:?}, socket_addr = {:?}", stream, socket_addr);
-					tokio::spawn(accept_connection(stream));
-				},
-				Err(err) => {
-					error!("Failed to accept incoming connection: {:?}", err);
-				}
+		// Every connection holds a sender until it is done, so the receiver
+		// only sees the channel close once they all are
+		let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
+		let mut shutdown = self.shutdown.clone();
+		loop {
+			let accepted = tokio::select! {
+				_ = net::shutdown_requested(&mut shutdown) => break,
+				accepted = Self::accept(listener) => accepted,
 			};
-	    }
-	    info!("Stopped listening on {}", self.addr);
-	    Ok(())
+			match accepted {
+				Ok(Accepted::Tcp(stream, socket_addr)) => self.spawn_tcp(stream, socket_addr, done_tx.clone()),
+				Ok(Accepted::Unix(stream)) => self.spawn_unix(stream, done_tx.clone()),
+				Err(err) => error!("Failed to accept incoming connection: {:?}", err),
+			};
+		}
+		info!("Stopped listening on {}", self.addr);
+		if self.unix_mode.is_some() {
+			if let Err(err) = fs::remove_file(&self.addr) {
+				warn!("Failed to remove socket file {}: {}", self.addr, err);
+			}
+		}
+		drop(done_tx);
+		match tokio::time::timeout(self.drain_timeout, done_rx.recv()).await {
+			Ok(_) => info!("All connections to {} closed", self.addr),
+			Err(_) => warn!("Gave up waiting for connections to {} after {:?}", self.addr, self.drain_timeout),
+		}
+		Ok(())
+	}
+
+	async fn accept(listener: &Listener) -> IOResult<Accepted> {
+		match listener {
+			Listener::Tcp(listener) => listener.accept().await.map(|(stream, socket_addr)| Accepted::Tcp(stream, socket_addr)),
+			Listener::Unix(listener) => listener.accept().await.map(|(stream, _)| Accepted::Unix(stream)),
+		}
+	}
+
+	fn context(&self, done_tx: DoneSender) -> ConnectionContext {
+		ConnectionContext{
+			dispatcher: self.dispatcher.clone(),
+			codec: self.codec.clone(),
+			shutdown: self.shutdown.clone(),
+			timeouts: self.timeouts,
+			max_in_flight: self.max_in_flight,
+			done_tx,
+		}
+	}
+
+	fn spawn_tcp(&self, stream: TcpStream, socket_addr: std::net::SocketAddr, done_tx: DoneSender) {
+		info!("stream = {:?}, socket_addr = {:?}", stream, socket_addr);
+		let ctx = self.context(done_tx);
+		let timeouts = self.timeouts;
+		match &self.tls {
+			Some(acceptor) => {
+				let acceptor = acceptor.clone();
+				tokio::spawn(async move {
+					// Bad certificates (or none, when one is required) end here
+					match tokio::time::timeout(timeouts.idle_timeout, acceptor.accept(stream)).await {
+						Ok(Ok(tls_stream)) => accept_connection(Box::new(tls_stream), socket_addr.to_string(), None, ctx).await,
+						Ok(Err(err)) => warn!("TLS handshake with {} failed: {}", socket_addr, err),
+						Err(_) => warn!("TLS handshake with {} timed out", socket_addr),
+					}
+				});
+			},
+			None => {
+				tokio::spawn(accept_connection(Box::new(stream), socket_addr.to_string(), None, ctx));
+			},
+		}
+	}
+
+	fn spawn_unix(&self, stream: UnixStream, done_tx: DoneSender) {
+		let peer_cred = match PeerCred::of(&stream) {
+			Ok(v) => v,
+			Err(err) => {
+				warn!("Rejected Unix socket connection without credentials: {:?}", err);
+				return;
+			}
+		};
+		if !self.allowed_uids.is_empty() && !self.allowed_uids.contains(&peer_cred.uid) {
+			warn!("Rejected Unix socket connection from uid {}, which is not allowed", peer_cred.uid);
+			return;
+		}
+		let addr = match peer_cred.pid {
+			Some(pid) => format!("pid {} (uid {})", pid, peer_cred.uid),
+			None => format!("uid {}", peer_cred.uid),
+		};
+		tokio::spawn(accept_connection(Box::new(stream), addr, Some(peer_cred), self.context(done_tx)));
 	}
 }
 
-async fn accept_connection(stream: TcpStream) {
-	let addr = stream.peer_addr().expect("connected streams should have a peer address");
+async fn accept_connection(stream: BoxedStream, addr: String, peer_cred: Option<PeerCred>, ctx: ConnectionContext) {
     info!("Peer address: {}", addr);
+    // `done_tx` is only held until the connection is over
+    let ConnectionContext{dispatcher, codec, mut shutdown, timeouts, max_in_flight, done_tx: _done_tx} = ctx;
 
-    let ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+    // Clients list the encodings they want as subprotocols, best first.
+    // Local processes don't have to sign their messages.
+    let local = peer_cred.is_some();
+    let mut encoding = Encoding::Json;
+    // The error type is tungstenite's
+    #[allow(clippy::result_large_err)]
+    let negotiate = |req: &Request, mut rsp: Response| {
+        let offered = req.headers().get(SEC_WEBSOCKET_PROTOCOL).and_then(|val| val.to_str().ok());
+        if let Some(chosen) = offered.and_then(|offered| Encoding::negotiate(offered, local)) {
+            encoding = chosen;
+            rsp.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(chosen.subprotocol()));
+        }
+        Ok(rsp)
+    };
+    let ws_stream = match tokio::time::timeout(timeouts.idle_timeout, tokio_tungstenite::accept_hdr_async(stream, negotiate)).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(err)) => {
+            warn!("WebSocket handshake with {} failed: {}", addr, err);
+            return;
+        }
+        Err(_) => {
+            warn!("WebSocket handshake with {} timed out", addr);
+            return;
+        }
+    };
 
-    info!("New WebSocket connection: {}", addr);
+    info!("New WebSocket connection: {} (encoding: {:?})", addr, encoding);
 
     let (mut write, mut read) = ws_stream.split();
 
-    while let Some(msg) = read.next().await {
-        info!("Got: {:?}", msg);
-        let msg = msg.expect("I don't like dealing with errors");
-        if msg.is_close() {
-        	break
-        } else if msg == Message::Text("get_time".to_string()) || msg == Message::Binary("get_time".as_bytes().to_vec()) {
-        	let now = Utc::now().to_rfc3339();
-        	write.send(Message::Text(now)).await.expect("Don't fail me");
-        } else {
-        	write.send(Message::Text("I don't get it".to_string())).await.expect("Don't fail me");
+    // Requests are handled concurrently, so replies may leave out of order
+    // and go through a single writer task. It runs until every request has
+    // been answered, and says goodbye if the node is shutting down.
+    let (out_tx, mut out_rx) = tokio::sync::mpsc::unbounded_channel::<Message>();
+    let writer_shutdown = shutdown.clone();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            if let Err(err) = write.send(msg).await {
+                error!("Failed to send message through WebSocket: {:?}", err);
+                return;
+            }
+        }
+        if *writer_shutdown.borrow() {
+            let close = Message::Close(Some(CloseFrame{
+                code: CloseCode::Away,
+                reason: "node shutting down".into(),
+            }));
+            if let Err(err) = write.send(close).await {
+                debug!("Failed to send close frame: {:?}", err);
+            }
+        }
+    });
+
+    let session = Arc::new(match peer_cred {
+        Some(peer_cred) => Session::local(peer_cred),
+        None => Session::new(),
+    });
+    let forwarder = tokio::spawn(forward_notices(dispatcher.clone(), session.clone(), codec.clone(), encoding, out_tx.clone()));
+
+    // Requests wait for a `running` permit, and the loop below for a
+    // `waiting` one before reading on: a client flooding the node stops
+    // being read, but can still cancel what is waiting
+    let running = Arc::new(Semaphore::new(max_in_flight));
+    let waiting = Arc::new(Semaphore::new(max_in_flight));
+    let responder = Responder{
+        dispatcher: dispatcher.clone(),
+        session: session.clone(),
+        codec: codec.clone(),
+        encoding,
+        out_tx: out_tx.clone(),
+        running,
+        request_timeout: timeouts.request_timeout,
+    };
+
+    // Upload chunks must be written in the order they arrived, so they take
+    // turns on a task of their own instead of holding up the loop below
+    let (uploads_tx, mut uploads_rx) = tokio::sync::mpsc::unbounded_channel::<QueuedRequest>();
+    {
+        let responder = responder.clone();
+        tokio::spawn(async move {
+            while let Some(job) = uploads_rx.recv().await {
+                responder.run(job).await;
+            }
+        });
+    }
+
+    // Anything the client sends (pongs included) shows it is still there
+    let mut last_heard = Instant::now();
+    let mut heartbeat = tokio::time::interval(timeouts.ping_interval);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let msg = tokio::select! {
+            _ = net::shutdown_requested(&mut shutdown) => {
+                info!("Closing WebSocket connection {}: shutting down", addr);
+                break;
+            },
+            _ = heartbeat.tick() => {
+                let silent = last_heard.elapsed();
+                if silent >= timeouts.idle_timeout {
+                    warn!("Dropping WebSocket connection {}: nothing heard for {:?}", addr, silent);
+                    break;
+                }
+                if silent >= timeouts.ping_interval {
+                    trace!("Pinging {}", addr);
+                    let _ = out_tx.send(Message::Ping(Vec::new()));
+                }
+                continue;
+            },
+            msg = read.next() => match msg {
+                Some(v) => v,
+                None => break,
+            },
+        };
+        last_heard = Instant::now();
+        // Whatever tungstenite can't read (bad UTF-8, oversized or broken
+        // frames, ...) leaves the stream in an unknown state
+        let msg = match msg {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Dropping WebSocket connection {}: {}", addr, err);
+                break;
+            }
+        };
+        trace!("Got {} frame of {} bytes from {}", net::frame_kind(&msg), msg.len(), addr);
+        if let Message::Close(_) = msg {
+            break;
+        }
+        let token = match net::frame_to_token(msg) {
+            Some(v) => v,
+            None => continue,
+        };
+        let decoded = match local {
+            true => codec.decode_local(&token),
+            false => codec.decode(&token),
+        };
+        let claims = match decoded {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Rejected message from {}: {:?}", addr, err);
+                let req_id = codec.peek_req_id(&token);
+                send_reply(&out_tx, &codec, encoding, req_id, DVReply::ErrorRpl(ErrorInfo::from(&err)));
+                continue;
+            }
+        };
+        let req = match claims.msg {
+            DVMessage::Req(req) => req,
+            DVMessage::Rpl(_) | DVMessage::Ntc(_) => {
+                let rpl = DVReply::ErrorRpl(ErrorInfo::from(&DVError::UnexpectedMessage("expected a request".to_string())));
+                send_reply(&out_tx, &codec, encoding, claims.req_id, rpl);
+                continue;
+            }
+        };
+        debug!("Got {} (reqId={:?}) from {}", req.msg_type(), claims.req_id, claims.iss);
+        // Cheap, and must not wait behind what it cancels
+        if let DVRequest::CancelReq(_) = req {
+            let rpl = dispatcher.dispatch(&session, req, &CancelToken::new()).await;
+            send_reply(&out_tx, &codec, encoding, claims.req_id, rpl);
+            continue;
+        }
+        let queued = tokio::select! {
+            _ = net::shutdown_requested(&mut shutdown) => {
+                info!("Closing WebSocket connection {}: shutting down", addr);
+                break;
+            },
+            permit = waiting.clone().acquire_owned() => match permit {
+                Ok(v) => v,
+                Err(_) => break,
+            },
+        };
+        let job = QueuedRequest{
+            cancel: session.begin_request(claims.req_id),
+            req,
+            req_id: claims.req_id,
+            queued,
+        };
+        if let DVRequest::UploadChunkReq(_) = job.req {
+            if uploads_tx.send(job).is_err() {
+                break;
+            }
+            continue;
         }
+        let responder = responder.clone();
+        tokio::spawn(async move {
+            responder.run(job).await;
+        });
+    }
+
+    // Nobody will read the replies, unless the node is only shutting down
+    // and waits for them
+    if !*shutdown.borrow() {
+        session.cancel_all();
+    }
+    forwarder.abort();
+    // The writer keeps going until the last upload chunk is answered
+    drop(uploads_tx);
+    drop(responder);
+    drop(out_tx);
+    if let Err(err) = writer.await {
+        error!("WebSocket writer for {} failed: {:?}", addr, err);
     }
+    info!("Closed WebSocket connection: {}", addr);
 
     // // We should not forward messages other than text or binary.
     // read.try_filter(|msg| future::ready(msg.eq(&Message::Text("ask_time".to_string()))))
     // 	.forward(write)
     //     .await
     //     .expect("Failed to forward messages")
+}
+
+/// A request that got a `waiting` permit, and now needs a `running` one.
+struct QueuedRequest {
+    req: DVRequest,
+    req_id: Option<u64>,
+    cancel: CancelToken,
+    queued: OwnedSemaphorePermit,
+}
+
+/// Runs the requests of a connection and sends back their replies.
+#[derive(Clone)]
+struct Responder {
+    dispatcher: Arc<Dispatcher>,
+    session: Arc<Session>,
+    codec: Arc<JwtCodec>,
+    encoding: Encoding,
+    out_tx: tokio::sync::mpsc::UnboundedSender<Message>,
+    running: Arc<Semaphore>,
+    request_timeout: Duration,
+}
+
+impl Responder {
+    async fn run(&self, job: QueuedRequest) {
+        let QueuedRequest{req, req_id, cancel, queued} = job;
+        let rpl = tokio::select! {
+            permit = self.running.clone().acquire_owned() => {
+                drop(queued);
+                let rpl = self.dispatcher.dispatch_within(&self.session, req, &cancel, self.request_timeout).await;
+                drop(permit);
+                rpl
+            },
+            _ = cancel.cancelled() => DVReply::ErrorRpl(ErrorInfo::from(&DVError::Cancelled("request was cancelled before it started".to_string()))),
+        };
+        self.session.end_request(req_id);
+        send_reply(&self.out_tx, &self.codec, self.encoding, req_id, rpl);
+    }
+}
+
+fn send_reply(out_tx: &tokio::sync::mpsc::UnboundedSender<Message>, codec: &JwtCodec, encoding: Encoding, req_id: Option<u64>, rpl: DVReply) {
+    let token = match codec.encode_reply(encoding, req_id, rpl) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Dropped reply to reqId={:?}: {:?}", req_id, err);
+            return;
+        }
+    };
+    if out_tx.send(net::token_to_frame(encoding, token)).is_err() {
+        warn!("Dropped reply to reqId={:?}: connection already closed", req_id);
+    }
+}
+
+/// Pushes a notice for every change the session has subscribed to.
+async fn forward_notices(dispatcher: Arc<Dispatcher>, session: Arc<Session>, codec: Arc<JwtCodec>, encoding: Encoding, out_tx: tokio::sync::mpsc::UnboundedSender<Message>) {
+    use tokio::sync::broadcast::error::RecvError;
+    let mut events = dispatcher.notices().listen();
+    loop {
+        let notices = match events.recv().await {
+            Ok(event) => session.notices_for(&event),
+            Err(RecvError::Lagged(count)) => {
+                warn!("Connection lagged behind and lost {} change events", count);
+                match session.has_subscriptions() {
+                    true => vec![DVNotice::NoticesLostNtc(NoticesLostNtc{count})],
+                    false => vec![],
+                }
+            },
+            Err(RecvError::Closed) => return,
+        };
+        for ntc in notices {
+            let token = match codec.encode_as(encoding, None, DVMessage::Ntc(ntc)) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("Failed to encode notice: {:?}", err);
+                    continue;
+                }
+            };
+            if out_tx.send(net::token_to_frame(encoding, token)).is_err() {
+                return;
+            }
+        }
+    }
 }
\ No newline at end of file